@@ -1,41 +1,50 @@
-use std::rc::Rc;
-use std::cell::RefCell;
+use std::sync::Arc;
 
 use crate::miner::Miner;
 use crate::wallet::Wallet;
 use crate::blockchain::Blockchain;
 use crate::cli::{CLI, Program, CLICommandExec};
 use crate::database::Database;
+pub use crate::cache::DEFAULT_UTXO_CACHE_SIZE;
+pub use crate::database::{DatabaseOptions, PruneTarget};
 
 pub struct Application {
-    database: Rc<Database>,
+    database: Arc<Database>, // Shared with the components, some of which run on their own threads (see miner.rs)
     miner: Miner,
     wallet: Wallet,
+    blockchain: Blockchain,
 }
 
 
 impl Application {
-    pub fn new(difficulty: u8) -> Application {
-        let database = match Database::open("database") {
+    pub fn new(difficulty: u8, options: DatabaseOptions) -> Application {
+        let database = match Database::open_with_options("database", options) {
             Ok(db) => db,
             Err(e) => panic!("{}", e),
         };
 
-        let database = Rc::new(database);
+        let database = Arc::new(database);
 
         // Create and initialize wallet
-        let mut wallet: Wallet = Wallet::new(Rc::clone(&database), String::from("keys.txt"));
+        let mut wallet: Wallet = Wallet::new(Arc::clone(&database), String::from("keys.txt"));
         wallet.initialize();
 
         // Create and initialize miner
         let miner;
 
         match wallet.get_address(0) {
-            Ok(address) => miner = Miner::new(address.clone(), Rc::clone(&database), difficulty),
+            Ok(address) => miner = Miner::new(address.clone(), Arc::clone(&database), difficulty),
             Err(_) => panic!("Wallet was not initialized properly: could not get default address")
         }
 
-        Application { database, miner, wallet }
+        let blockchain = Blockchain::new(Arc::clone(&database), difficulty);
+
+        Application { database, miner, wallet, blockchain }
+    }
+
+    // Minimum fee rate (per byte) of the transactions accepted in the transaction pool
+    pub fn set_min_fee_rate(&mut self, min_fee_rate: f32) {
+        self.miner.set_min_fee_rate(min_fee_rate);
     }
 
 
@@ -49,9 +58,11 @@ impl Application {
                 match instruction.program {
                     Program::WALLET => self.wallet.execute(instruction),
                     Program::MINER  => self.miner.execute(instruction),
+                    Program::BLOCKCHAIN => self.blockchain.execute(instruction),
                     Program::NONE   => (),
                 }
             }
+            self.blockchain.report_background_tasks();
         }
 
         // // Block 1
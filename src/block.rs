@@ -7,6 +7,11 @@ use crate::transaction::{Transaction, TxOut};
 
 const PUB_KEY_HASH_SIZE: usize = 20;
 
+// Genesis block: fixed content, so that every database starts from the same chain
+const GENESIS_TIMESTAMP: u128 = 1_685_000_000_000;
+const GENESIS_REWARD: f32 = 25.0;
+const GENESIS_ADDRESS: &str = "128GaUUoKKnEgioDsm5Pa9FxmXtzQMk3F9";
+
 #[derive(Serialize, Deserialize)]
 pub struct Block {
     index: u32,
@@ -18,6 +23,18 @@ pub struct Block {
     nonce: u32,
 }
 
+// Fields of a Block without its transactions. Headers are kept for every block of the chain, even when
+//     the block itself is pruned (see database.rs)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlockHeader {
+    pub index: u32,
+    pub hash: [u8; 32],
+    pub prev_hash: [u8; 32],
+    pub timestamp: u128,
+    pub merkle_root: [u8; 32],
+    pub nonce: u32,
+}
+
 
 impl Block {
 
@@ -33,17 +50,24 @@ impl Block {
         }
     }
 
+    // First block of the chain, identical for every database
+    pub fn genesis() -> Block {
+        let pub_key_hash = crypto::address_to_public_key_hash(&String::from(GENESIS_ADDRESS))
+            .expect("Genesis address must be valid");
+
+        let mut block = Block::new();
+        block.timestamp = GENESIS_TIMESTAMP;
+        let mut coinbase = Transaction::new(Vec::new(), vec![TxOut::new(GENESIS_REWARD, pub_key_hash)]);
+        coinbase.hash();
+        block.add_transaction(coinbase);
+        block.merkle_root = block.compute_merkle_root();
+        block.calculate_hash();
+        block
+    }
+
     // --- Private
     fn concatenate(&self) -> String {
-        let mut data = String::new();
-
-        data.push_str(&self.index.to_string());                    // Index
-        data.push_str(&hex::encode(&self.prev_hash));            // Previous hash
-        data.push_str(&self.timestamp.to_string());             // Timestamp
-        data.push_str(&hex::encode(&self.merkle_root));       // Merkle root
-        data.push_str(&self.nonce.to_string());              // Nonce
-
-        data
+        self.get_header().concatenate()
     }
 
     pub fn calculate_hash(&mut self) {
@@ -51,10 +75,42 @@ impl Block {
         crypto::calculate_sha256_hash(data.as_bytes(), &mut self.hash );
     }
 
+    // Whether the stored hash is the hash of the block fields, e.g. for a block read from a file
+    pub fn has_valid_hash(&self) -> bool {
+        let mut hash = [0u8; 32];
+        crypto::calculate_sha256_hash(self.concatenate().as_bytes(), &mut hash);
+        hash == self.hash
+    }
+
+    // Root of the merkle tree of the transaction hashes: each level hashes pairs of the level below with
+    //     Sha256(Sha256(left + right)), the last hash of a level with an odd count is paired with itself
+    pub fn compute_merkle_root(&self) -> [u8; 32] {
+        let mut level: Vec<[u8; 32]> = self.transactions.iter().map(|tx| *tx.get_hash()).collect();
+        if level.is_empty() {
+            return [0u8; 32];
+        }
+
+        while level.len() > 1 {
+            level = level.chunks(2).map(|pair| {
+                let mut data = pair[0].to_vec();
+                data.extend_from_slice(pair.get(1).unwrap_or(&pair[0]));
+                let mut hash = [0u8; 32];
+                crypto::calculate_double_sha256_hash(&data, &mut hash);
+                hash
+            }).collect();
+        }
+        level[0]
+    }
+
+    // Whether the hash of self has at least 'difficulty' leading zeros (in hex)
+    pub fn has_proof_of_work(&self, difficulty: u8) -> bool {
+        crypto::leading_zeros_count(&hex::encode(self.hash)) >= difficulty
+    }
+
     fn mine_until_done(&mut self, difficulty: u8) {
         self.calculate_hash();
 
-        while crypto::leading_zeros_count(&hex::encode(&self.hash)) < difficulty {
+        while !self.has_proof_of_work(difficulty) {
             self.nonce += 1;
             self.calculate_hash();
         }
@@ -73,7 +129,14 @@ impl Block {
             return Err("Error while mining block: could not get current time");
         }
 
-        self.add_transaction(Transaction::new(Vec::new(), vec![TxOut::new(reward, pub_key_hash)]));
+        // The coinbase is locked to the block height, which also keeps coinbase hashes unique
+        let mut coinbase = Transaction::new(Vec::new(), vec![TxOut::new(reward, pub_key_hash)]);
+        coinbase.set_lock_time(self.index);
+        coinbase.hash();
+
+        self.add_transaction(coinbase);
+        // The block hash commits to the transactions through the merkle root
+        self.merkle_root = self.compute_merkle_root();
         self.mine_until_done(difficulty);
         Ok(())
     }
@@ -85,10 +148,26 @@ impl Block {
 impl Block {
     pub fn get_hash(&self) -> &[u8; 32] { &self.hash }
     pub fn get_index(&self) -> u32 { self.index }
+    pub fn get_prev_hash(&self) -> &[u8; 32] { &self.prev_hash }
+    pub fn get_timestamp(&self) -> u128 { self.timestamp }
+    pub fn get_merkle_root(&self) -> &[u8; 32] { &self.merkle_root }
+    // Block time in unix seconds, as used by transaction timelocks
+    pub fn get_time(&self) -> u64 { (self.timestamp / 1000) as u64 }
     pub fn get_transactions(&self) -> &Vec<Transaction> {
         &self.transactions
     }
 
+    pub fn get_header(&self) -> BlockHeader {
+        BlockHeader {
+            index: self.index,
+            hash: self.hash,
+            prev_hash: self.prev_hash,
+            timestamp: self.timestamp,
+            merkle_root: self.merkle_root,
+            nonce: self.nonce,
+        }
+    }
+
     pub fn set_index(&mut self, index: u32) {
         self.index = index;
     }
@@ -99,6 +178,32 @@ impl Block {
 }
 
 
+impl BlockHeader {
+    // Block time in unix seconds, as used by transaction timelocks
+    pub fn get_time(&self) -> u64 { (self.timestamp / 1000) as u64 }
+
+    // Whether 'hash' is the hash of the other fields, as for Block::has_valid_hash
+    pub fn has_valid_hash(&self) -> bool {
+        let mut hash = [0u8; 32];
+        crypto::calculate_sha256_hash(self.concatenate().as_bytes(), &mut hash);
+        hash == self.hash
+    }
+
+    // Data hashed into the block hash
+    fn concatenate(&self) -> String {
+        let mut data = String::new();
+
+        data.push_str(&self.index.to_string());                    // Index
+        data.push_str(&hex::encode(&self.prev_hash));            // Previous hash
+        data.push_str(&self.timestamp.to_string());             // Timestamp
+        data.push_str(&hex::encode(&self.merkle_root));       // Merkle root
+        data.push_str(&self.nonce.to_string());              // Nonce
+
+        data
+    }
+}
+
+
 impl fmt::Display for Block {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let tab = "        ";
@@ -1,64 +1,509 @@
-// Blockchain is for testing only
-// It will later be replaced by a database
+/// Blockchain.rs
+///
+/// Read-only view of the active chain stored in the Database, used to explore blocks from the command
+///     line. Blocks are added to the chain by the Miner (see miner.rs).
+///
+/// A pruned database only keeps the most recent blocks, older blocks can only be shown by their header.
+///
+/// Compact block filters (see filter.rs) let light clients find the blocks relevant to them without
+///     revealing their addresses, they require the block filter index.
+///
+/// The Blockchain also starts the background validation of a loaded UTXO snapshot (see snapshot.rs) on
+///     its own thread, hands it the history blocks imported with 'importchain', and reports its outcome.
 
-use std::collections::LinkedList;
-use std::fmt;
+use std::sync::Arc;
 
-use crate::block::Block;
+use crate::chainfile;
+use crate::cli::{CLICommandExec, Command, Instruction};
 use crate::crypto;
+use crate::database::{Database, Direction, PruneTarget, MIN_BLOCKS_TO_KEEP};
+use crate::miner::BLOCK_REWARD;
+use crate::rocks;
+use crate::snapshot::{self, BackgroundValidation, UtxoSnapshot, ValidationThread};
+use crate::verify::{self, DEFAULT_CHECK_DEPTH};
 
-const INITIAL_MINING_REWARD: f32 = 25.0;
-const PUB_KEY_HASH_SIZE: usize = 20;
+// Blocks between two progress reports of 'verifychain' and 'reindex'
+const PROGRESS_INTERVAL: u32 = 100;
+// Filter headers returned by a single 'getfilterheaders'
+const MAX_FILTER_HEADERS: u32 = 2000;
 
 pub struct Blockchain {
-    chain: LinkedList<Block>,
-    difficulty: u8,
-    reward: f32
+    database: Arc<Database>,
+    difficulty: u8, // Proof of work required from the blocks validated here
+    background: Option<ValidationThread>, // Validation of the history of a loaded snapshot
 }
 
 
 impl Blockchain {
-    pub fn new(difficulty: u8) -> Blockchain {
-        let mut blockchain = Blockchain{chain: LinkedList::new(), difficulty, reward: INITIAL_MINING_REWARD};
-        let mut genesis = Block::new();
-
-        genesis.set_index(0);
-        genesis.mine(difficulty, INITIAL_MINING_REWARD, crypto::address_to_public_key_hash(&String::from("128GaUUoKKnEgioDsm5Pa9FxmXtzQMk3F9")).unwrap())
-            .expect("Could not add genesis block");
+    pub fn new(database: Arc<Database>, difficulty: u8) -> Blockchain {
+        let mut blockchain = Blockchain { database, difficulty, background: None };
+        blockchain.start_background_validation();
         blockchain
     }
 
-    pub fn mine_and_add_block(&mut self, mut block: Block, miner_addr: [u8; PUB_KEY_HASH_SIZE]) -> bool {
-        // block.set_index(self.chain.len() as u32);
-        // block.set_prev_hash_from_block(self.chain.back());
-        // block.mine(self.difficulty, self.reward, miner_addr);
-        //
-        // self.chain.push_back(block);
-        //
-        // true
-        true
+    // Report the outcome of the background validation once its thread has ended
+    pub fn report_background_tasks(&mut self) {
+        if !self.background.as_ref().is_some_and(|background| background.is_finished()) {
+            return;
+        }
+
+        match self.background.take().map(|background| background.join()) {
+            Some(Ok(Ok(true))) => println!("Background validation completed, the UTXO snapshot is valid"),
+            Some(Ok(Err(e))) => {
+                println!("Background validation failed: {e}");
+                println!("The UTXO snapshot cannot be trusted, delete the database directory to sync from the genesis block");
+            },
+            Some(Err(_)) => println!("Error: the background validation thread panicked"),
+            Some(Ok(Ok(false))) | None => (),
+        }
     }
 
-    pub fn get_last_block(&self) -> Option<&Block> {
-        self.chain.back()
+    fn start_background_validation(&mut self) {
+        let background = BackgroundValidation::new(Arc::clone(&self.database), BLOCK_REWARD, self.difficulty)
+            .and_then(|background| background.map(BackgroundValidation::spawn).transpose());
+        match background {
+            Ok(background) => self.background = background,
+            Err(e) => println!("Error: cannot start background validation: {e}"),
+        }
+    }
+
+    // Hash of the block designated either by its hex encoded hash or by its height in the active chain
+    fn find_block_hash(&self, reference: &str) -> rocks::Result<Option<[u8; 32]>> {
+        if let Ok(height) = reference.parse::<u32>() {
+            return self.database.get_block_hash(height);
+        }
+        Ok(hex::decode(reference).ok().and_then(|hash| <[u8; 32]>::try_from(hash).ok()))
+    }
+
+    // Key of 'address' in the address index, for public key hash and Schnorr addresses
+    fn parse_address(&self, instruction: &Instruction) -> Option<Vec<u8>> {
+        if instruction.args.len() != 1 {
+            println!("Wrong number of arguments");
+            return None;
+        }
+        if !self.database.is_address_index_enabled() {
+            println!("The address index is disabled, restart with -addressindex to enable it");
+            return None;
+        }
+
+        let address = &instruction.args[0];
+        if let Ok(pub_key_hash) = crypto::address_to_public_key_hash(address) {
+            return Some(pub_key_hash.to_vec());
+        }
+        if let Ok(public_key) = crypto::address_to_schnorr_public_key(address) {
+            return Some(public_key.to_vec());
+        }
+        println!("Please, provide a valid address");
+        None
     }
 }
 
 
-impl Blockchain {
-    pub fn get_difficulty(&self) -> u8 {
-        self.difficulty
+// ------ Instruction execution
+impl CLICommandExec for Blockchain {
+    fn execute(&mut self, instruction: Instruction) {
+        match instruction.command {
+            Command::GETBLOCKHASH   => self.cli_get_block_hash(instruction),
+            Command::GETBLOCK       => self.cli_get_block(instruction),
+            Command::LISTBLOCKS     => self.cli_list_blocks(instruction),
+            Command::GETTRANSACTION => self.cli_get_transaction(instruction),
+            Command::GETADDRESSHISTORY => self.cli_get_address_history(instruction),
+            Command::GETADDRESSBALANCE => self.cli_get_address_balance(instruction),
+            Command::GETBLOCKCHAININFO => self.cli_get_blockchain_info(instruction),
+            Command::DUMPTXOUTSET   => self.cli_dump_tx_out_set(instruction),
+            Command::LOADTXOUTSET   => self.cli_load_tx_out_set(instruction),
+            Command::EXPORTCHAIN    => self.cli_export_chain(instruction),
+            Command::IMPORTCHAIN    => self.cli_import_chain(instruction),
+            Command::VERIFYCHAIN    => self.cli_verify_chain(instruction),
+            Command::REINDEX        => self.cli_reindex(instruction),
+            Command::INVALIDATEBLOCK => self.cli_invalidate_block(instruction),
+            Command::GETBLOCKFILTER => self.cli_get_block_filter(instruction),
+            Command::GETFILTERHEADERS => self.cli_get_filter_headers(instruction),
+
+            _ => (),
+        };
     }
 }
 
+impl Blockchain {
+    // 'getblockhash <height>'
+    fn cli_get_block_hash(&self, instruction: Instruction) {
+        if instruction.args.len() != 1 {
+            println!("Wrong number of arguments");
+            return;
+        }
+
+        let height = match instruction.args[0].parse::<u32>() {
+            Ok(height) => height,
+            Err(_) => { println!("Please, provide a valid height"); return; }
+        };
+
+        match self.database.get_block_hash(height) {
+            Ok(Some(hash)) => println!("{}", hex::encode(hash)),
+            Ok(None) => println!("No block at height {}", height),
+            Err(e) => println!("Error: {e}"),
+        }
+    }
+
+    // 'getblock <hash|height>'
+    fn cli_get_block(&self, instruction: Instruction) {
+        if instruction.args.len() != 1 {
+            println!("Wrong number of arguments");
+            return;
+        }
+
+        let hash = match self.find_block_hash(&instruction.args[0]) {
+            Ok(Some(hash)) => hash,
+            Ok(None) => { println!("Block not found"); return; },
+            Err(e) => { println!("Error: {e}"); return; },
+        };
+
+        match (self.database.get_block(&hash), self.database.get_header(&hash)) {
+            (Ok(Some(block)), _) => println!("{}", block),
+            (Ok(None), Ok(Some(header))) => {
+                println!("Block {} (index {}) is pruned, only its header is stored", hex::encode(header.hash), header.index);
+                println!("prev_hash: {}", hex::encode(header.prev_hash));
+                println!("timestamp: {}", header.timestamp);
+            },
+            (Ok(None), Ok(None)) => println!("Block not found"),
+            (Err(e), _) | (_, Err(e)) => println!("Error: {e}"),
+        }
+    }
+
+    // 'listblocks [start height] [end height]', from the most recent block, '--r' to start from the oldest
+    fn cli_list_blocks(&self, instruction: Instruction) {
+        let heights: Vec<u32> = match instruction.args.iter().map(|arg| arg.parse::<u32>()).collect() {
+            Ok(heights) => heights,
+            Err(_) => { println!("Please, provide valid heights"); return; }
+        };
+
+        let (tip, prune_height) = match (self.database.get_height(), self.database.get_prune_height()) {
+            (Ok(Some(tip)), Ok(prune_height)) => (tip, prune_height.unwrap_or(0)),
+            (Ok(None), _) => return,
+            (Err(e), _) | (_, Err(e)) => { println!("Error: {e}"); return; }
+        };
+        // Pruned blocks are skipped
+        let start = heights.first().copied().unwrap_or(0).max(prune_height);
+        let end = heights.get(1).copied().unwrap_or(tip).min(tip);
+        let direction = if instruction.options.contains(&'r') { Direction::Forward } else { Direction::Backward };
+
+        for block in self.database.iter_chain_range(start, end, direction) {
+            match block {
+                Ok(block) => println!("{} {} ({} transactions)", block.get_index(), hex::encode(block.get_hash()), block.get_transactions().len()),
+                Err(e) => { println!("Error: {e}"); return; }
+            }
+        }
+    }
+
+    // 'gettransaction <txid>', requires the transaction index
+    fn cli_get_transaction(&self, instruction: Instruction) {
+        if instruction.args.len() != 1 {
+            println!("Wrong number of arguments");
+            return;
+        }
+        if !self.database.is_tx_index_enabled() {
+            println!("The transaction index is disabled, restart with -txindex to enable it");
+            return;
+        }
+
+        let tx_hash = match hex::decode(&instruction.args[0]).ok().and_then(|hash| <[u8; 32]>::try_from(hash).ok()) {
+            Some(tx_hash) => tx_hash,
+            None => { println!("Please, provide a valid transaction hash"); return; }
+        };
+
+        match (self.database.get_transaction(&tx_hash), self.database.get_height()) {
+            (Ok(Some((tx, block))), Ok(Some(tip))) => {
+                println!("{}", tx);
+                println!("Witness hash: {}", hex::encode(tx.get_witness_hash()));
+                println!("Block: {} (index {})", hex::encode(block.get_hash()), block.get_index());
+                println!("Confirmations: {}", tip - block.get_index() + 1);
+            },
+            (Ok(_), Ok(_)) => println!("Transaction not found"),
+            (Err(e), _) | (_, Err(e)) => println!("Error: {e}"),
+        }
+    }
+
+    // 'getaddresshistory <address>', requires the address index
+    fn cli_get_address_history(&self, instruction: Instruction) {
+        let destination = match self.parse_address(&instruction) {
+            Some(destination) => destination,
+            None => return,
+        };
+
+        match self.database.get_address_history(&destination) {
+            Ok(history) => history.iter().for_each(|event| {
+                println!("Received {} in {}:{} (block {})", event.amount, hex::encode(event.outpoint.tx_hash), event.outpoint.n, event.height);
+                if let Some(spent_by) = event.spent_by {
+                    println!("    spent by {} (block {})", hex::encode(spent_by.tx_hash), spent_by.height);
+                }
+            }),
+            Err(e) => println!("Error: {e}"),
+        }
+    }
+
+    // 'getaddressbalance <address>', requires the address index
+    fn cli_get_address_balance(&self, instruction: Instruction) {
+        let destination = match self.parse_address(&instruction) {
+            Some(destination) => destination,
+            None => return,
+        };
+
+        match self.database.get_address_balance(&destination) {
+            Ok(balance) => println!("Balance: {}", balance),
+            Err(e) => println!("Error: {e}"),
+        }
+    }
+
+    // 'getblockfilter <hash|height>', requires the block filter index
+    fn cli_get_block_filter(&self, instruction: Instruction) {
+        if instruction.args.len() != 1 {
+            println!("Wrong number of arguments");
+            return;
+        }
+        if !self.database.is_block_filter_index_enabled() {
+            println!("The block filter index is disabled, restart with -blockfilterindex to enable it");
+            return;
+        }
+
+        let hash = match self.find_block_hash(&instruction.args[0]) {
+            Ok(Some(hash)) => hash,
+            Ok(None) => { println!("Block not found"); return; },
+            Err(e) => { println!("Error: {e}"); return; },
+        };
 
-impl fmt::Display for Blockchain {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        writeln!(f, "Blockchain {{")?;
-        writeln!(f, "    difficulty: {},", self.difficulty)?;
-        writeln!(f, "    chain: [")?;
-        self.chain.iter().for_each(|b| {writeln!(f, "        {}", b); });
-        writeln!(f, "    ],")?;
-        writeln!(f, "}}")
+        match self.database.get_block_filter(&hash) {
+            Ok(Some(entry)) => {
+                println!("Filter: {}", hex::encode(&entry.filter));
+                println!("Header: {}", hex::encode(entry.header));
+            },
+            Ok(None) => println!("Block not found in the active chain"),
+            Err(e) => println!("Error: {e}"),
+        }
     }
-}
\ No newline at end of file
+
+    // 'getfilterheaders <start height> [count]', filter headers of up to 'count' blocks (2000 by default)
+    fn cli_get_filter_headers(&self, instruction: Instruction) {
+        if instruction.args.is_empty() || instruction.args.len() > 2 {
+            println!("Wrong number of arguments");
+            return;
+        }
+        if !self.database.is_block_filter_index_enabled() {
+            println!("The block filter index is disabled, restart with -blockfilterindex to enable it");
+            return;
+        }
+
+        let (start, count) = match (instruction.args[0].parse::<u32>(), instruction.args.get(1).map(|count| count.parse::<u32>())) {
+            (Ok(start), None) => (start, MAX_FILTER_HEADERS),
+            (Ok(start), Some(Ok(count))) => (start, count.min(MAX_FILTER_HEADERS)),
+            _ => { println!("Please, provide a valid start height and count"); return; }
+        };
+
+        for height in start..start.saturating_add(count) {
+            let hash = match self.database.get_block_hash(height) {
+                Ok(Some(hash)) => hash,
+                Ok(None) => return,
+                Err(e) => { println!("Error: {e}"); return; },
+            };
+            match self.database.get_block_filter(&hash) {
+                Ok(Some(entry)) => println!("{} {} {}", height, hex::encode(hash), hex::encode(entry.header)),
+                Ok(None) => { println!("Filter of block {} not found", height); return; },
+                Err(e) => { println!("Error: {e}"); return; },
+            }
+        }
+    }
+
+    // 'getblockchaininfo': chain tip, pruning and enabled indexes
+    fn cli_get_blockchain_info(&self, instruction: Instruction) {
+        if !instruction.args.is_empty() {
+            println!("Wrong number of arguments");
+            return;
+        }
+
+        let (height, hash, prune_height) = match (self.database.get_height(), self.database.get_last_block_hash(), self.database.get_prune_height()) {
+            (Ok(Some(height)), Ok(Some(hash)), Ok(prune_height)) => (height, hash, prune_height),
+            (Ok(_), Ok(_), Ok(_)) => { println!("The chain is empty"); return; },
+            (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => { println!("Error: {e}"); return; },
+        };
+        let enabled = |enabled: bool| if enabled { "enabled" } else { "disabled" };
+
+        println!("Height: {}", height);
+        println!("Best block: {}", hex::encode(hash));
+        match prune_height {
+            Some(prune_height) => println!("Pruned: yes, blocks are stored from height {}", prune_height),
+            None => println!("Pruned: no"),
+        }
+        if let Some(target) = self.database.get_options().prune {
+            match target {
+                PruneTarget::Depth(depth) => println!("Prune target: {} blocks", depth.max(MIN_BLOCKS_TO_KEEP)),
+                PruneTarget::Size(size) => println!("Prune target: {} bytes", size),
+            }
+        }
+        println!("Transaction index: {}", enabled(self.database.is_tx_index_enabled()));
+        println!("Address index: {}", enabled(self.database.is_address_index_enabled()));
+        println!("Block filter index: {}", enabled(self.database.is_block_filter_index_enabled()));
+        let (entries, dirty, max_size) = self.database.get_utxo_cache_usage();
+        println!("UTXO cache: {} entries ({} not written yet), {} MB maximum", entries, dirty, max_size / 1_000_000);
+        if let Some(background) = &self.background {
+            println!("UTXO snapshot: history validated up to height {} of {}", background.get_height(), background.get_snapshot_height());
+        }
+    }
+
+    // 'dumptxoutset <file> [height]', the UTXO set at the last block by default
+    fn cli_dump_tx_out_set(&self, instruction: Instruction) {
+        if instruction.args.is_empty() || instruction.args.len() > 2 {
+            println!("Wrong number of arguments");
+            return;
+        }
+
+        let height = match instruction.args.get(1).map(|height| height.parse::<u32>()) {
+            Some(Ok(height)) => height,
+            Some(Err(_)) => { println!("Please, provide a valid height"); return; },
+            None => match self.database.get_height() {
+                Ok(height) => height.unwrap_or(0),
+                Err(e) => { println!("Error: {e}"); return; },
+            },
+        };
+
+        match snapshot::create_snapshot(&self.database, height).and_then(|snapshot| snapshot.write_to_file(&instruction.args[0]).map(|_| snapshot)) {
+            Ok(snapshot) => {
+                println!("Snapshot of {} outputs at height {} written to {}", snapshot.utxos.len(), height, instruction.args[0]);
+                println!("Content hash: {}", hex::encode(snapshot.utxo_hash));
+            },
+            Err(e) => println!("Error: {e}"),
+        }
+    }
+
+    // 'loadtxoutset <file> <content hash>', into a new database. The content hash must come from a trusted node
+    fn cli_load_tx_out_set(&mut self, instruction: Instruction) {
+        if instruction.args.len() != 2 {
+            println!("Wrong number of arguments");
+            return;
+        }
+
+        let expected_hash = match hex::decode(&instruction.args[1]) {
+            Ok(hash) if hash.len() == 32 => hash,
+            _ => { println!("Please, provide the content hash of the snapshot"); return; }
+        };
+        let snapshot = match UtxoSnapshot::read_from_file(&instruction.args[0]) {
+            Ok(snapshot) => snapshot,
+            Err(e) => { println!("Error: {e}"); return; },
+        };
+        if expected_hash != snapshot.utxo_hash {
+            println!("The snapshot content hash {} does not match the expected hash", hex::encode(snapshot.utxo_hash));
+            return;
+        }
+
+        match snapshot::load_snapshot(&self.database, &snapshot) {
+            Ok(()) => {
+                println!("Snapshot of {} outputs loaded, the chain starts at height {}", snapshot.utxos.len(), snapshot.height);
+                println!("Import the chain up to height {} with 'importchain' to validate the snapshot", snapshot.height);
+                self.start_background_validation();
+            },
+            Err(e) => println!("Error: {e}"),
+        }
+    }
+
+    // 'exportchain <file>'
+    fn cli_export_chain(&self, instruction: Instruction) {
+        if instruction.args.len() != 1 {
+            println!("Wrong number of arguments");
+            return;
+        }
+
+        match chainfile::export_chain(&self.database, &instruction.args[0]) {
+            Ok(count) => println!("{} blocks written to {}", count, instruction.args[0]),
+            Err(e) => println!("Error: {e}"),
+        }
+    }
+
+    // 'importchain <file>', blocks below a loaded snapshot are queued for its background validation
+    fn cli_import_chain(&mut self, instruction: Instruction) {
+        if instruction.args.len() != 1 {
+            println!("Wrong number of arguments");
+            return;
+        }
+
+        let background = &self.background;
+        let result = chainfile::import_chain(&self.database, &instruction.args[0], BLOCK_REWARD, self.difficulty, |block| {
+            if let Some(background) = background {
+                background.add_block(block);
+            }
+            Ok(())
+        });
+        match result {
+            Ok(summary) => {
+                println!("{} blocks connected, {} blocks already known", summary.connected, summary.known);
+                if summary.history > 0 {
+                    println!("{} blocks queued for the background validation of the UTXO snapshot", summary.history);
+                }
+            },
+            Err(e) => println!("Error: {e}"),
+        }
+    }
+
+    // 'verifychain [depth]', the last DEFAULT_CHECK_DEPTH blocks by default and every block for a depth of 0
+    fn cli_verify_chain(&self, instruction: Instruction) {
+        if instruction.args.len() > 1 {
+            println!("Wrong number of arguments");
+            return;
+        }
+        let depth = match instruction.args.first().map(|depth| depth.parse::<u32>()) {
+            Some(Ok(depth)) => depth,
+            Some(Err(_)) => { println!("Please, provide a valid depth"); return; },
+            None => DEFAULT_CHECK_DEPTH,
+        };
+
+        let progress = |checked: u32, total: u32| {
+            if checked.is_multiple_of(PROGRESS_INTERVAL) || checked == total {
+                println!("Checked {} of {} blocks", checked, total);
+            }
+        };
+        match verify::verify_chain(&self.database, depth, BLOCK_REWARD, self.difficulty, progress) {
+            Ok(corruptions) if corruptions.is_empty() => println!("No corruption found"),
+            Ok(corruptions) => {
+                corruptions.iter().for_each(|corruption| println!("Corruption: {}", corruption));
+                println!("{} problems found, run 'reindex' to rebuild the chain state from the stored blocks", corruptions.len());
+            },
+            Err(e) => println!("Error: {e}"),
+        }
+    }
+
+    // 'reindex'
+    fn cli_reindex(&self, instruction: Instruction) {
+        if !instruction.args.is_empty() {
+            println!("Wrong number of arguments");
+            return;
+        }
+
+        let progress = |height: u32, tip: u32| {
+            if height.is_multiple_of(PROGRESS_INTERVAL) || height == tip {
+                println!("Reindexed block {} of {}", height, tip);
+            }
+        };
+        match verify::reindex(&self.database, BLOCK_REWARD, self.difficulty, progress) {
+            Ok(height) => println!("Chain state rebuilt up to block {}", height),
+            Err(e) => println!("Error: {e}"),
+        }
+    }
+
+    // 'invalidateblock <hash or height>': disconnect the block and every block above it from the active chain.
+    //     The blocks stay stored, but their transactions are not returned to the transaction pool
+    fn cli_invalidate_block(&self, instruction: Instruction) {
+        if instruction.args.len() != 1 {
+            println!("Wrong number of arguments");
+            return;
+        }
+
+        let hash = match self.find_block_hash(&instruction.args[0]) {
+            Ok(Some(hash)) => hash,
+            Ok(None) => { println!("Block not found"); return; },
+            Err(e) => { println!("Error: {e}"); return; },
+        };
+        match (self.database.invalidate_block(&hash), self.database.get_height()) {
+            (Ok(count), Ok(Some(tip))) => println!("{} blocks disconnected, the last block is now block {}", count, tip),
+            (Err(rocks::DatabaseError::UnexpectedBlock), _) => println!("Please, provide a block of the active chain above the genesis block"),
+            (Err(e), _) | (_, Err(e)) => println!("Error: {e}"),
+            (Ok(_), Ok(None)) => println!("Error: the chain is empty"),
+        }
+    }
+}
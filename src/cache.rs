@@ -0,0 +1,171 @@
+/// Cache.rs
+///
+/// In-memory layer in front of the 'utxo' column family (see utxo.rs). Connecting a block reads every
+///     output it spends, so the UtxoCache keeps recently read and created entries in memory, and buffers
+///     the changes of the connected blocks instead of writing the UTXO set with each block.
+///
+/// Each cached entry is either:
+///     - clean: same as the stored entry, kept to serve reads
+///     - dirty: created or spent since the last flush, and not stored yet. A spent entry is kept (as
+///       None) until the flush deletes the stored one, unless it is fresh: created after the last flush,
+///       so never stored, in which case spending it just forgets it
+///
+/// The Database flushes the dirty entries in a single batch, along with the hash of the block the stored
+///     UTXO set now matches (the UTXO tip), when the cache is full, every UTXO_FLUSH_INTERVAL blocks and
+///     when it is dropped. If the program stops before a flush, the stored UTXO set is behind the last
+///     block, and the blocks above the UTXO tip are connected again when the Database is opened.
+
+use std::collections::HashMap;
+
+use crate::utxo::{OutPoint, UtxoEntry};
+
+// Memory of the UTXO cache when none is given, in bytes
+pub const DEFAULT_UTXO_CACHE_SIZE: usize = 100_000_000;
+// Blocks connected between two flushes, at most
+pub const UTXO_FLUSH_INTERVAL: u32 = 500;
+// Estimated memory used by a cached entry (key, entry, flags and hash map overhead), in bytes
+pub const CACHE_ENTRY_SIZE: usize = 160;
+
+#[derive(Debug, Clone)]
+struct CacheEntry {
+    entry: Option<UtxoEntry>, // None when spent
+    dirty: bool,
+    fresh: bool,
+}
+
+pub struct UtxoCache {
+    entries: HashMap<OutPoint, CacheEntry>,
+    max_size: usize, // In bytes, 0 to write the UTXO set with each block
+    blocks_since_flush: u32,
+}
+
+
+impl UtxoCache {
+    pub fn new(max_size: usize) -> UtxoCache {
+        UtxoCache { entries: HashMap::new(), max_size, blocks_since_flush: 0 }
+    }
+
+    // Some(None) if 'outpoint' is known to be spent, None if it is not cached
+    pub fn get(&self, outpoint: &OutPoint) -> Option<Option<&UtxoEntry>> {
+        self.entries.get(outpoint).map(|cached| cached.entry.as_ref())
+    }
+
+    // Keep an entry read from the storage, if there is room for it
+    pub fn insert_clean(&mut self, outpoint: OutPoint, entry: UtxoEntry) {
+        if self.get_size() + CACHE_ENTRY_SIZE <= self.max_size {
+            self.entries.entry(outpoint).or_insert(CacheEntry { entry: Some(entry), dirty: false, fresh: false });
+        }
+    }
+
+    // Record the changes of a connected block: outputs created (Some) and spent (None)
+    pub fn apply<'a, I>(&mut self, changes: I)
+    where I: IntoIterator<Item = (&'a OutPoint, &'a Option<UtxoEntry>)> {
+        for (outpoint, entry) in changes {
+            match entry {
+                Some(entry) => {
+                    // An output spent since the last flush is still stored, until the flush writes over it
+                    let fresh = !matches!(self.entries.get(outpoint), Some(CacheEntry { entry: None, dirty: true, .. }));
+                    self.entries.insert(*outpoint, CacheEntry { entry: Some(entry.clone()), dirty: true, fresh });
+                },
+                None => match self.entries.get(outpoint) {
+                    Some(cached) if cached.fresh => { self.entries.remove(outpoint); },
+                    _ => { self.entries.insert(*outpoint, CacheEntry { entry: None, dirty: true, fresh: false }); },
+                },
+            }
+        }
+        self.blocks_since_flush += 1;
+    }
+
+    // Entries to write (Some) and delete (None) by the next flush
+    pub fn get_dirty(&self) -> impl Iterator<Item = (&OutPoint, Option<&UtxoEntry>)> {
+        self.entries.iter().filter(|(_, cached)| cached.dirty).map(|(outpoint, cached)| (outpoint, cached.entry.as_ref()))
+    }
+
+    pub fn get_blocks_since_flush(&self) -> u32 {
+        self.blocks_since_flush
+    }
+
+    pub fn get_dirty_count(&self) -> usize {
+        self.entries.values().filter(|cached| cached.dirty).count()
+    }
+
+    // Whether the next block, changing 'changes' entries, must be written along with the UTXO set
+    pub fn needs_flush(&self, changes: usize) -> bool {
+        self.max_size == 0
+            || self.get_size() + changes * CACHE_ENTRY_SIZE > self.max_size
+            || self.blocks_since_flush + 1 >= UTXO_FLUSH_INTERVAL
+    }
+
+    // The dirty entries were written: they become clean, and spent entries are forgotten. Everything is
+    //     forgotten when the cache is more than half full, to make room for the next blocks
+    pub fn mark_flushed(&mut self) {
+        self.entries.retain(|_, cached| cached.entry.is_some());
+        for cached in self.entries.values_mut() {
+            cached.dirty = false;
+            cached.fresh = false;
+        }
+        if self.get_size() > self.max_size / 2 {
+            self.entries.clear();
+        }
+        self.blocks_since_flush = 0;
+    }
+
+    // Forget every entry, when the stored UTXO set is replaced
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.blocks_since_flush = 0;
+    }
+
+    pub fn get_count(&self) -> usize {
+        self.entries.len()
+    }
+
+    // Estimated memory used by the cached entries, in bytes
+    pub fn get_size(&self) -> usize {
+        self.entries.len() * CACHE_ENTRY_SIZE
+    }
+
+    pub fn get_max_size(&self) -> usize {
+        self.max_size
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::transaction::TxOut;
+    use super::*;
+
+    fn entry(amount: f32) -> Option<UtxoEntry> {
+        Some(UtxoEntry::new(TxOut::new(amount, [1u8; 20]), 1, false))
+    }
+
+    #[test]
+    fn test_utxo_cache() {
+        let mut cache = UtxoCache::new(10 * CACHE_ENTRY_SIZE);
+        let stored = OutPoint::new([1u8; 32], 0);
+        let created = OutPoint::new([2u8; 32], 0);
+        cache.insert_clean(stored, entry(1.0).unwrap());
+        assert_eq!(cache.get_dirty_count(), 0);
+
+        // Spending a stored entry keeps it until the flush, spending a fresh entry forgets it
+        cache.apply(vec![(&stored, &None), (&created, &entry(2.0))]);
+        assert!(matches!(cache.get(&stored), Some(None)));
+        assert_eq!(cache.get(&created).unwrap().unwrap().get_amount(), 2.0);
+        assert_eq!(cache.get_dirty_count(), 2);
+        cache.apply(vec![(&created, &None)]);
+        assert!(cache.get(&created).is_none());
+        assert_eq!(cache.get_dirty().collect::<Vec<_>>().len(), 1);
+
+        // Flushed entries are clean, spent entries are forgotten
+        cache.apply(vec![(&created, &entry(3.0))]);
+        cache.mark_flushed();
+        assert_eq!(cache.get_dirty_count(), 0);
+        assert!(cache.get(&stored).is_none());
+        assert_eq!(cache.get(&created).unwrap().unwrap().get_amount(), 3.0);
+
+        assert!(!cache.needs_flush(8));
+        assert!(cache.needs_flush(10));
+        assert!(UtxoCache::new(0).needs_flush(1));
+    }
+}
@@ -0,0 +1,237 @@
+/// Chainfile.rs
+///
+/// Flat-file export of the active chain, used to seed offline nodes and to archive chains (see the
+///     'exportchain' and 'importchain' blockchain commands).
+///
+/// File format: CHAIN_FILE_MAGIC, then one record per block from the genesis block to the last block.
+///     Each record is the length of the block (u32 big-endian) followed by the bincode serialized Block.
+///
+/// Imported blocks go through the same checks as mined blocks (see validation.rs) before being connected.
+///     Blocks already in the active chain are skipped, so a file can be imported again after new blocks
+///     were added to it. When a UTXO snapshot is loaded, the blocks below the snapshot are handed over to
+///     its background validation instead (see snapshot.rs).
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+
+use thiserror::Error;
+
+use crate::block::Block;
+use crate::database::{Database, Direction};
+use crate::rocks::DatabaseError;
+use crate::snapshot::SnapshotError;
+use crate::validation::{self, ValidationError};
+
+const CHAIN_FILE_MAGIC: &[u8; 8] = b"CHAINEXP";
+// Upper bound of a record, so that a corrupted length cannot exhaust memory
+const MAX_RECORD_SIZE: usize = 32_000_000;
+
+#[derive(Error, Debug)]
+pub enum ChainFileError {
+    Io(#[from] std::io::Error),
+    Serialize(#[from] Box<bincode::ErrorKind>),
+    Database(#[from] DatabaseError),
+    Snapshot(#[from] SnapshotError),
+    InvalidFile,
+    Truncated,
+    RecordTooLarge(usize),
+    Pruned,
+    InvalidHash(u32),
+    InvalidBlock(u32, ValidationError),
+    ConflictingBlock(u32),
+    MissingParent(u32),
+}
+
+pub type Result<T> = std::result::Result<T, ChainFileError>;
+
+impl std::fmt::Display for ChainFileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChainFileError::Io(e) => write!(f, "chain file error: {}", e),
+            ChainFileError::Serialize(_) => write!(f, "invalid block encoding in chain file"),
+            ChainFileError::Database(e) => write!(f, "{}", e),
+            ChainFileError::Snapshot(e) => write!(f, "{}", e),
+            ChainFileError::InvalidFile => write!(f, "not a chain file"),
+            ChainFileError::Truncated => write!(f, "chain file ends in the middle of a block"),
+            ChainFileError::RecordTooLarge(size) => write!(f, "chain file record of {} bytes exceeds the maximum size", size),
+            ChainFileError::Pruned => write!(f, "the database is pruned, older blocks cannot be exported"),
+            ChainFileError::InvalidHash(height) => write!(f, "block at height {} does not match its hash", height),
+            ChainFileError::InvalidBlock(height, e) => write!(f, "invalid block at height {}: {}", height, e),
+            ChainFileError::ConflictingBlock(height) => write!(f, "block at height {} conflicts with the active chain", height),
+            ChainFileError::MissingParent(height) => write!(f, "block at height {} does not extend the active chain", height),
+        }
+    }
+}
+
+// Blocks read by 'import_chain'
+#[derive(Debug, Default)]
+pub struct ImportSummary {
+    pub connected: u32,
+    pub known: u32,   // Already in the active chain
+    pub history: u32, // Handed over to the background validation of a snapshot
+}
+
+
+// Write every block of the active chain to 'path', and return the number of blocks written
+pub fn export_chain(database: &Database, path: &str) -> Result<u32> {
+    if database.is_pruned()? {
+        return Err(ChainFileError::Pruned);
+    }
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all(CHAIN_FILE_MAGIC)?;
+    let mut count = 0;
+    for block in database.iter_chain(Direction::Forward)? {
+        let data = bincode::serialize(&block?)?;
+        writer.write_all(&(data.len() as u32).to_be_bytes())?;
+        writer.write_all(&data)?;
+        count += 1;
+    }
+    writer.flush()?;
+    Ok(count)
+}
+
+// Validate and connect the blocks of the file at 'path' which extend the active chain. Blocks below a
+//     loaded snapshot are passed to 'add_history_block'
+pub fn import_chain<F>(database: &Database, path: &str, reward: f32, difficulty: u8, mut add_history_block: F) -> Result<ImportSummary>
+where F: FnMut(Block) -> std::result::Result<(), SnapshotError> {
+    let mut summary = ImportSummary::default();
+    // Held for the whole file, so that blocks mined meanwhile cannot conflict with the imported ones
+    let chain_guard = database.lock_chain();
+    let snapshot_height = database.get_snapshot()?.map(|(height, _)| height);
+
+    for block in ChainFileReader::open(path)? {
+        let block = block?;
+        let height = block.get_index();
+        if !block.has_valid_hash() {
+            return Err(ChainFileError::InvalidHash(height));
+        }
+
+        let tip = database.get_height()?.unwrap_or(0);
+        if height <= tip {
+            if database.get_block_hash(height)? != Some(*block.get_hash()) {
+                return Err(ChainFileError::ConflictingBlock(height));
+            }
+            match snapshot_height {
+                Some(snapshot_height) if height > 0 && height <= snapshot_height => {
+                    add_history_block(block)?;
+                    summary.history += 1;
+                },
+                _ => summary.known += 1,
+            }
+            continue;
+        }
+
+        if height != tip + 1 {
+            return Err(ChainFileError::MissingParent(height));
+        }
+        validation::check_block(database, &block, reward, difficulty).map_err(|e| ChainFileError::InvalidBlock(height, e))?;
+        chain_guard.connect_block(&block).map_err(|e| match e {
+            DatabaseError::UnexpectedBlock => ChainFileError::MissingParent(height),
+            e => ChainFileError::Database(e),
+        })?;
+        summary.connected += 1;
+    }
+    Ok(summary)
+}
+
+
+// ------ Reading
+// Blocks of a chain file, in file order
+pub struct ChainFileReader {
+    reader: BufReader<File>,
+    done: bool,
+}
+
+impl ChainFileReader {
+    pub fn open(path: &str) -> Result<ChainFileReader> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut magic = [0u8; 8];
+        match reader.read_exact(&mut magic) {
+            Ok(()) if &magic == CHAIN_FILE_MAGIC => Ok(ChainFileReader { reader, done: false }),
+            Ok(()) => Err(ChainFileError::InvalidFile),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => Err(ChainFileError::InvalidFile),
+            Err(e) => Err(ChainFileError::Io(e)),
+        }
+    }
+
+    // Next block, None at the end of the file
+    fn read_block(&mut self) -> Result<Option<Block>> {
+        let mut length = [0u8; 4];
+        let mut read = 0;
+        while read < length.len() {
+            match self.reader.read(&mut length[read..])? {
+                0 if read == 0 => return Ok(None),
+                0 => return Err(ChainFileError::Truncated),
+                n => read += n,
+            }
+        }
+
+        let length = u32::from_be_bytes(length) as usize;
+        if length > MAX_RECORD_SIZE {
+            return Err(ChainFileError::RecordTooLarge(length));
+        }
+        let mut data = vec![0u8; length];
+        self.reader.read_exact(&mut data).map_err(|e| match e.kind() {
+            ErrorKind::UnexpectedEof => ChainFileError::Truncated,
+            _ => ChainFileError::Io(e),
+        })?;
+        Ok(Some(bincode::deserialize(&data)?))
+    }
+}
+
+impl Iterator for ChainFileReader {
+    type Item = Result<Block>;
+
+    fn next(&mut self) -> Option<Result<Block>> {
+        if self.done {
+            return None;
+        }
+        match self.read_block() {
+            Ok(Some(block)) => Some(Ok(block)),
+            Ok(None) => { self.done = true; None },
+            Err(e) => { self.done = true; Some(Err(e)) },
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::database::DatabaseOptions;
+    use crate::database::test_util::connect_blocks;
+    use crate::snapshot::compute_utxo_hash;
+    use super::*;
+
+    #[test]
+    fn test_export_and_import() {
+        let database = Database::open_in_memory(DatabaseOptions::default()).unwrap();
+        connect_blocks(&database, 3);
+
+        let path = std::env::temp_dir().join("chain-export-test.dat");
+        let path = path.to_str().unwrap();
+        assert_eq!(export_chain(&database, path).unwrap(), 4);
+
+        let node = Database::open_in_memory(DatabaseOptions::default()).unwrap();
+        let summary = import_chain(&node, path, 50.0, 0, |_| Ok(())).unwrap();
+        assert_eq!((summary.connected, summary.known), (3, 1));
+        assert_eq!(node.get_last_block_hash().unwrap(), database.get_last_block_hash().unwrap());
+        assert_eq!(compute_utxo_hash(&node.get_utxos().unwrap()).unwrap(), compute_utxo_hash(&database.get_utxos().unwrap()).unwrap());
+
+        // Known blocks are skipped, a block claiming too much reward is rejected
+        let summary = import_chain(&node, path, 50.0, 0, |_| Ok(())).unwrap();
+        assert_eq!((summary.connected, summary.known), (0, 4));
+        connect_blocks(&database, 1);
+        export_chain(&database, path).unwrap();
+        assert!(matches!(import_chain(&node, path, 25.0, 0, |_| Ok(())), Err(ChainFileError::InvalidBlock(4, _))));
+        // Blocks without enough proof of work are rejected
+        assert!(matches!(import_chain(&node, path, 50.0, 8, |_| Ok(())),
+            Err(ChainFileError::InvalidBlock(4, ValidationError::InsufficientWork))));
+
+        // A truncated file is detected
+        let data = std::fs::read(path).unwrap();
+        std::fs::write(path, &data[..data.len() - 1]).unwrap();
+        assert!(matches!(import_chain(&node, path, 50.0, 0, |_| Ok(())), Err(ChainFileError::Truncated)));
+        std::fs::remove_file(path).unwrap();
+    }
+}
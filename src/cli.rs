@@ -1,4 +1,4 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::io;
 use std::io::Write;
 use std::str::SplitWhitespace;
@@ -11,6 +11,7 @@ pub trait CLICommandExec {
 pub enum Program {
     WALLET,
     MINER,
+    BLOCKCHAIN,
 
     NONE,
 }
@@ -22,11 +23,39 @@ pub enum Command {
     GETADDRESS,
     SEND,
     SHOWUTXO,
+    PUBLISH,
+    LOOKUP,
+    CREATEPSBT,
+    CREATEWATCHPSBT,
+    SIGNPSBT,
+    COMBINEPSBT,
+    FINALIZEPSBT,
+    DECODEPSBT,
+    SCANBLOCKFILTERS,
 
     // Miner
     START,
     STOP,
     SHOWTXPOOL,
+    SENDRAWTRANSACTION,
+
+    // Blockchain
+    GETBLOCKHASH,
+    GETBLOCK,
+    LISTBLOCKS,
+    GETTRANSACTION,
+    GETADDRESSHISTORY,
+    GETADDRESSBALANCE,
+    GETBLOCKCHAININFO,
+    DUMPTXOUTSET,
+    LOADTXOUTSET,
+    EXPORTCHAIN,
+    IMPORTCHAIN,
+    VERIFYCHAIN,
+    REINDEX,
+    INVALIDATEBLOCK,
+    GETBLOCKFILTER,
+    GETFILTERHEADERS,
 
     NONE,
 }
@@ -36,6 +65,7 @@ pub struct Instruction {
     pub program: Program,
     pub command: Command,
     pub args: Vec<String>,
+    pub params: HashMap<String, String>, // Named arguments, typed as 'name=value'
     pub options: HashSet<char>,
 }
 
@@ -45,6 +75,7 @@ impl Instruction {
             program: Program::NONE,
             command: Command::NONE,
             args: vec![],
+            params: HashMap::new(),
             options: HashSet::new(),
         }
     }
@@ -104,6 +135,8 @@ impl CLI {
                         instruction.options.insert(c);
                     }
                 }
+            } else if let Some((name, value)) = w.split_once('=').filter(|(name, _)| is_param_name(name)) {
+                instruction.params.insert(name.to_string(), value.to_string());
             } else {
                 instruction.args.push(w.to_string());
             }
@@ -117,14 +150,47 @@ impl CLI {
             "getaddress"    => (Program::WALLET, Command::GETADDRESS),
             "showutxo"      => (Program::WALLET, Command::SHOWUTXO),
             "send"          => (Program::WALLET, Command::SEND),
+            "publish"       => (Program::WALLET, Command::PUBLISH),
+            "lookup"        => (Program::WALLET, Command::LOOKUP),
+            "createpsbt"    => (Program::WALLET, Command::CREATEPSBT),
+            "createwatchpsbt" => (Program::WALLET, Command::CREATEWATCHPSBT),
+            "signpsbt"      => (Program::WALLET, Command::SIGNPSBT),
+            "combinepsbt"   => (Program::WALLET, Command::COMBINEPSBT),
+            "finalizepsbt"  => (Program::WALLET, Command::FINALIZEPSBT),
+            "decodepsbt"    => (Program::WALLET, Command::DECODEPSBT),
+            "scanblockfilters" => (Program::WALLET, Command::SCANBLOCKFILTERS),
 
             // Miner
             "start"         => (Program::MINER, Command::START),
             "stop"          => (Program::MINER, Command::STOP),
             "showtxpool"    => (Program::MINER, Command::SHOWTXPOOL),
+            "sendrawtransaction" => (Program::MINER, Command::SENDRAWTRANSACTION),
+
+            // Blockchain
+            "getblockhash"  => (Program::BLOCKCHAIN, Command::GETBLOCKHASH),
+            "getblock"      => (Program::BLOCKCHAIN, Command::GETBLOCK),
+            "listblocks"    => (Program::BLOCKCHAIN, Command::LISTBLOCKS),
+            "gettransaction" => (Program::BLOCKCHAIN, Command::GETTRANSACTION),
+            "getaddresshistory" => (Program::BLOCKCHAIN, Command::GETADDRESSHISTORY),
+            "getaddressbalance" => (Program::BLOCKCHAIN, Command::GETADDRESSBALANCE),
+            "getblockchaininfo" => (Program::BLOCKCHAIN, Command::GETBLOCKCHAININFO),
+            "dumptxoutset"  => (Program::BLOCKCHAIN, Command::DUMPTXOUTSET),
+            "loadtxoutset"  => (Program::BLOCKCHAIN, Command::LOADTXOUTSET),
+            "exportchain"   => (Program::BLOCKCHAIN, Command::EXPORTCHAIN),
+            "importchain"   => (Program::BLOCKCHAIN, Command::IMPORTCHAIN),
+            "verifychain"   => (Program::BLOCKCHAIN, Command::VERIFYCHAIN),
+            "reindex"       => (Program::BLOCKCHAIN, Command::REINDEX),
+            "invalidateblock" => (Program::BLOCKCHAIN, Command::INVALIDATEBLOCK),
+            "getblockfilter" => (Program::BLOCKCHAIN, Command::GETBLOCKFILTER),
+            "getfilterheaders" => (Program::BLOCKCHAIN, Command::GETFILTERHEADERS),
 
             _ => (Program::NONE, Command::NONE)
         }
     }
 
-}
\ No newline at end of file
+}
+
+// Parameter names are lowercase words, so that arguments containing '=' (e.g. base64 padding) are kept as arguments
+fn is_param_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_lowercase())
+}
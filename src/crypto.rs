@@ -6,6 +6,13 @@ use base58::{FromBase58, ToBase58};
 use k256::{ecdsa::{SigningKey, Signature, signature::Signer}, PublicKey};
 use k256::{ecdsa::{VerifyingKey, signature::Verifier}};
 use k256::ecdsa::signature::SignatureEncoding;
+use k256::schnorr;
+use k256::schnorr::signature::hazmat::{PrehashVerifier, RandomizedPrehashSigner};
+use k256::elliptic_curve::{Field, PrimeField};
+use k256::elliptic_curve::ops::Reduce;
+use k256::elliptic_curve::point::DecompressPoint;
+use k256::elliptic_curve::subtle::Choice;
+use k256::{AffinePoint, FieldBytes, ProjectivePoint, Scalar, U256};
 
 use thiserror::Error;
 
@@ -18,6 +25,12 @@ pub enum CryptoError {
 
 pub type Result<T> = std::result::Result<T, CryptoError>;
 
+// Version bytes prefixed to the hash or key encoded in an address
+const PUB_KEY_HASH_ADDRESS_PREFIX: u8 = 0;
+const SCHNORR_ADDRESS_PREFIX: u8 = 1;
+
+const SCHNORR_CHALLENGE_TAG: &[u8] = b"BIP0340/challenge";
+
 impl std::fmt::Display for CryptoError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "crypto error")
@@ -31,6 +44,13 @@ pub fn calculate_sha256_hash(data: &[u8], buf : &mut [u8]) {
     buf.copy_from_slice(&hasher.finalize());
 }
 
+// Sha256(Sha256(data)), used for transaction hashes
+pub fn calculate_double_sha256_hash(data: &[u8], buf: &mut [u8]) {
+    let mut buffer = [0u8; 32];
+    calculate_sha256_hash(data, &mut buffer);
+    calculate_sha256_hash(&buffer, buf);
+}
+
 pub fn leading_zeros_count(hash: &str) -> u8 {
     let mut count = 0;
     let mut iter = hash.chars();
@@ -60,14 +80,14 @@ pub fn get_public_key(signing_key: &SigningKey) -> Vec<u8> {
 pub fn get_address(signing_key: SigningKey) -> String {
     let pub_key_hash = get_public_key_hash(&signing_key);
     // Create a variable result and apply changes to it until we get the final address
-    let mut result = add_prefix_to_public_key_hash(0, &pub_key_hash);
+    let mut result = add_prefix_to_public_key_hash(PUB_KEY_HASH_ADDRESS_PREFIX, &pub_key_hash);
     get_check_sum(&result).iter().for_each(|b| result.push(*b));
     result.to_base58()
 }
 
 pub fn address_to_public_key_hash(address: &String) -> Result<[u8; 20]> {
     if let Ok(mut pub_key_hash) = address.from_base58() {
-        if pub_key_hash.len() == 25 {
+        if pub_key_hash.len() == 25 && pub_key_hash[0] == PUB_KEY_HASH_ADDRESS_PREFIX {
             pub_key_hash.remove(0);
             pub_key_hash.drain(pub_key_hash.len()-4..);
 
@@ -80,6 +100,24 @@ pub fn address_to_public_key_hash(address: &String) -> Result<[u8; 20]> {
     Err(CryptoError::Base58DecodeError)
 }
 
+// Address of the x-only public key of 'signing_key', used by Schnorr outputs
+pub fn get_schnorr_address(signing_key: &SigningKey) -> String {
+    let mut result = add_prefix_to_public_key_hash(SCHNORR_ADDRESS_PREFIX, &get_schnorr_public_key(signing_key));
+    get_check_sum(&result).iter().for_each(|b| result.push(*b));
+    result.to_base58()
+}
+
+pub fn address_to_schnorr_public_key(address: &String) -> Result<[u8; 32]> {
+    if let Ok(decoded) = address.from_base58() {
+        if decoded.len() == 37 && decoded[0] == SCHNORR_ADDRESS_PREFIX {
+            let mut result = [0u8; 32];
+            result.copy_from_slice(&decoded[1..33]);
+            return Ok(result);
+        }
+    }
+    Err(CryptoError::Base58DecodeError)
+}
+
 // --- Keys/Address creation
 pub fn add_prefix_to_public_key_hash(prefix: u8, hash: &[u8]) -> Vec<u8> {
     let mut result = vec![prefix];
@@ -96,8 +134,13 @@ pub fn get_ripemd_hash(data: &[u8]) -> [u8; 20] {
 
 // Hash public key (sha256) and convert it to a 160 bytes hash (ripemd160)
 pub fn get_public_key_hash(signing_key: &SigningKey) -> [u8; 20] {
+    hash_public_key(&get_public_key(signing_key))
+}
+
+// Ripemd160(Sha256(public_key)), as used in transaction output destinations
+pub fn hash_public_key(public_key: &[u8]) -> [u8; 20] {
     let mut buffer = [0u8; 32];
-    calculate_sha256_hash(&get_public_key(signing_key), &mut buffer);
+    calculate_sha256_hash(public_key, &mut buffer);
     get_ripemd_hash(&buffer)
 }
 
@@ -130,6 +173,87 @@ pub fn verify_signature(public_key: &[u8], der_signature: &[u8], message: &[u8])
     Err(CryptoError::InvalidPubKey)
 }
 
+
+// --- Schnorr (BIP340)
+fn get_schnorr_signing_key(signing_key: &SigningKey) -> schnorr::SigningKey {
+    schnorr::SigningKey::from(*signing_key.as_nonzero_scalar())
+}
+
+// x-only public key
+pub fn get_schnorr_public_key(signing_key: &SigningKey) -> [u8; 32] {
+    get_schnorr_signing_key(signing_key).verifying_key().to_bytes().into()
+}
+
+// 'message' is signed as is, so it must already be a 32 bytes hash
+pub fn get_schnorr_signature(signing_key: &SigningKey, message: &[u8; 32]) -> Result<Vec<u8>> {
+    match get_schnorr_signing_key(signing_key).sign_prehash_with_rng(&mut OsRng, message) {
+        Ok(signature) => Ok(signature.to_bytes().to_vec()),
+        Err(_) => Err(CryptoError::InvalidSignature),
+    }
+}
+
+pub fn verify_schnorr_signature(public_key: &[u8], signature: &[u8], message: &[u8]) -> Result<bool> {
+    let verifying_key = schnorr::VerifyingKey::from_bytes(public_key).map_err(|_| CryptoError::InvalidPubKey)?;
+    let signature = parse_schnorr_signature(signature)?;
+    Ok(verifying_key.verify_prehash(message, &signature).is_ok())
+}
+
+fn parse_schnorr_signature(signature: &[u8]) -> Result<schnorr::Signature> {
+    if signature.len() != schnorr::Signature::BYTE_SIZE {
+        return Err(CryptoError::InvalidSignature);
+    }
+    schnorr::Signature::try_from(signature).map_err(|_| CryptoError::InvalidSignature)
+}
+
+// Signature to check as part of a batch
+pub struct SchnorrBatchItem {
+    pub public_key: Vec<u8>,
+    pub signature: Vec<u8>,
+    pub message: [u8; 32],
+}
+
+// Verify all signatures at once: with random weights a_i (a_0 = 1), the batch is valid if
+//     (sum a_i * s_i) * G == sum a_i * R_i + sum (a_i * e_i) * P_i
+// which costs a single combined check instead of one check per signature
+pub fn verify_schnorr_batch(items: &[SchnorrBatchItem]) -> Result<bool> {
+    let mut left = Scalar::ZERO;
+    let mut right = ProjectivePoint::IDENTITY;
+
+    for (i, item) in items.iter().enumerate() {
+        let verifying_key = schnorr::VerifyingKey::from_bytes(&item.public_key).map_err(|_| CryptoError::InvalidPubKey)?;
+        parse_schnorr_signature(&item.signature)?;
+
+        let (r_bytes, s_bytes) = item.signature.split_at(32);
+        let r_point: Option<AffinePoint> = AffinePoint::decompress(FieldBytes::from_slice(r_bytes), Choice::from(0)).into();
+        let s: Option<Scalar> = Scalar::from_repr(*FieldBytes::from_slice(s_bytes)).into();
+        let (r_point, s) = match (r_point, s) {
+            (Some(r_point), Some(s)) => (r_point, s),
+            _ => return Ok(false),
+        };
+
+        let mut challenge = tagged_hash(SCHNORR_CHALLENGE_TAG);
+        challenge.update(r_bytes);
+        challenge.update(verifying_key.to_bytes());
+        challenge.update(item.message);
+        let e = <Scalar as Reduce<U256>>::reduce_bytes(&challenge.finalize());
+
+        let a = if i == 0 { Scalar::ONE } else { Scalar::random(&mut OsRng) };
+        left += a * s;
+        right += ProjectivePoint::from(r_point) * a + ProjectivePoint::from(*verifying_key.as_affine()) * (a * e);
+    }
+
+    Ok(ProjectivePoint::GENERATOR * left == right)
+}
+
+// Sha256(Sha256(tag) || Sha256(tag) || ...), ready to be updated with the data
+fn tagged_hash(tag: &[u8]) -> Sha256 {
+    let tag_hash = Sha256::digest(tag);
+    let mut hasher = Sha256::new();
+    hasher.update(tag_hash);
+    hasher.update(tag_hash);
+    hasher
+}
+
 #[cfg(test)]
 mod tests {
     use k256::ecdsa::{DerSignature, Signature, SigningKey, VerifyingKey};
@@ -154,4 +278,42 @@ mod tests {
         //let signature_hex = hex::decode(signature).unwrap();
         assert!(verify_signature(get_public_key(&signing_key).as_slice(), signature.as_slice(), b"data").unwrap())
     }
+
+    #[test]
+    fn test_verify_schnorr_signature() {
+        let signing_key = SigningKey::from_slice(hex::decode("ae1af0af67c13ee57a00d770c157247f55bf793769e73f05ebc7be08062ea347").unwrap().as_slice()).unwrap();
+        let message = [7u8; 32];
+        let signature = get_schnorr_signature(&signing_key, &message).unwrap();
+
+        assert_eq!(signature.len(), 64);
+        assert!(verify_schnorr_signature(&get_schnorr_public_key(&signing_key), &signature, &message).unwrap());
+        assert!(!verify_schnorr_signature(&get_schnorr_public_key(&signing_key), &signature, &[8u8; 32]).unwrap());
+    }
+
+    #[test]
+    fn test_verify_schnorr_batch() {
+        let mut items: Vec<SchnorrBatchItem> = (0..4u8).map(|i| {
+            let signing_key = create_signing_key();
+            let message = [i; 32];
+            SchnorrBatchItem {
+                public_key: get_schnorr_public_key(&signing_key).to_vec(),
+                signature: get_schnorr_signature(&signing_key, &message).unwrap(),
+                message,
+            }
+        }).collect();
+
+        assert!(verify_schnorr_batch(&items).unwrap());
+
+        items[2].message = [9u8; 32];
+        assert!(!verify_schnorr_batch(&items).unwrap());
+    }
+
+    #[test]
+    fn test_schnorr_address() {
+        let signing_key = create_signing_key();
+        let address = get_schnorr_address(&signing_key);
+
+        assert_eq!(address_to_schnorr_public_key(&address).unwrap(), get_schnorr_public_key(&signing_key));
+        assert!(address_to_public_key_hash(&address).is_err());
+    }
 }
@@ -1,46 +1,230 @@
+use std::collections::{BTreeMap, HashMap};
 use std::marker::PhantomData;
-use std::rc::Rc;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
 use bincode::deserialize;
-use crate::block::Block;
-use crate::rocks::{Rocks, LedgerColumn, columns, Result, ColumnName, ColumnType};
+use serde::{Deserialize, Serialize};
+use crate::block::{Block, BlockHeader};
+use crate::cache::UtxoCache;
+use crate::crypto;
+use crate::filter;
+use crate::migration;
+use crate::transaction::{Lock, Transaction};
+use crate::memory::MemoryStorage;
+pub use crate::rocks::Direction;
+use crate::rocks::{Rocks, Storage, LedgerColumn, WriteBatch, columns, DatabaseError, Result, ColumnName, ColumnType};
+use crate::snapshot::UtxoSnapshot;
+use crate::utxo::{BlockUndo, OutPoint, UtxoEntry, OUTPOINT_KEY_SIZE};
+
+// Version of the layout of the stored data. It must be increased, with a migration (see migration.rs),
+//     whenever a stored type or key changes
+pub const SCHEMA_VERSION: u32 = 5;
+const SCHEMA_VERSION_KEY: &[u8] = b"schema_version";
+// Lowest height whose block is still stored, only set once the database has been pruned
+const PRUNE_HEIGHT_KEY: &[u8] = b"prune_height";
+// Height of the loaded UTXO snapshot, until its history is validated
+const SNAPSHOT_HEIGHT_KEY: &[u8] = b"snapshot_height";
+// Lowest height included in the data index, only set when the blocks below it were never indexed
+const DATA_INDEX_HEIGHT_KEY: &[u8] = b"data_index_height";
+
+// Blocks (and their undo data) which are never pruned, so that reorganizations up to this depth remain possible
+pub const MIN_BLOCKS_TO_KEEP: u32 = 100;
 
 pub enum BlockHashKeys {
     Genesis,
     LastBlock,
+    AddressIndexTip, // Last block included in the address index
+    SnapshotUtxoHash, // Content hash of the loaded UTXO snapshot, until its history is validated
+    BlockFilterTip, // Last block included in the block filter index
+    UtxoTip, // Last block included in the stored UTXO set, behind the last block until the UTXO cache is flushed
 }
 
 impl BlockHashKeys {
     pub fn to_bytes(&self) -> &[u8] {
         match self {
             BlockHashKeys::Genesis => b"genesis",
-            BlockHashKeys::LastBlock => b"last_block"
+            BlockHashKeys::LastBlock => b"last_block",
+            BlockHashKeys::AddressIndexTip => b"address_index_tip",
+            BlockHashKeys::SnapshotUtxoHash => b"snapshot_utxo_hash",
+            BlockHashKeys::BlockFilterTip => b"block_filter_tip",
+            BlockHashKeys::UtxoTip => b"utxo_tip",
         }
     }
 }
 
+// Optional indexes, maintained when blocks are connected, pruning and the size of the UTXO cache
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DatabaseOptions {
+    pub txindex: bool, // Transaction hash -> block and position
+    pub addressindex: bool, // Destination (public key hash or Schnorr key) + outpoint -> output received and its spending
+    pub blockfilterindex: bool, // Block hash -> compact block filter and filter header (see filter.rs)
+    pub prune: Option<PruneTarget>, // Delete old blocks, incompatible with the indexes
+    pub utxo_cache: usize, // Memory of the UTXO cache in bytes (see cache.rs), 0 writes the UTXO set with each block
+}
+
+// Blocks kept by a pruned database, never less than the last MIN_BLOCKS_TO_KEEP blocks
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PruneTarget {
+    Depth(u32), // Number of most recent blocks
+    Size(u64),  // Total size in bytes of the most recent blocks
+}
+
+// Compact block filter of a block, and its position in the filter header chain
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FilterEntry {
+    pub filter: Vec<u8>,
+    pub header: [u8; 32],
+}
+
+// Header of a stored block, kept when the block is pruned
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HeaderEntry {
+    pub header: BlockHeader,
+    pub size: u64, // Serialized size of the block
+}
+
+// Location of a transaction in the active chain
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TxIndexEntry {
+    pub block_hash: [u8; 32],
+    pub position: u32, // Index of the transaction in the block
+}
+
+// Output received by an address, and the input spending it if any
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AddressEvent {
+    pub outpoint: OutPoint,
+    pub amount: f32,
+    pub height: u32,
+    pub spent_by: Option<SpendingInput>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SpendingInput {
+    pub tx_hash: [u8; 32],
+    pub height: u32,
+}
+
+// Transaction of the active chain committing to some data in a data output, and its block
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DataCommitment {
+    pub block_hash: [u8; 32],
+    pub height: u32,
+    pub tx_hash: [u8; 32],
+}
+
+// Iterate over the blocks of the active chain between two heights (both included), using the height index
+pub struct ChainIterator<'a> {
+    database: &'a Database,
+    next: Option<u32>,
+    start: u32,
+    end: u32,
+    direction: Direction,
+}
+
+// Exclusive access to the chain state (see 'Database::lock_chain'). Blocks are only connected and
+//     disconnected through a ChainGuard, so an update made of several steps holding one, e.g. a reindex,
+//     is never interleaved with blocks connected by other threads, e.g. the miner
+pub struct ChainGuard<'a> {
+    database: &'a Database,
+    _lock: MutexGuard<'a, ()>,
+}
+
 pub struct Database {
-    db: Rc<Rocks>,
+    db: Arc<dyn Storage>,
+    options: DatabaseOptions,
     block_cf: LedgerColumn<columns::Block>,
     block_hash_cf: LedgerColumn<columns::BlockHash>,
+    utxo_cf: LedgerColumn<columns::Utxo>,
+    undo_cf: LedgerColumn<columns::Undo>,
+    block_height_cf: LedgerColumn<columns::BlockHeight>,
+    tx_index_cf: LedgerColumn<columns::TxIndex>,
+    address_index_cf: LedgerColumn<columns::AddressIndex>,
+    meta_cf: LedgerColumn<columns::Meta>,
+    header_cf: LedgerColumn<columns::Header>,
+    block_filter_cf: LedgerColumn<columns::BlockFilter>,
+    data_index_cf: LedgerColumn<columns::DataIndex>,
+    utxo_cache: Mutex<UtxoCache>,
+    chain_lock: Mutex<()>, // Held by the ChainGuard, while the chain state is updated
 }
 
 impl Database {
     pub fn open(path: &str) -> Result<Database> {
-        let db = Rc::new(Rocks::open(path)?);
-        let block_cf = LedgerColumn::new(Rc::clone(&db));
-        let block_hash_cf = LedgerColumn::new(Rc::clone(&db));
+        Database::open_with_options(path, DatabaseOptions::default())
+    }
 
-        Ok(Database {
+    // Open the RocksDB database at 'path'
+    pub fn open_with_options(path: &str, options: DatabaseOptions) -> Result<Database> {
+        Database::with_storage(Arc::new(Rocks::open(path)?), options)
+    }
+
+    // Open an empty database kept in memory, dropped along with it
+    pub fn open_in_memory(options: DatabaseOptions) -> Result<Database> {
+        Database::with_storage(Arc::new(MemoryStorage::new()), options)
+    }
+
+    // Open the database stored in 'db', upgrade its schema, connect the genesis block if it is empty and
+    //     build the enabled indexes
+    pub fn with_storage(db: Arc<dyn Storage>, options: DatabaseOptions) -> Result<Database> {
+        let block_cf = LedgerColumn::new(Arc::clone(&db));
+        let block_hash_cf = LedgerColumn::new(Arc::clone(&db));
+        let utxo_cf = LedgerColumn::new(Arc::clone(&db));
+        let undo_cf = LedgerColumn::new(Arc::clone(&db));
+        let block_height_cf = LedgerColumn::new(Arc::clone(&db));
+        let tx_index_cf = LedgerColumn::new(Arc::clone(&db));
+        let address_index_cf = LedgerColumn::new(Arc::clone(&db));
+        let meta_cf = LedgerColumn::new(Arc::clone(&db));
+        let header_cf = LedgerColumn::new(Arc::clone(&db));
+        let block_filter_cf = LedgerColumn::new(Arc::clone(&db));
+        let data_index_cf = LedgerColumn::new(Arc::clone(&db));
+
+        let database = Database {
             db,
+            options,
             block_cf,
             block_hash_cf,
-        })
+            utxo_cf,
+            undo_cf,
+            block_height_cf,
+            tx_index_cf,
+            address_index_cf,
+            meta_cf,
+            header_cf,
+            block_filter_cf,
+            data_index_cf,
+            utxo_cache: Mutex::new(UtxoCache::new(options.utxo_cache)),
+            chain_lock: Mutex::new(()),
+        };
+
+        // Indexes are built from the blocks, which a pruned database does not have anymore
+        if (options.txindex || options.addressindex || options.blockfilterindex) && (options.prune.is_some() || database.is_pruned()?) {
+            return Err(DatabaseError::InvalidOptions("the transaction, address and block filter indexes cannot be used with pruning"));
+        }
+
+        migration::upgrade(&database)?;
+        database.replay_utxo_changes()?;
+        if database.get_last_block()?.is_none() {
+            database.connect_block(&Block::genesis())?;
+        }
+        if options.txindex {
+            database.catch_up_tx_index()?;
+        }
+        if options.addressindex && database.block_hash_cf.get(BlockHashKeys::AddressIndexTip.to_bytes())? != database.block_hash_cf.get(BlockHashKeys::LastBlock.to_bytes())? {
+            database.rebuild_address_index()?;
+        }
+        if options.blockfilterindex && database.block_hash_cf.get(BlockHashKeys::BlockFilterTip.to_bytes())? != database.block_hash_cf.get(BlockHashKeys::LastBlock.to_bytes())? {
+            database.rebuild_block_filters()?;
+        }
+        Ok(database)
     }
 
-    pub fn get_block(&self, hash: &[gu8; 32]) -> Result<Option<Block>> {
+    pub fn get_block(&self, hash: &[u8; 32]) -> Result<Option<Block>> {
         self.block_cf.get(hash)
     }
 
+    pub fn get_last_block_hash(&self) -> Result<Option<[u8; 32]>> {
+        self.block_hash_cf.get(BlockHashKeys::LastBlock.to_bytes())
+    }
+
     pub fn get_last_block(&self) -> Result<Option<Block>> {
         if let Some(block_hash) = self.block_hash_cf.get(BlockHashKeys::LastBlock.to_bytes())? {
             if let Some(block) = self.block_cf.get(&block_hash)? {
@@ -50,35 +234,930 @@ impl Database {
         Ok(None)
     }
 
-    pub fn put_block(&self, block: &Block) -> Result<()> {
-        self.block_cf.put(block.get_hash(), block)
+    // --- Schema
+    pub fn get_schema_version(&self) -> Result<Option<u32>> {
+        self.meta_cf.get(SCHEMA_VERSION_KEY)
+    }
+
+    pub(crate) fn put_schema_version(&self, batch: &mut WriteBatch, version: u32) -> Result<()> {
+        self.meta_cf.put_batch(batch, SCHEMA_VERSION_KEY, &version)
+    }
+
+    pub(crate) fn write(&self, batch: WriteBatch) -> Result<()> {
+        self.db.write(batch)
+    }
+
+    // --- Active chain
+    // Height of the last block
+    pub fn get_height(&self) -> Result<Option<u32>> {
+        Ok(self.get_last_block()?.map(|block| block.get_index()))
+    }
+
+    // Hash of the block at 'height' in the active chain
+    pub fn get_block_hash(&self, height: u32) -> Result<Option<[u8; 32]>> {
+        self.block_height_cf.get(&height.to_be_bytes())
+    }
+
+    pub fn get_block_by_height(&self, height: u32) -> Result<Option<Block>> {
+        match self.get_block_hash(height)? {
+            Some(hash) => self.get_block(&hash),
+            None => Ok(None),
+        }
+    }
+
+    // Header of any stored block, including pruned blocks
+    pub fn get_header(&self, hash: &[u8; 32]) -> Result<Option<BlockHeader>> {
+        Ok(self.header_cf.get(hash)?.map(|entry| entry.header))
+    }
+
+    pub fn get_header_by_height(&self, height: u32) -> Result<Option<BlockHeader>> {
+        match self.get_block_hash(height)? {
+            Some(hash) => self.get_header(&hash),
+            None => Ok(None),
+        }
+    }
+
+    // Every block of the active chain which is still stored
+    pub fn iter_chain(&self, direction: Direction) -> Result<ChainIterator> {
+        let start = self.get_prune_height()?.unwrap_or(0);
+        let end = self.get_height()?.unwrap_or(0);
+        Ok(self.iter_chain_range(start, end, direction))
+    }
+
+    // Blocks of the active chain from height 'start' to 'end' (both included)
+    pub fn iter_chain_range(&self, start: u32, end: u32, direction: Direction) -> ChainIterator {
+        let next = match (start <= end, direction) {
+            (false, _) => None,
+            (true, Direction::Forward) => Some(start),
+            (true, Direction::Backward) => Some(end),
+        };
+        ChainIterator { database: self, next, start, end, direction }
+    }
+
+
+    // --- Chain state
+    // The cache stays locked while the stored entry is read, so that a flush cannot make it outdated
+    //     before it is cached
+    pub fn get_utxo(&self, outpoint: &OutPoint) -> Result<Option<UtxoEntry>> {
+        let mut cache = self.lock_cache();
+        if let Some(entry) = cache.get(outpoint) {
+            return Ok(entry.cloned());
+        }
+        let entry = self.utxo_cf.get(&outpoint.to_key())?;
+        if let Some(entry) = &entry {
+            cache.insert_clean(*outpoint, entry.clone());
+        }
+        Ok(entry)
+    }
+
+    // Every output of the UTXO set, in key order: the stored entries updated with the changes of the cache
+    pub fn get_utxos(&self) -> Result<Vec<(OutPoint, UtxoEntry)>> {
+        let cache = self.lock_cache();
+        let mut utxos = BTreeMap::new();
+        for item in self.utxo_cf.iter(Direction::Forward)? {
+            let (key, entry) = item?;
+            if let Some(outpoint) = OutPoint::from_key(&key) {
+                utxos.insert(outpoint.to_key(), (outpoint, entry));
+            }
+        }
+        for (outpoint, entry) in cache.get_dirty() {
+            match entry {
+                Some(entry) => { utxos.insert(outpoint.to_key(), (*outpoint, entry.clone())); },
+                None => { utxos.remove(&outpoint.to_key()); },
+            }
+        }
+        Ok(utxos.into_values().collect())
+    }
+
+    // UTXO changes of 'block' on top of the current UTXO set: entries created (Some) and spent (None), and
+    //     the undo data of the block. An output created and spent in the block is not a change
+    fn get_utxo_changes(&self, block: &Block) -> Result<(HashMap<OutPoint, Option<UtxoEntry>>, BlockUndo)> {
+        let mut changes = HashMap::new();
+        let mut undo = BlockUndo::default();
+        for tx in block.get_transactions() {
+            for input in tx.get_inputs() {
+                let outpoint = OutPoint::new(*input.get_prev_utxo(), input.get_n());
+                let entry = match changes.remove(&outpoint) {
+                    Some(Some(entry)) => entry,
+                    Some(None) => return Err(DatabaseError::MissingOutput),
+                    None => {
+                        let entry = self.get_utxo(&outpoint)?.ok_or(DatabaseError::MissingOutput)?;
+                        changes.insert(outpoint, None);
+                        entry
+                    },
+                };
+                undo.spent.push((outpoint, entry));
+            }
+
+            for (n, output) in tx.get_outputs().iter().enumerate() {
+                if !output.is_unspendable() {
+                    let entry = UtxoEntry::new(output.clone(), block.get_index(), tx.is_coinbase());
+                    changes.insert(OutPoint::new(*tx.get_hash(), n as u32), Some(entry));
+                }
+            }
+        }
+        Ok((changes, undo))
+    }
+
+    // Write the entries changed since the last flush, then 'changes', to the stored UTXO set
+    fn put_utxo_changes(&self, batch: &mut WriteBatch, cache: &UtxoCache, changes: &HashMap<OutPoint, Option<UtxoEntry>>) -> Result<()> {
+        for (outpoint, entry) in cache.get_dirty().chain(changes.iter().map(|(outpoint, entry)| (outpoint, entry.as_ref()))) {
+            match entry {
+                Some(entry) => self.utxo_cf.put_batch(batch, &outpoint.to_key(), entry)?,
+                None => self.utxo_cf.delete_batch(batch, &outpoint.to_key()),
+            }
+        }
+        Ok(())
+    }
+
+    // Write the changes buffered by the UTXO cache, in a single batch along with the UTXO tip
+    pub fn flush(&self) -> Result<()> {
+        let _chain = self.lock_chain();
+        match self.get_last_block_hash()? {
+            Some(hash) => self.flush_to(&hash),
+            None => Ok(()),
+        }
+    }
+
+    // Write the changes buffered by the UTXO cache, which include every block up to 'tip'
+    fn flush_to(&self, tip: &[u8; 32]) -> Result<()> {
+        let mut cache = self.lock_cache();
+        if cache.get_blocks_since_flush() == 0 {
+            return Ok(());
+        }
+        let mut batch = WriteBatch::new();
+        self.put_utxo_changes(&mut batch, &cache, &HashMap::new())?;
+        self.block_hash_cf.put_batch(&mut batch, BlockHashKeys::UtxoTip.to_bytes(), tip)?;
+        self.db.write(batch)?;
+        cache.mark_flushed();
+        Ok(())
+    }
+
+    // Locks are only held for single updates which leave the chain state consistent, so they remain
+    //     usable after a thread panicked while holding them
+    fn lock_cache(&self) -> MutexGuard<'_, UtxoCache> {
+        self.utxo_cache.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Wait until no other thread updates the chain state, and keep it for the caller until the guard is dropped
+    pub fn lock_chain(&self) -> ChainGuard<'_> {
+        ChainGuard { database: self, _lock: self.chain_lock.lock().unwrap_or_else(PoisonError::into_inner) }
+    }
+
+    // Entries and unflushed entries of the UTXO cache, and its maximum size in bytes
+    pub fn get_utxo_cache_usage(&self) -> (usize, usize, usize) {
+        let cache = self.lock_cache();
+        (cache.get_count(), cache.get_dirty_count(), cache.get_max_size())
+    }
+
+    // Connect again the blocks above the UTXO tip, whose changes were still in the UTXO cache when the
+    //     program stopped. Their blocks and undo data are already stored, only the UTXO set is updated
+    fn replay_utxo_changes(&self) -> Result<()> {
+        let last_block = match self.get_last_block()? {
+            Some(block) => block,
+            None => return Ok(()),
+        };
+        let start = match self.block_hash_cf.get(BlockHashKeys::UtxoTip.to_bytes())? {
+            Some(hash) if &hash == last_block.get_hash() => return Ok(()),
+            Some(hash) => self.get_header(&hash)?.ok_or(DatabaseError::MissingBlock)?.index + 1,
+            None => 0,
+        };
+
+        println!("Updating the UTXO set with blocks {} to {}", start, last_block.get_index());
+        for height in start..=last_block.get_index() {
+            let block = self.get_block_by_height(height)?.ok_or(DatabaseError::MissingBlock)?;
+            let (changes, _) = self.get_utxo_changes(&block)?;
+            let flush = {
+                let mut cache = self.lock_cache();
+                let flush = cache.needs_flush(changes.len());
+                cache.apply(&changes);
+                flush
+            };
+            if flush {
+                self.flush_to(block.get_hash())?;
+            }
+        }
+        self.flush_to(last_block.get_hash())
+    }
+
+    // Store 'block', which must extend the last block (or be the first block of an empty chain), spend
+    //     the outputs referenced by its transactions, add the outputs it creates and make it the last block.
+    //     The spent entries are stored as the undo data of the block. Everything is written in a single
+    //     batch, so the chain tip, undo data and indexes always match. The UTXO changes are kept by the
+    //     UTXO cache, and written in the same batch when the cache is flushed. The block is expected to
+    //     be valid (see validation.rs)
+    pub fn connect_block(&self, block: &Block) -> Result<()> {
+        self.lock_chain().connect_block(block)
+    }
+
+    fn connect_block_locked(&self, block: &Block) -> Result<()> {
+        match self.get_last_block()? {
+            Some(last_block) if last_block.get_hash() == block.get_prev_hash() => (),
+            None if block.get_index() == 0 => (),
+            _ => return Err(DatabaseError::UnexpectedBlock),
+        }
+
+        let mut batch = WriteBatch::new();
+        let (changes, undo) = self.get_utxo_changes(block)?;
+        let header = HeaderEntry { header: block.get_header(), size: bincode::serialized_size(block)? };
+        self.block_cf.put_batch(&mut batch, block.get_hash(), block)?;
+        self.header_cf.put_batch(&mut batch, block.get_hash(), &header)?;
+        self.undo_cf.put_batch(&mut batch, block.get_hash(), &undo)?;
+        self.block_height_cf.put_batch(&mut batch, &block.get_index().to_be_bytes(), block.get_hash())?;
+        if self.options.txindex {
+            self.index_transactions(&mut batch, block)?;
+        }
+        if self.options.addressindex {
+            self.index_addresses(&mut batch, block, &undo)?;
+            self.block_hash_cf.put_batch(&mut batch, BlockHashKeys::AddressIndexTip.to_bytes(), block.get_hash())?;
+        }
+        if self.options.blockfilterindex {
+            self.index_block_filter(&mut batch, block)?;
+            self.block_hash_cf.put_batch(&mut batch, BlockHashKeys::BlockFilterTip.to_bytes(), block.get_hash())?;
+        }
+        self.index_data(&mut batch, block)?;
+        if block.get_index() == 0 {
+            self.block_hash_cf.put_batch(&mut batch, BlockHashKeys::Genesis.to_bytes(), block.get_hash())?;
+        }
+        self.block_hash_cf.put_batch(&mut batch, BlockHashKeys::LastBlock.to_bytes(), block.get_hash())?;
+        if let Some(target) = self.options.prune {
+            self.prune_blocks(&mut batch, block.get_index(), target)?;
+        }
+
+        // The cache only changes once the block is written, and stays locked meanwhile so that readers see
+        //     the UTXO set either before or after the block
+        let mut cache = self.lock_cache();
+        let flush = cache.needs_flush(changes.len());
+        if flush {
+            self.put_utxo_changes(&mut batch, &cache, &changes)?;
+            self.block_hash_cf.put_batch(&mut batch, BlockHashKeys::UtxoTip.to_bytes(), block.get_hash())?;
+        }
+        self.db.write(batch)?;
+        cache.apply(&changes);
+        if flush {
+            cache.mark_flushed();
+        }
+        Ok(())
+    }
+
+    // Disconnect a single block, for tests. The node disconnects blocks with 'invalidate_block'
+    #[cfg(test)]
+    pub fn disconnect_block(&self, block: &Block) -> Result<()> {
+        self.lock_chain().disconnect_block(block)
+    }
+
+    fn disconnect_block_locked(&self, block: &Block) -> Result<()> {
+        match self.get_last_block()? {
+            Some(last_block) if last_block.get_hash() == block.get_hash() && block.get_index() > 0 => (),
+            _ => return Err(DatabaseError::UnexpectedBlock),
+        }
+        let mut undo = self.get_block_undo(block.get_hash())?.ok_or(DatabaseError::MissingUndo)?;
+        self.flush_to(block.get_hash())?;
+
+        let mut batch = WriteBatch::new();
+        if self.options.addressindex {
+            self.unindex_addresses(&mut batch, block, &undo)?;
+            self.block_hash_cf.put_batch(&mut batch, BlockHashKeys::AddressIndexTip.to_bytes(), block.get_prev_hash())?;
+        }
+        if self.options.blockfilterindex {
+            self.block_hash_cf.put_batch(&mut batch, BlockHashKeys::BlockFilterTip.to_bytes(), block.get_prev_hash())?;
+        }
+        // Like index entries, the filter cannot outlive its block in the active chain
+        self.block_filter_cf.delete_batch(&mut batch, block.get_hash());
+        self.unindex_data(&mut batch, block);
+
+        // Entries are restored in reverse spending order, so that an output created and spent in the
+        //     block is restored before being removed with the outputs of its transaction
+        for tx in block.get_transactions().iter().rev() {
+            // Index entries are removed even if the index is disabled, so that none can outlive its block
+            self.tx_index_cf.delete_batch(&mut batch, tx.get_hash());
+            for n in 0..tx.get_outputs().len() {
+                self.utxo_cf.delete_batch(&mut batch, &OutPoint::new(*tx.get_hash(), n as u32).to_key());
+            }
+
+            for _ in tx.get_inputs() {
+                let (outpoint, entry) = undo.spent.pop().ok_or(DatabaseError::MissingUndo)?;
+                self.utxo_cf.put_batch(&mut batch, &outpoint.to_key(), &entry)?;
+            }
+        }
+
+        self.undo_cf.delete_batch(&mut batch, block.get_hash());
+        self.block_height_cf.delete_batch(&mut batch, &block.get_index().to_be_bytes());
+        self.block_hash_cf.put_batch(&mut batch, BlockHashKeys::LastBlock.to_bytes(), block.get_prev_hash())?;
+        self.block_hash_cf.put_batch(&mut batch, BlockHashKeys::UtxoTip.to_bytes(), block.get_prev_hash())?;
+
+        // Cached entries may have been created or restored by the disconnection
+        let mut cache = self.lock_cache();
+        cache.clear();
+        self.db.write(batch)
+    }
+
+    // Disconnect 'hash' and every block above it from the active chain, and return the number of blocks
+    //     disconnected. Their transactions are not kept anywhere, they must be sent again
+    pub fn invalidate_block(&self, hash: &[u8; 32]) -> Result<u32> {
+        let chain_guard = self.lock_chain();
+        let height = match self.get_header(hash)? {
+            Some(header) if header.index > 0 && self.get_block_hash(header.index)? == Some(*hash) => header.index,
+            _ => return Err(DatabaseError::UnexpectedBlock),
+        };
+
+        let mut disconnected = 0;
+        while let Some(last_block) = self.get_last_block()? {
+            if last_block.get_index() < height {
+                break;
+            }
+            chain_guard.disconnect_block(&last_block)?;
+            disconnected += 1;
+        }
+        Ok(disconnected)
+    }
+
+    pub fn get_block_undo(&self, hash: &[u8; 32]) -> Result<Option<BlockUndo>> {
+        self.undo_cf.get(hash)
+    }
+
+    // Store the header of every stored block, for databases created before the header column
+    pub(crate) fn put_headers(&self, batch: &mut WriteBatch) -> Result<()> {
+        for item in self.block_cf.iter(Direction::Forward)? {
+            let (hash, block) = item?;
+            let header = HeaderEntry { header: block.get_header(), size: bincode::serialized_size(&block)? };
+            self.header_cf.put_batch(batch, &hash, &header)?;
+        }
+        Ok(())
+    }
+
+    // Delete the UTXO set, undo data, height index and optional indexes, so that they can be rebuilt by
+    //     connecting the stored blocks again. Blocks and headers are kept
+    fn clear_chain_state(&self) -> Result<()> {
+        let mut cache = self.lock_cache();
+        cache.clear();
+        let mut batch = WriteBatch::new();
+        for item in self.utxo_cf.iter(Direction::Forward)? {
+            self.utxo_cf.delete_batch(&mut batch, &item?.0);
+        }
+        for item in self.undo_cf.iter(Direction::Forward)? {
+            self.undo_cf.delete_batch(&mut batch, &item?.0);
+        }
+        for item in self.block_height_cf.iter(Direction::Forward)? {
+            self.block_height_cf.delete_batch(&mut batch, &item?.0);
+        }
+        for item in self.tx_index_cf.iter(Direction::Forward)? {
+            self.tx_index_cf.delete_batch(&mut batch, &item?.0);
+        }
+        for item in self.address_index_cf.iter(Direction::Forward)? {
+            self.address_index_cf.delete_batch(&mut batch, &item?.0);
+        }
+        for item in self.block_filter_cf.iter(Direction::Forward)? {
+            self.block_filter_cf.delete_batch(&mut batch, &item?.0);
+        }
+        for item in self.data_index_cf.iter(Direction::Forward)? {
+            self.data_index_cf.delete_batch(&mut batch, &item?.0);
+        }
+        self.meta_cf.delete_batch(&mut batch, DATA_INDEX_HEIGHT_KEY);
+        self.block_hash_cf.delete_batch(&mut batch, BlockHashKeys::AddressIndexTip.to_bytes());
+        self.block_hash_cf.delete_batch(&mut batch, BlockHashKeys::BlockFilterTip.to_bytes());
+        self.block_hash_cf.delete_batch(&mut batch, BlockHashKeys::UtxoTip.to_bytes());
+        self.block_hash_cf.delete_batch(&mut batch, BlockHashKeys::LastBlock.to_bytes());
+        self.db.write(batch)
+    }
+
+    // Transaction index entry of 'tx_hash', whether the index is enabled or not
+    pub(crate) fn get_tx_index_entry(&self, tx_hash: &[u8; 32]) -> Result<Option<TxIndexEntry>> {
+        self.tx_index_cf.get(tx_hash)
+    }
+
+    pub(crate) fn get_address_index_tip(&self) -> Result<Option<[u8; 32]>> {
+        self.block_hash_cf.get(BlockHashKeys::AddressIndexTip.to_bytes())
+    }
+
+    // Record that the stored UTXO set includes every block, for databases created before the UTXO cache
+    pub(crate) fn put_utxo_tip(&self, batch: &mut WriteBatch) -> Result<()> {
+        match self.get_last_block_hash()? {
+            Some(hash) => self.block_hash_cf.put_batch(batch, BlockHashKeys::UtxoTip.to_bytes(), &hash),
+            None => Ok(()),
+        }
+    }
+
+    // --- Pruning
+    pub fn get_options(&self) -> &DatabaseOptions {
+        &self.options
+    }
+
+    // Lowest height whose block is stored, None if no block was ever pruned
+    pub fn get_prune_height(&self) -> Result<Option<u32>> {
+        self.meta_cf.get(PRUNE_HEIGHT_KEY)
+    }
+
+    pub fn is_pruned(&self) -> Result<bool> {
+        self.meta_cf.exists(PRUNE_HEIGHT_KEY)
+    }
+
+    // Delete the blocks and undo data which 'target' does not keep anymore once the block at height 'tip'
+    //     is connected by 'batch'. Headers and the UTXO set are kept, so new blocks can still be validated
+    fn prune_blocks(&self, batch: &mut WriteBatch, tip: u32, target: PruneTarget) -> Result<()> {
+        let prune_height = self.get_prune_height()?.unwrap_or(0);
+        let window_start = (tip + 1).saturating_sub(MIN_BLOCKS_TO_KEEP);
+
+        // Lowest height to keep
+        let keep_from = match target {
+            PruneTarget::Depth(depth) => (tip + 1).saturating_sub(depth.max(MIN_BLOCKS_TO_KEEP)),
+            PruneTarget::Size(budget) => {
+                let mut keep_from = tip + 1;
+                let mut size = 0;
+                while keep_from > prune_height {
+                    let hash = self.block_height_cf.get_batch(batch, &(keep_from - 1).to_be_bytes())?.ok_or(DatabaseError::MissingBlock)?;
+                    let entry = self.header_cf.get_batch(batch, &hash)?.ok_or(DatabaseError::MissingBlock)?;
+                    if size + entry.size > budget {
+                        break;
+                    }
+                    size += entry.size;
+                    keep_from -= 1;
+                }
+                keep_from.min(window_start)
+            },
+        };
+        // Blocks above the stored UTXO set are needed to connect them again if the UTXO cache is not flushed
+        let utxo_height = match self.block_hash_cf.get_batch(batch, BlockHashKeys::UtxoTip.to_bytes())? {
+            Some(hash) => self.header_cf.get_batch(batch, &hash)?.map_or(0, |entry| entry.header.index),
+            None => 0,
+        };
+        let keep_from = keep_from.min(utxo_height + 1);
+        if keep_from <= prune_height {
+            return Ok(());
+        }
+
+        for height in prune_height..keep_from {
+            let hash = self.get_block_hash(height)?.ok_or(DatabaseError::MissingBlock)?;
+            self.block_cf.delete_batch(batch, &hash);
+            self.undo_cf.delete_batch(batch, &hash);
+        }
+        self.meta_cf.put_batch(batch, PRUNE_HEIGHT_KEY, &keep_from)
+    }
+
+    // --- UTXO snapshot
+    // Height and content hash of the loaded UTXO snapshot whose history is not validated yet
+    pub fn get_snapshot(&self) -> Result<Option<(u32, [u8; 32])>> {
+        match (self.meta_cf.get(SNAPSHOT_HEIGHT_KEY)?, self.block_hash_cf.get(BlockHashKeys::SnapshotUtxoHash.to_bytes())?) {
+            (Some(height), Some(utxo_hash)) => Ok(Some((height, utxo_hash))),
+            _ => Ok(None),
+        }
+    }
+
+    // Replace the chain by the chain of 'snapshot', which must be verified (see snapshot.rs). Only the
+    //     last block of the snapshot is stored, so the database is pruned below it
+    fn load_snapshot(&self, snapshot: &UtxoSnapshot) -> Result<()> {
+        // The blocks below the snapshot are never connected, the indexes would miss them
+        if self.options.txindex || self.options.addressindex || self.options.blockfilterindex {
+            return Err(DatabaseError::InvalidOptions("a snapshot cannot be loaded with the transaction, address or block filter index enabled"));
+        }
+        let mut cache = self.lock_cache();
+        cache.clear();
+        let mut batch = WriteBatch::new();
+        for item in self.utxo_cf.iter(Direction::Forward)? {
+            let (key, _) = item?;
+            self.utxo_cf.delete_batch(&mut batch, &key);
+        }
+        for item in self.block_cf.iter(Direction::Forward)? {
+            let (hash, _) = item?;
+            self.block_cf.delete_batch(&mut batch, &hash);
+            self.undo_cf.delete_batch(&mut batch, &hash);
+        }
+        for item in self.data_index_cf.iter(Direction::Forward)? {
+            let (key, _) = item?;
+            self.data_index_cf.delete_batch(&mut batch, &key);
+        }
+
+        // The size of the blocks of the snapshot is unknown, only the last one is stored
+        for header in &snapshot.headers {
+            let entry = HeaderEntry { header: header.clone(), size: 0 };
+            self.header_cf.put_batch(&mut batch, &header.hash, &entry)?;
+            self.block_height_cf.put_batch(&mut batch, &header.index.to_be_bytes(), &header.hash)?;
+        }
+        let tip = &snapshot.tip;
+        let entry = HeaderEntry { header: tip.get_header(), size: bincode::serialized_size(tip)? };
+        self.header_cf.put_batch(&mut batch, tip.get_hash(), &entry)?;
+        self.block_cf.put_batch(&mut batch, tip.get_hash(), tip)?;
+        self.index_data(&mut batch, tip)?;
+        self.meta_cf.put_batch(&mut batch, DATA_INDEX_HEIGHT_KEY, &snapshot.height)?;
+
+        for (outpoint, entry) in &snapshot.utxos {
+            self.utxo_cf.put_batch(&mut batch, &outpoint.to_key(), entry)?;
+        }
+        self.block_hash_cf.put_batch(&mut batch, BlockHashKeys::LastBlock.to_bytes(), tip.get_hash())?;
+        self.block_hash_cf.put_batch(&mut batch, BlockHashKeys::UtxoTip.to_bytes(), tip.get_hash())?;
+        self.meta_cf.put_batch(&mut batch, PRUNE_HEIGHT_KEY, &snapshot.height)?;
+        self.meta_cf.put_batch(&mut batch, SNAPSHOT_HEIGHT_KEY, &snapshot.height)?;
+        self.block_hash_cf.put_batch(&mut batch, BlockHashKeys::SnapshotUtxoHash.to_bytes(), &snapshot.utxo_hash)?;
+        self.db.write(batch)
+    }
+
+    // Forget the loaded snapshot, once its history is validated
+    pub(crate) fn clear_snapshot(&self) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        self.meta_cf.delete_batch(&mut batch, SNAPSHOT_HEIGHT_KEY);
+        self.block_hash_cf.delete_batch(&mut batch, BlockHashKeys::SnapshotUtxoHash.to_bytes());
+        self.db.write(batch)
+    }
+
+    // --- Transaction index
+    pub fn is_tx_index_enabled(&self) -> bool {
+        self.options.txindex
+    }
+
+    // Transaction 'tx_hash' and the block of the active chain containing it, if the index is enabled
+    pub fn get_transaction(&self, tx_hash: &[u8; 32]) -> Result<Option<(Transaction, Block)>> {
+        let entry = match self.tx_index_cf.get(tx_hash)? {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        let block = self.get_block(&entry.block_hash)?.ok_or(DatabaseError::MissingBlock)?;
+        let tx = block.get_transactions().get(entry.position as usize).cloned().ok_or(DatabaseError::MissingBlock)?;
+        Ok(Some((tx, block)))
+    }
+
+    fn index_transactions(&self, batch: &mut WriteBatch, block: &Block) -> Result<()> {
+        for (position, tx) in block.get_transactions().iter().enumerate() {
+            let entry = TxIndexEntry { block_hash: *block.get_hash(), position: position as u32 };
+            self.tx_index_cf.put_batch(batch, tx.get_hash(), &entry)?;
+        }
+        Ok(())
+    }
+
+    // Index the blocks connected while the index was disabled. They are the most recent blocks, so the
+    //     chain is walked back until a block whose coinbase is already indexed
+    fn catch_up_tx_index(&self) -> Result<()> {
+        for block in self.iter_chain(Direction::Backward)? {
+            let block = block?;
+            let coinbase_indexed = match block.get_transactions().iter().find(|tx| tx.is_coinbase()) {
+                Some(coinbase) => self.tx_index_cf.get(coinbase.get_hash())?.is_some(),
+                None => false,
+            };
+            if coinbase_indexed {
+                break;
+            }
+
+            let mut batch = WriteBatch::new();
+            self.index_transactions(&mut batch, &block)?;
+            self.db.write(batch)?;
+        }
+        Ok(())
+    }
+
+    // --- Address index
+    pub fn is_address_index_enabled(&self) -> bool {
+        self.options.addressindex
+    }
+
+    // Outputs received by 'destination' (see 'get_destination_key'), oldest first
+    pub fn get_address_history(&self, destination: &[u8]) -> Result<Vec<AddressEvent>> {
+        let mut history = Vec::new();
+        for item in self.address_index_cf.iter_prefix(destination, Direction::Forward)? {
+            let (key, event) = item?;
+            // Rows of longer destinations starting with 'destination' share the prefix
+            if key.len() == destination.len() + OUTPOINT_KEY_SIZE {
+                history.push(event);
+            }
+        }
+        history.sort_by_key(|event| event.height);
+        Ok(history)
+    }
+
+    pub fn get_address_balance(&self, destination: &[u8]) -> Result<f32> {
+        let history = self.get_address_history(destination)?;
+        Ok(history.iter().filter(|event| event.spent_by.is_none()).map(|event| event.amount).sum())
+    }
+
+    // Add the outputs received in 'block' to the index, and mark the outputs it spends ('undo') as spent
+    fn index_addresses(&self, batch: &mut WriteBatch, block: &Block, undo: &BlockUndo) -> Result<()> {
+        let mut spent = undo.spent.iter();
+        for tx in block.get_transactions() {
+            for _ in tx.get_inputs() {
+                let (outpoint, entry) = spent.next().ok_or(DatabaseError::MissingUndo)?;
+                let spent_by = Some(SpendingInput { tx_hash: *tx.get_hash(), height: block.get_index() });
+                self.update_address_event(batch, entry.get_lock(), outpoint, spent_by)?;
+            }
+
+            for (n, output) in tx.get_outputs().iter().enumerate() {
+                if let Some(destination) = get_destination_key(output.get_lock()) {
+                    let outpoint = OutPoint::new(*tx.get_hash(), n as u32);
+                    let event = AddressEvent { outpoint, amount: output.get_amount(), height: block.get_index(), spent_by: None };
+                    self.address_index_cf.put_batch(batch, &get_address_index_key(&destination, &outpoint), &event)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Undo 'index_addresses'
+    fn unindex_addresses(&self, batch: &mut WriteBatch, block: &Block, undo: &BlockUndo) -> Result<()> {
+        let mut spent = undo.spent.iter().rev();
+        for tx in block.get_transactions().iter().rev() {
+            for (n, output) in tx.get_outputs().iter().enumerate() {
+                if let Some(destination) = get_destination_key(output.get_lock()) {
+                    let outpoint = OutPoint::new(*tx.get_hash(), n as u32);
+                    self.address_index_cf.delete_batch(batch, &get_address_index_key(&destination, &outpoint));
+                }
+            }
+
+            for _ in tx.get_inputs() {
+                let (outpoint, entry) = spent.next().ok_or(DatabaseError::MissingUndo)?;
+                self.update_address_event(batch, entry.get_lock(), outpoint, None)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn update_address_event(&self, batch: &mut WriteBatch, lock: &Lock, outpoint: &OutPoint, spent_by: Option<SpendingInput>) -> Result<()> {
+        if let Some(destination) = get_destination_key(lock) {
+            let key = get_address_index_key(&destination, outpoint);
+            if let Some(mut event) = self.address_index_cf.get_batch(batch, &key)? {
+                event.spent_by = spent_by;
+                self.address_index_cf.put_batch(batch, &key, &event)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Remove every row of the address index along with its tip, so that it is built again on the next
+    //     open with the index enabled
+    pub(crate) fn clear_address_index(&self, batch: &mut WriteBatch) -> Result<()> {
+        for item in self.address_index_cf.iter(Direction::Forward)? {
+            let (key, _) = item?;
+            self.address_index_cf.delete_batch(batch, &key);
+        }
+        self.block_hash_cf.delete_batch(batch, BlockHashKeys::AddressIndexTip.to_bytes());
+        Ok(())
+    }
+
+    // Index the whole active chain again, when blocks were connected or disconnected while the index was
+    //     disabled. Each block is indexed in its own batch, along with the index tip
+    fn rebuild_address_index(&self) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        self.clear_address_index(&mut batch)?;
+        self.db.write(batch)?;
+
+        for block in self.iter_chain(Direction::Forward)? {
+            let block = block?;
+            let undo = self.get_block_undo(block.get_hash())?.ok_or(DatabaseError::MissingUndo)?;
+
+            let mut batch = WriteBatch::new();
+            self.index_addresses(&mut batch, &block, &undo)?;
+            self.block_hash_cf.put_batch(&mut batch, BlockHashKeys::AddressIndexTip.to_bytes(), block.get_hash())?;
+            self.db.write(batch)?;
+        }
+        Ok(())
+    }
+
+    // --- Block filter index
+    pub fn is_block_filter_index_enabled(&self) -> bool {
+        self.options.blockfilterindex
+    }
+
+    // Filter of the block 'hash' of the active chain, if the index is enabled
+    pub fn get_block_filter(&self, hash: &[u8; 32]) -> Result<Option<FilterEntry>> {
+        self.block_filter_cf.get(hash)
+    }
+
+    // Build the filter of 'block' and chain its header to the filter header of its parent
+    fn index_block_filter(&self, batch: &mut WriteBatch, block: &Block) -> Result<()> {
+        let prev_header = match block.get_index() {
+            0 => [0u8; 32],
+            _ => self.block_filter_cf.get_batch(batch, block.get_prev_hash())?.ok_or(DatabaseError::MissingBlock)?.header,
+        };
+        let filter = filter::build_filter(block);
+        let header = filter::get_filter_header(&filter, &prev_header);
+        self.block_filter_cf.put_batch(batch, block.get_hash(), &FilterEntry { filter, header })
+    }
+
+    // Build the filters of the whole active chain again, when blocks were connected or disconnected while
+    //     the index was disabled. Each block is indexed in its own batch, along with the index tip
+    fn rebuild_block_filters(&self) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        for item in self.block_filter_cf.iter(Direction::Forward)? {
+            let (key, _) = item?;
+            self.block_filter_cf.delete_batch(&mut batch, &key);
+        }
+        self.block_hash_cf.delete_batch(&mut batch, BlockHashKeys::BlockFilterTip.to_bytes());
+        self.db.write(batch)?;
+
+        for block in self.iter_chain(Direction::Forward)? {
+            let block = block?;
+            let mut batch = WriteBatch::new();
+            self.index_block_filter(&mut batch, &block)?;
+            self.block_hash_cf.put_batch(&mut batch, BlockHashKeys::BlockFilterTip.to_bytes(), block.get_hash())?;
+            self.db.write(batch)?;
+        }
+        Ok(())
+    }
+
+    // --- Data index
+    // Lowest height searched by 'find_data_commitment', None if every block of the active chain is indexed.
+    //     The blocks below a loaded snapshot, or pruned before the index existed, were never indexed
+    pub fn get_data_index_height(&self) -> Result<Option<u32>> {
+        self.meta_cf.get(DATA_INDEX_HEIGHT_KEY)
+    }
+
+    // Most recent transaction of the active chain committing to 'data' in a data output. Unlike the
+    //     optional indexes, the data index is always maintained and is kept when blocks are pruned
+    pub fn find_data_commitment(&self, data: &[u8]) -> Result<Option<DataCommitment>> {
+        match self.data_index_cf.iter_prefix(&get_data_index_prefix(data), Direction::Backward)?.next() {
+            Some(item) => Ok(Some(item?.1)),
+            None => Ok(None),
+        }
+    }
+
+    fn index_data(&self, batch: &mut WriteBatch, block: &Block) -> Result<()> {
+        for tx in block.get_transactions() {
+            for output in tx.get_outputs() {
+                if let Lock::Data(data) = output.get_lock() {
+                    let commitment = DataCommitment { block_hash: *block.get_hash(), height: block.get_index(), tx_hash: *tx.get_hash() };
+                    self.data_index_cf.put_batch(batch, &get_data_index_key(data, block.get_index(), tx.get_hash()), &commitment)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn unindex_data(&self, batch: &mut WriteBatch, block: &Block) {
+        for tx in block.get_transactions() {
+            for output in tx.get_outputs() {
+                if let Lock::Data(data) = output.get_lock() {
+                    self.data_index_cf.delete_batch(batch, &get_data_index_key(data, block.get_index(), tx.get_hash()));
+                }
+            }
+        }
+    }
+
+    // Index the data outputs of the stored blocks, in databases created before the data index. Pruned
+    //     blocks cannot be indexed anymore, so the index starts at the prune height
+    pub(crate) fn build_data_index(&self, batch: &mut WriteBatch) -> Result<()> {
+        for block in self.iter_chain(Direction::Forward)? {
+            self.index_data(batch, &block?)?;
+        }
+        if let Some(prune_height) = self.get_prune_height()? {
+            self.meta_cf.put_batch(batch, DATA_INDEX_HEIGHT_KEY, &prune_height)?;
+        }
+        Ok(())
+    }
+}
+
+// Key of the address index for outputs locked by 'lock': the public key hash or the Schnorr public key
+pub fn get_destination_key(lock: &Lock) -> Option<Vec<u8>> {
+    match lock {
+        Lock::PubKeyHash(destination) => Some(destination.to_vec()),
+        Lock::SchnorrKey(public_key) => Some(public_key.to_vec()),
+        Lock::Data(_) => None,
+    }
+}
+
+impl ChainGuard<'_> {
+    // See 'Database::connect_block'
+    pub fn connect_block(&self, block: &Block) -> Result<()> {
+        self.database.connect_block_locked(block)
+    }
+
+    // Undo 'connect_block' for the last block: remove the outputs it created, restore the entries it spent
+    //     from its undo data and make its parent the last block, in a single batch. The block itself stays stored.
+    //     Disconnecting is rare, so the UTXO cache is flushed first and the UTXO set is written directly
+    pub fn disconnect_block(&self, block: &Block) -> Result<()> {
+        self.database.disconnect_block_locked(block)
+    }
+
+    // See 'Database::clear_chain_state'
+    pub(crate) fn clear_chain_state(&self) -> Result<()> {
+        self.database.clear_chain_state()
+    }
+
+    // See 'Database::load_snapshot'
+    pub(crate) fn load_snapshot(&self, snapshot: &UtxoSnapshot) -> Result<()> {
+        self.database.load_snapshot(snapshot)
+    }
+}
+
+// Key of the address index row of 'outpoint', received by 'destination'
+fn get_address_index_key(destination: &[u8], outpoint: &OutPoint) -> Vec<u8> {
+    let mut key = destination.to_vec();
+    key.extend_from_slice(&outpoint.to_key());
+    key
+}
+
+// Prefix of the data index rows of 'data': Sha256(data)
+fn get_data_index_prefix(data: &[u8]) -> [u8; 32] {
+    let mut hash = [0u8; 32];
+    crypto::calculate_sha256_hash(data, &mut hash);
+    hash
+}
+
+// Key of the data index row of 'data', committed by 'tx_hash' at 'height'. The height follows the prefix
+//     in big endian, so that the most recent commitment is the last row of the prefix
+fn get_data_index_key(data: &[u8], height: u32, tx_hash: &[u8; 32]) -> Vec<u8> {
+    let mut key = get_data_index_prefix(data).to_vec();
+    key.extend_from_slice(&height.to_be_bytes());
+    key.extend_from_slice(tx_hash);
+    key
+}
+
+// Changes still in the UTXO cache would otherwise be connected again on the next open
+impl Drop for Database {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            println!("Error: could not write the UTXO cache: {e}");
+        }
+    }
+}
+
+impl<'a> Iterator for ChainIterator<'a> {
+    type Item = Result<Block>;
+
+    fn next(&mut self) -> Option<Result<Block>> {
+        let height = self.next?;
+        self.next = match self.direction {
+            Direction::Forward if height < self.end => Some(height + 1),
+            Direction::Backward if height > self.start => Some(height - 1),
+            _ => None,
+        };
+
+        match self.database.get_block_by_height(height) {
+            Ok(Some(block)) => Some(Ok(block)),
+            Ok(None) => { self.next = None; None },
+            Err(e) => { self.next = None; Some(Err(e)) },
+        }
     }
 }
 
+// Fixtures shared by the tests of the modules built on the Database
 #[cfg(test)]
+pub mod test_util {
+    use std::sync::Arc;
+
+    use crate::block::Block;
+    use crate::database::{Database, DatabaseOptions};
+    use crate::memory::MemoryStorage;
+    use crate::rocks::Storage;
+    use crate::transaction::Transaction;
 
+    // Database in memory, along with its storage so that tests can alter the columns or open it again
+    pub fn open_test_storage(options: DatabaseOptions) -> (Arc<dyn Storage>, Database) {
+        let db: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let database = Database::with_storage(Arc::clone(&db), options).unwrap();
+        (db, database)
+    }
+
+    // Block following 'prev_block' with 'transactions', whose coinbase pays 50 to [1u8; 20]
+    pub fn create_next_block(prev_block: &Block, transactions: Vec<Transaction>) -> Block {
+        let mut block = Block::new();
+        block.set_index(prev_block.get_index() + 1);
+        block.set_prev_hash_from_block(prev_block);
+        transactions.into_iter().for_each(|tx| block.add_transaction(tx));
+        block.mine(0, 50.0, [1u8; 20]).unwrap();
+        block
+    }
+
+    // Connect 'count' blocks without transactions on top of the active chain, and return the last block
+    //     of the chain before them followed by the connected blocks
+    pub fn connect_blocks(database: &Database, count: u32) -> Vec<Block> {
+        let mut blocks = vec![database.get_last_block().unwrap().unwrap()];
+        for _ in 0..count {
+            let block = create_next_block(blocks.last().unwrap(), Vec::new());
+            database.connect_block(&block).unwrap();
+            blocks.push(block);
+        }
+        blocks
+    }
+}
+
+#[cfg(test)]
 mod tests {
     use crate::block::Block;
-    use crate::database::{Database, LastBlockHash};
+    use crate::filter;
+    use crate::database::{BlockHashKeys, DataCommitment, Database, DatabaseError, DatabaseOptions, Direction, PruneTarget, MIN_BLOCKS_TO_KEEP};
+    use crate::database::test_util::{connect_blocks, create_next_block, open_test_storage};
+    use std::sync::Arc;
+    use crate::transaction::{Transaction, TxIn, TxOut};
+    use crate::utxo::OutPoint;
+
+    fn open_test_database() -> Database {
+        Database::open_in_memory(DatabaseOptions::default()).unwrap()
+    }
 
     #[test]
     fn add_meta() {
-        let storage = Database::open("database-test").unwrap();
+        let storage = open_test_database();
         let mut block = Block::new();
         block.calculate_hash();
 
         let meta1 = block.get_hash();
-        storage.meta_cf.put(b"last_block", &meta1.to_vec());
+        storage.block_hash_cf.put(b"last_block", meta1).unwrap();
 
-        let mut meta1_from_db = storage.meta_cf.get(b"last_block").unwrap().unwrap();
+        let meta1_from_db = storage.block_hash_cf.get(b"last_block").unwrap().unwrap();
 
 
-        assert_eq!(meta1.to_vec(), meta1_from_db)
+        assert_eq!(meta1, &meta1_from_db)
     }
 
     //#[test]
     fn add_block() {
-        let storage = Database::open("database-test").unwrap();
+        let storage = open_test_database();
 
         let mut block = Block::new();
         block.set_index(0);
@@ -89,4 +1168,334 @@ mod tests {
 
         assert_eq!(block.get_hash(), block_from_db.get_hash())
     }
+
+    #[test]
+    fn connect_and_disconnect_block() {
+        let storage = open_test_database();
+        let genesis = storage.get_last_block().unwrap().unwrap();
+        assert_eq!(genesis.get_hash(), Block::genesis().get_hash());
+        assert_eq!(storage.block_hash_cf.get(BlockHashKeys::Genesis.to_bytes()).unwrap().as_ref(), Some(genesis.get_hash()));
+
+        let block1 = create_next_block(&genesis, Vec::new());
+        storage.connect_block(&block1).unwrap();
+        let coinbase = OutPoint::new(*block1.get_transactions()[0].get_hash(), 0);
+        let entry = storage.get_utxo(&coinbase).unwrap().unwrap();
+        assert!(entry.coinbase);
+        assert_eq!(entry.height, 1);
+
+        // Spend the coinbase of block 1 into a payment and a data output, then spend the payment in the same block
+        let mut spend = Transaction::new(
+            vec![TxIn::new(0, vec![4u8; 65], *block1.get_transactions()[0].get_hash())],
+            vec![TxOut::new(40.0, [2u8; 20]), TxOut::new_data(vec![1, 2, 3])],
+        );
+        spend.hash();
+        let mut child = Transaction::new(
+            vec![TxIn::new(0, vec![4u8; 65], *spend.get_hash())],
+            vec![TxOut::new(30.0, [3u8; 20])],
+        );
+        child.hash();
+        let block2 = create_next_block(&block1, vec![spend.clone(), child.clone()]);
+        storage.connect_block(&block2).unwrap();
+        assert!(storage.get_utxo(&coinbase).unwrap().is_none());
+        assert_eq!(storage.get_block_undo(block2.get_hash()).unwrap().unwrap().spent.len(), 2);
+
+        storage.disconnect_block(&block2).unwrap();
+        storage.connect_block(&block2).unwrap();
+        assert!(storage.get_utxo(&OutPoint::new(*spend.get_hash(), 0)).unwrap().is_none());
+        assert_eq!(storage.get_utxo(&OutPoint::new(*child.get_hash(), 0)).unwrap().unwrap().get_amount(), 30.0);
+
+        let block3 = create_next_block(&block2, Vec::new());
+        storage.connect_block(&block3).unwrap();
+        storage.disconnect_block(&block3).unwrap();
+        storage.disconnect_block(&block2).unwrap();
+        assert!(storage.get_block_undo(block2.get_hash()).unwrap().is_none());
+        assert!(storage.get_utxo(&OutPoint::new(*child.get_hash(), 0)).unwrap().is_none());
+        storage.connect_block(&block2).unwrap();
+        assert!(storage.get_utxo(&OutPoint::new(*spend.get_hash(), 1)).unwrap().is_none());
+        assert!(storage.connect_block(&block2).is_err());
+
+        // Invalidating a block disconnects the blocks above it too
+        storage.connect_block(&block3).unwrap();
+        assert_eq!(storage.invalidate_block(block2.get_hash()).unwrap(), 2);
+        assert_eq!(storage.get_last_block().unwrap().unwrap().get_hash(), block1.get_hash());
+        assert!(matches!(storage.invalidate_block(block2.get_hash()), Err(DatabaseError::UnexpectedBlock)));
+        assert!(matches!(storage.invalidate_block(genesis.get_hash()), Err(DatabaseError::UnexpectedBlock)));
+        storage.connect_block(&block2).unwrap();
+
+        storage.disconnect_block(&block2).unwrap();
+        assert_eq!(storage.get_last_block().unwrap().unwrap().get_hash(), block1.get_hash());
+        assert!(storage.get_utxo(&OutPoint::new(*spend.get_hash(), 0)).unwrap().is_none());
+        let restored = storage.get_utxo(&coinbase).unwrap().unwrap();
+        assert!(restored.coinbase);
+        assert_eq!(restored.height, 1);
+        assert_eq!(storage.get_utxos().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn iterate_chain() {
+        let storage = open_test_database();
+        let blocks = connect_blocks(&storage, 4);
+
+        let hashes = |iter: Vec<crate::rocks::Result<Block>>| -> Vec<[u8; 32]> {
+            iter.into_iter().map(|block| *block.unwrap().get_hash()).collect()
+        };
+        let expected: Vec<[u8; 32]> = blocks.iter().map(|block| *block.get_hash()).collect();
+
+        assert_eq!(hashes(storage.iter_chain(Direction::Forward).unwrap().collect()), expected);
+        assert_eq!(hashes(storage.iter_chain(Direction::Backward).unwrap().collect()), expected.iter().rev().cloned().collect::<Vec<_>>());
+        assert_eq!(hashes(storage.iter_chain_range(1, 3, Direction::Forward).collect()), expected[1..=3].to_vec());
+        assert_eq!(hashes(storage.iter_chain_range(3, 10, Direction::Forward).collect()), expected[3..].to_vec());
+        assert_eq!(storage.iter_chain_range(3, 1, Direction::Backward).count(), 0);
+
+        storage.disconnect_block(&blocks[4]).unwrap();
+        assert_eq!(storage.get_height().unwrap(), Some(3));
+        assert!(storage.get_block_hash(4).unwrap().is_none());
+        assert_eq!(storage.get_block_by_height(2).unwrap().unwrap().get_hash(), blocks[2].get_hash());
+    }
+
+    #[test]
+    fn transaction_index() {
+        let (db, storage) = open_test_storage(DatabaseOptions::default());
+        let genesis = storage.get_last_block().unwrap().unwrap();
+        let block1 = create_next_block(&genesis, Vec::new());
+        storage.connect_block(&block1).unwrap();
+        let coinbase = *block1.get_transactions()[0].get_hash();
+        assert!(storage.get_transaction(&coinbase).unwrap().is_none());
+        drop(storage);
+
+        // Blocks connected before the index was enabled are indexed on open
+        let storage = Database::with_storage(db, DatabaseOptions { txindex: true, ..Default::default() }).unwrap();
+        let (tx, block) = storage.get_transaction(&coinbase).unwrap().unwrap();
+        assert_eq!(tx.get_hash(), &coinbase);
+        assert_eq!(block.get_hash(), block1.get_hash());
+        assert!(storage.get_transaction(genesis.get_transactions()[0].get_hash()).unwrap().is_some());
+
+        let block2 = create_next_block(&block1, Vec::new());
+        storage.connect_block(&block2).unwrap();
+        let coinbase2 = *block2.get_transactions()[0].get_hash();
+        assert_eq!(storage.get_transaction(&coinbase2).unwrap().unwrap().1.get_index(), 2);
+
+        storage.disconnect_block(&block2).unwrap();
+        assert!(storage.get_transaction(&coinbase2).unwrap().is_none());
+    }
+
+    #[test]
+    fn address_index() {
+        let options = DatabaseOptions { addressindex: true, ..Default::default() };
+        let (db, storage) = open_test_storage(options);
+        let genesis = storage.get_last_block().unwrap().unwrap();
+
+        // Block 1 pays 50 to [1u8; 20] (see create_next_block), block 2 spends 40 of it to [2u8; 20]
+        let block1 = create_next_block(&genesis, Vec::new());
+        storage.connect_block(&block1).unwrap();
+        let mut spend = Transaction::new(
+            vec![TxIn::new(0, vec![4u8; 65], *block1.get_transactions()[0].get_hash())],
+            vec![TxOut::new(40.0, [2u8; 20])],
+        );
+        spend.hash();
+        let block2 = create_next_block(&block1, vec![spend.clone()]);
+        storage.connect_block(&block2).unwrap();
+
+        let history = storage.get_address_history(&[1u8; 20]).unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].spent_by.unwrap().tx_hash, *spend.get_hash());
+        assert_eq!(history[0].spent_by.unwrap().height, 2);
+        assert_eq!(storage.get_address_balance(&[1u8; 20]).unwrap(), 50.0);
+        assert_eq!(storage.get_address_balance(&[2u8; 20]).unwrap(), 40.0);
+
+        storage.disconnect_block(&block2).unwrap();
+        assert!(storage.get_address_history(&[1u8; 20]).unwrap()[0].spent_by.is_none());
+        assert!(storage.get_address_history(&[2u8; 20]).unwrap().is_empty());
+        drop(storage);
+
+        // Blocks connected while the index is disabled are indexed on the next open with the index
+        let storage = Database::with_storage(Arc::clone(&db), DatabaseOptions::default()).unwrap();
+        storage.connect_block(&block2).unwrap();
+        drop(storage);
+        let storage = Database::with_storage(db, options).unwrap();
+        assert_eq!(storage.get_address_history(&[1u8; 20]).unwrap(), history);
+    }
+
+    #[test]
+    fn block_filter_index() {
+        let options = DatabaseOptions { blockfilterindex: true, ..Default::default() };
+        let (db, storage) = open_test_storage(options);
+        let genesis = storage.get_last_block().unwrap().unwrap();
+        let block1 = create_next_block(&genesis, Vec::new());
+        storage.connect_block(&block1).unwrap();
+
+        // Headers chain from the genesis filter, and the filter matches the coinbase destination of block 1
+        let genesis_entry = storage.get_block_filter(genesis.get_hash()).unwrap().unwrap();
+        assert_eq!(genesis_entry.header, filter::get_filter_header(&genesis_entry.filter, &[0u8; 32]));
+        let entry = storage.get_block_filter(block1.get_hash()).unwrap().unwrap();
+        assert_eq!(entry.header, filter::get_filter_header(&entry.filter, &genesis_entry.header));
+        assert!(filter::match_any(&entry.filter, block1.get_hash(), &[vec![1u8; 20]]).unwrap());
+
+        storage.disconnect_block(&block1).unwrap();
+        assert!(storage.get_block_filter(block1.get_hash()).unwrap().is_none());
+        drop(storage);
+
+        // Blocks connected while the index is disabled are indexed on the next open with the index
+        let storage = Database::with_storage(Arc::clone(&db), DatabaseOptions::default()).unwrap();
+        storage.connect_block(&block1).unwrap();
+        drop(storage);
+        let storage = Database::with_storage(db, options).unwrap();
+        assert_eq!(storage.get_block_filter(block1.get_hash()).unwrap().unwrap().header, entry.header);
+    }
+
+    #[test]
+    fn utxo_cache() {
+        let options = DatabaseOptions { utxo_cache: 1_000_000, ..Default::default() };
+        let (db, storage) = open_test_storage(options);
+        let blocks = connect_blocks(&storage, 3);
+
+        // Reads are served by the cache, the stored UTXO set is behind the last block
+        let coinbase = OutPoint::new(*blocks[3].get_transactions()[0].get_hash(), 0);
+        assert!(storage.get_utxo(&coinbase).unwrap().is_some());
+        assert!(storage.utxo_cf.get(&coinbase.to_key()).unwrap().is_none());
+        assert_eq!(storage.get_utxos().unwrap().len(), 4);
+        let expected = storage.get_utxos().unwrap();
+
+        // Changes lost when the program stops are connected again on open
+        std::mem::forget(storage);
+        let storage = Database::with_storage(Arc::clone(&db), options).unwrap();
+        assert!(storage.utxo_cf.get(&coinbase.to_key()).unwrap().is_some());
+        assert_eq!(storage.block_hash_cf.get(BlockHashKeys::UtxoTip.to_bytes()).unwrap().as_ref(), Some(blocks[3].get_hash()));
+        assert_eq!(format!("{:?}", storage.get_utxos().unwrap()), format!("{:?}", expected));
+
+        // Disconnecting writes the UTXO set directly, dropping the database flushes the cache
+        let block4 = create_next_block(&blocks[3], Vec::new());
+        storage.connect_block(&block4).unwrap();
+        storage.disconnect_block(&block4).unwrap();
+        assert_eq!(storage.utxo_cf.iter(Direction::Forward).unwrap().count(), 4);
+        storage.connect_block(&block4).unwrap();
+        drop(storage);
+        let storage = Database::with_storage(db, DatabaseOptions::default()).unwrap();
+        assert_eq!(storage.utxo_cf.iter(Direction::Forward).unwrap().count(), 5);
+    }
+
+    #[test]
+    fn shared_between_threads() {
+        let options = DatabaseOptions { utxo_cache: 1_000_000, ..Default::default() };
+        let storage = Arc::new(Database::open_in_memory(options).unwrap());
+        let mut last_block = storage.get_last_block().unwrap().unwrap();
+
+        // Readers never see the UTXO set go back while blocks are connected
+        let readers: Vec<_> = (0..2).map(|_| {
+            let storage = Arc::clone(&storage);
+            std::thread::spawn(move || {
+                let mut seen = 0;
+                for _ in 0..50 {
+                    let count = storage.get_utxos().unwrap().len();
+                    assert!(count >= seen);
+                    seen = count;
+                }
+            })
+        }).collect();
+        for _ in 0..20 {
+            let block = create_next_block(&last_block, Vec::new());
+            storage.connect_block(&block).unwrap();
+            last_block = block;
+        }
+        readers.into_iter().for_each(|reader| reader.join().unwrap());
+
+        // The same block connected by two threads is only connected once
+        let block = Arc::new(create_next_block(&last_block, Vec::new()));
+        let writers: Vec<_> = (0..2).map(|_| {
+            let (storage, block) = (Arc::clone(&storage), Arc::clone(&block));
+            std::thread::spawn(move || storage.connect_block(&block).is_ok())
+        }).collect();
+        let connected = writers.into_iter().map(|writer| writer.join().unwrap()).filter(|connected| *connected).count();
+        assert_eq!(connected, 1);
+        assert_eq!(storage.get_utxos().unwrap().len(), 22);
+    }
+
+    #[test]
+    fn prune_blocks() {
+        let options = DatabaseOptions { prune: Some(PruneTarget::Depth(10)), ..Default::default() };
+        let storage = Database::open_in_memory(options).unwrap();
+        let blocks = connect_blocks(&storage, MIN_BLOCKS_TO_KEEP + 10);
+
+        // The depth is raised to MIN_BLOCKS_TO_KEEP
+        assert_eq!(storage.get_prune_height().unwrap(), Some(11));
+        assert!(storage.get_block(blocks[10].get_hash()).unwrap().is_none());
+        assert!(storage.get_block_undo(blocks[10].get_hash()).unwrap().is_none());
+        assert_eq!(storage.get_header_by_height(10).unwrap().unwrap().hash, *blocks[10].get_hash());
+        assert!(storage.get_block(blocks[11].get_hash()).unwrap().is_some());
+        assert_eq!(storage.iter_chain(Direction::Forward).unwrap().count(), MIN_BLOCKS_TO_KEEP as usize);
+
+        // Recent blocks can still be disconnected, the UTXO set is complete
+        storage.disconnect_block(blocks.last().unwrap()).unwrap();
+        assert_eq!(storage.get_utxos().unwrap().len(), blocks.len() - 1);
+
+        // Indexes need every block
+        drop(storage);
+        let options = DatabaseOptions { txindex: true, prune: Some(PruneTarget::Size(0)), ..Default::default() };
+        assert!(matches!(Database::open_in_memory(options), Err(DatabaseError::InvalidOptions(_))));
+    }
+
+    #[test]
+    fn data_index() {
+        let options = DatabaseOptions { prune: Some(PruneTarget::Depth(10)), ..Default::default() };
+        let storage = Database::open_in_memory(options).unwrap();
+        let genesis = storage.get_last_block().unwrap().unwrap();
+        let block1 = create_next_block(&genesis, Vec::new());
+        storage.connect_block(&block1).unwrap();
+
+        // The same data is committed by blocks 2 and 3, the most recent commitment is found
+        let mut commit = Transaction::new(
+            vec![TxIn::new(0, vec![4u8; 65], *block1.get_transactions()[0].get_hash())],
+            vec![TxOut::new(40.0, [2u8; 20]), TxOut::new_data(vec![1, 2, 3])],
+        );
+        commit.hash();
+        let block2 = create_next_block(&block1, vec![commit.clone()]);
+        storage.connect_block(&block2).unwrap();
+        let mut recommit = Transaction::new(
+            vec![TxIn::new(0, vec![4u8; 65], *commit.get_hash())],
+            vec![TxOut::new_data(vec![1, 2, 3]), TxOut::new_data(vec![4])],
+        );
+        recommit.hash();
+        let block3 = create_next_block(&block2, vec![recommit.clone()]);
+        storage.connect_block(&block3).unwrap();
+
+        let commitment = storage.find_data_commitment(&[1, 2, 3]).unwrap().unwrap();
+        assert_eq!(commitment, DataCommitment { block_hash: *block3.get_hash(), height: 3, tx_hash: *recommit.get_hash() });
+        assert_eq!(storage.find_data_commitment(&[4]).unwrap().unwrap().height, 3);
+        assert!(storage.find_data_commitment(&[1, 2]).unwrap().is_none());
+
+        storage.disconnect_block(&block3).unwrap();
+        let commitment = storage.find_data_commitment(&[1, 2, 3]).unwrap().unwrap();
+        assert_eq!(commitment, DataCommitment { block_hash: *block2.get_hash(), height: 2, tx_hash: *commit.get_hash() });
+        assert!(storage.find_data_commitment(&[4]).unwrap().is_none());
+
+        // Commitments of pruned blocks are still found
+        connect_blocks(&storage, MIN_BLOCKS_TO_KEEP + 10);
+        assert!(storage.get_block(block2.get_hash()).unwrap().is_none());
+        assert_eq!(storage.find_data_commitment(&[1, 2, 3]).unwrap().unwrap().block_hash, *block2.get_hash());
+        assert!(storage.get_data_index_height().unwrap().is_none());
+    }
+
+    #[test]
+    fn failed_connect_writes_nothing() {
+        let storage = open_test_database();
+        let genesis = storage.get_last_block().unwrap().unwrap();
+
+        // The payment is valid, the second transaction spends an unknown output
+        let mut payment = Transaction::new(
+            vec![TxIn::new(0, vec![4u8; 65], *genesis.get_transactions()[0].get_hash())],
+            vec![TxOut::new(20.0, [2u8; 20])],
+        );
+        payment.hash();
+        let mut invalid = Transaction::new(vec![TxIn::new(0, vec![4u8; 65], [9u8; 32])], vec![TxOut::new(1.0, [3u8; 20])]);
+        invalid.hash();
+
+        let block1 = create_next_block(&genesis, vec![payment.clone(), invalid]);
+        assert!(storage.connect_block(&block1).is_err());
+
+        assert_eq!(storage.get_last_block().unwrap().unwrap().get_hash(), genesis.get_hash());
+        assert!(storage.get_utxo(&OutPoint::new(*genesis.get_transactions()[0].get_hash(), 0)).unwrap().is_some());
+        assert!(storage.get_utxo(&OutPoint::new(*payment.get_hash(), 0)).unwrap().is_none());
+        assert!(storage.get_block(block1.get_hash()).unwrap().is_none());
+        assert!(storage.get_block_hash(1).unwrap().is_none());
+    }
 }
@@ -0,0 +1,194 @@
+/// Encoding.rs
+///
+/// Helpers for the canonical byte-level encoding of protocol structures (see transaction.rs for the
+///     transaction layout).
+///
+/// Integers are little-endian. Lengths and counts are encoded as varints:
+///     - value < 0xfd          1 byte
+///     - value <= 0xffff       0xfd followed by the value as u16
+///     - value <= 0xffffffff   0xfe followed by the value as u32
+///     - otherwise             0xff followed by the value as u64
+///
+/// A varint must use the shortest form able to hold its value, so that every structure has exactly
+///     one encoding.
+
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum EncodingError {
+    UnexpectedEnd,
+    NonCanonicalVarint,
+    TrailingBytes,
+    InvalidValue,
+}
+
+pub type Result<T> = std::result::Result<T, EncodingError>;
+
+impl std::fmt::Display for EncodingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EncodingError::UnexpectedEnd => write!(f, "unexpected end of data"),
+            EncodingError::NonCanonicalVarint => write!(f, "varint is not minimally encoded"),
+            EncodingError::TrailingBytes => write!(f, "trailing bytes after encoded data"),
+            EncodingError::InvalidValue => write!(f, "invalid encoded value"),
+        }
+    }
+}
+
+
+// --- Writing
+pub fn write_varint(buf: &mut Vec<u8>, value: u64) {
+    if value < 0xfd {
+        buf.push(value as u8);
+    } else if value <= 0xffff {
+        buf.push(0xfd);
+        buf.extend_from_slice(&(value as u16).to_le_bytes());
+    } else if value <= 0xffff_ffff {
+        buf.push(0xfe);
+        buf.extend_from_slice(&(value as u32).to_le_bytes());
+    } else {
+        buf.push(0xff);
+        buf.extend_from_slice(&value.to_le_bytes());
+    }
+}
+
+pub fn write_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+pub fn write_f32(buf: &mut Vec<u8>, value: f32) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+// Number of bytes used by the varint encoding of 'value'
+pub fn varint_size(value: u64) -> usize {
+    match value {
+        0..=0xfc => 1,
+        0xfd..=0xffff => 3,
+        0x1_0000..=0xffff_ffff => 5,
+        _ => 9,
+    }
+}
+
+// Length-prefixed bytes
+pub fn write_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    write_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+
+// --- Reading
+pub struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Reader<'a> {
+        Reader { data, position: 0 }
+    }
+
+    pub fn read_array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let mut result = [0u8; N];
+        result.copy_from_slice(self.take(N)?);
+        Ok(result)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.read_array()?))
+    }
+
+    pub fn read_f32(&mut self) -> Result<f32> {
+        Ok(f32::from_le_bytes(self.read_array()?))
+    }
+
+    pub fn read_varint(&mut self) -> Result<u64> {
+        let (value, min) = match self.read_u8()? {
+            0xfd => (u16::from_le_bytes(self.read_array()?) as u64, 0xfd),
+            0xfe => (u32::from_le_bytes(self.read_array()?) as u64, 0x1_0000),
+            0xff => (u64::from_le_bytes(self.read_array()?), 0x1_0000_0000),
+            prefix => return Ok(prefix as u64),
+        };
+
+        if value < min {
+            return Err(EncodingError::NonCanonicalVarint);
+        }
+        Ok(value)
+    }
+
+    // Length-prefixed bytes
+    pub fn read_bytes(&mut self) -> Result<Vec<u8>> {
+        let len = self.read_len()?;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    // Varint used as a length or count, checked against the remaining data so that a corrupted
+    //     value cannot trigger a huge allocation
+    pub fn read_len(&mut self) -> Result<usize> {
+        let len = self.read_varint()?;
+        if len > self.remaining() as u64 {
+            return Err(EncodingError::UnexpectedEnd);
+        }
+        Ok(len as usize)
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.data.len() - self.position
+    }
+
+    // Fail if some data was not consumed
+    pub fn finish(&self) -> Result<()> {
+        if self.remaining() != 0 {
+            return Err(EncodingError::TrailingBytes);
+        }
+        Ok(())
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if len > self.remaining() {
+            return Err(EncodingError::UnexpectedEnd);
+        }
+        let slice = &self.data[self.position..self.position + len];
+        self.position += len;
+        Ok(slice)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_varint_round_trip() {
+        for value in [0u64, 0xfc, 0xfd, 0xffff, 0x1_0000, 0xffff_ffff, 0x1_0000_0000, u64::MAX] {
+            let mut buf = Vec::new();
+            write_varint(&mut buf, value);
+
+            let mut reader = Reader::new(&buf);
+            assert_eq!(reader.read_varint().unwrap(), value);
+            reader.finish().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_varint_sizes() {
+        let sizes = [(0xfcu64, 1usize), (0xfd, 3), (0xffff, 3), (0x1_0000, 5), (0x1_0000_0000, 9)];
+        for (value, size) in sizes {
+            let mut buf = Vec::new();
+            write_varint(&mut buf, value);
+            assert_eq!(buf.len(), size);
+            assert_eq!(varint_size(value), size);
+        }
+    }
+
+    #[test]
+    fn test_non_canonical_varint() {
+        let mut reader = Reader::new(&[0xfd, 0x10, 0x00]);
+        assert!(matches!(reader.read_varint(), Err(EncodingError::NonCanonicalVarint)));
+    }
+}
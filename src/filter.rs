@@ -0,0 +1,259 @@
+/// Filter.rs
+///
+/// Compact block filters let a light wallet find the blocks relevant to it without downloading every
+///     block, and without telling the node which addresses it watches: the wallet fetches the filter of
+///     each block, tests its own elements against it locally, and only fetches the blocks that match.
+///
+/// The filter of a Block is a Golomb-coded set (as in BIP158) of:
+///     - the destination of each output (public key hash or Schnorr public key, see database.rs), data
+///       outputs excluded
+///     - the outpoint (see utxo.rs) spent by each input
+///
+/// Each element is hashed with SipHash-2-4, keyed by the first 16 bytes of the block hash, into the range
+///     [0, N * FILTER_M) where N is the number of elements. The sorted hashes are encoded as the
+///     differences between consecutive values, Golomb-Rice coded with parameter FILTER_P. False positives
+///     happen with a probability of about 1 / FILTER_M per element tested.
+///
+/// Encoding: number of elements (varint, see encoding.rs), then the Golomb-Rice coded differences,
+///     most significant bit first and padded with zeros to a whole byte.
+///
+/// Filter headers chain the filters together: the header of a block is the double SHA256 of the
+///     double SHA256 of its filter followed by the header of the previous block (zeros for the genesis
+///     block), so that a client can check a filter against a header obtained from several nodes.
+
+// SipHasher is deprecated in favour of DefaultHasher, whose algorithm is not specified. Filters need
+//     SipHash-2-4 exactly, which SipHasher still implements
+#[allow(deprecated)]
+use std::hash::{Hasher, SipHasher};
+
+use thiserror::Error;
+
+use crate::block::Block;
+use crate::crypto;
+use crate::database::get_destination_key;
+use crate::encoding::{self, Reader};
+use crate::utxo::OutPoint;
+
+// Golomb-Rice parameter: number of low bits written as is
+pub const FILTER_P: u8 = 19;
+// Inverse of the false positive rate
+pub const FILTER_M: u64 = 784_931;
+
+#[derive(Error, Debug)]
+pub enum FilterError {
+    InvalidFilter,
+}
+
+pub type Result<T> = std::result::Result<T, FilterError>;
+
+impl std::fmt::Display for FilterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FilterError::InvalidFilter => write!(f, "invalid block filter encoding"),
+        }
+    }
+}
+
+
+// Elements covered by the filter of 'block'
+pub fn get_filter_elements(block: &Block) -> Vec<Vec<u8>> {
+    let mut elements = Vec::new();
+    for tx in block.get_transactions() {
+        for output in tx.get_outputs() {
+            if let Some(destination) = get_destination_key(output.get_lock()) {
+                elements.push(destination);
+            }
+        }
+        for input in tx.get_inputs() {
+            elements.push(OutPoint::new(*input.get_prev_utxo(), input.get_n()).to_key().to_vec());
+        }
+    }
+    elements.sort();
+    elements.dedup();
+    elements
+}
+
+pub fn build_filter(block: &Block) -> Vec<u8> {
+    let elements = get_filter_elements(block);
+    let mut values = hash_elements(block.get_hash(), &elements);
+    values.sort_unstable();
+
+    let mut filter = Vec::new();
+    encoding::write_varint(&mut filter, values.len() as u64);
+    let mut writer = BitWriter::new(filter);
+    let mut last = 0;
+    for value in values {
+        writer.write_golomb_rice(value - last);
+        last = value;
+    }
+    writer.finish()
+}
+
+// Header of 'filter', whose previous block has the filter header 'prev_header'
+pub fn get_filter_header(filter: &[u8], prev_header: &[u8; 32]) -> [u8; 32] {
+    let mut filter_hash = [0u8; 32];
+    crypto::calculate_double_sha256_hash(filter, &mut filter_hash);
+
+    let mut data = filter_hash.to_vec();
+    data.extend_from_slice(prev_header);
+    let mut header = [0u8; 32];
+    crypto::calculate_double_sha256_hash(&data, &mut header);
+    header
+}
+
+// Whether 'filter', of the block 'block_hash', may contain any of 'elements'. False positives are possible,
+//     false negatives are not
+pub fn match_any(filter: &[u8], block_hash: &[u8; 32], elements: &[Vec<u8>]) -> Result<bool> {
+    let mut reader = Reader::new(filter);
+    let count = reader.read_varint().map_err(|_| FilterError::InvalidFilter)?;
+    if count == 0 || elements.is_empty() {
+        return Ok(false);
+    }
+
+    let mut queries = hash_range(block_hash, elements, count);
+    queries.sort_unstable();
+
+    let mut reader = BitReader::new(&filter[filter.len() - reader.remaining()..]);
+    let mut value = 0;
+    let mut queries = queries.into_iter().peekable();
+    for _ in 0..count {
+        value += reader.read_golomb_rice()?;
+        while let Some(&query) = queries.peek() {
+            if query == value {
+                return Ok(true);
+            }
+            if query > value {
+                break;
+            }
+            queries.next();
+        }
+        if queries.peek().is_none() {
+            return Ok(false);
+        }
+    }
+    Ok(false)
+}
+
+
+// --- Hashing
+fn hash_elements(block_hash: &[u8; 32], elements: &[Vec<u8>]) -> Vec<u64> {
+    hash_range(block_hash, elements, elements.len() as u64)
+}
+
+// Hash each element into [0, count * FILTER_M)
+#[allow(deprecated)]
+fn hash_range(block_hash: &[u8; 32], elements: &[Vec<u8>], count: u64) -> Vec<u64> {
+    let mut k0 = [0u8; 8];
+    let mut k1 = [0u8; 8];
+    k0.copy_from_slice(&block_hash[..8]);
+    k1.copy_from_slice(&block_hash[8..16]);
+    let range = count * FILTER_M;
+
+    elements.iter().map(|element| {
+        let mut hasher = SipHasher::new_with_keys(u64::from_le_bytes(k0), u64::from_le_bytes(k1));
+        hasher.write(element);
+        // Map the 64 bit hash uniformly into the range, without a division
+        ((hasher.finish() as u128 * range as u128) >> 64) as u64
+    }).collect()
+}
+
+
+// --- Golomb-Rice coding
+struct BitWriter {
+    data: Vec<u8>,
+    bits: u8, // Bits used in the last byte, 0 when it is full
+}
+
+impl BitWriter {
+    fn new(data: Vec<u8>) -> BitWriter {
+        BitWriter { data, bits: 0 }
+    }
+
+    fn write_bit(&mut self, bit: bool) {
+        if self.bits == 0 {
+            self.data.push(0);
+        }
+        if bit {
+            *self.data.last_mut().unwrap() |= 0x80 >> self.bits;
+        }
+        self.bits = (self.bits + 1) % 8;
+    }
+
+    // Quotient in unary (ones ended by a zero), then the FILTER_P low bits
+    fn write_golomb_rice(&mut self, value: u64) {
+        for _ in 0..(value >> FILTER_P) {
+            self.write_bit(true);
+        }
+        self.write_bit(false);
+        for bit in (0..FILTER_P).rev() {
+            self.write_bit((value >> bit) & 1 == 1);
+        }
+    }
+
+    fn finish(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize, // In bits
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> BitReader<'a> {
+        BitReader { data, position: 0 }
+    }
+
+    fn read_bit(&mut self) -> Result<bool> {
+        let byte = self.data.get(self.position / 8).ok_or(FilterError::InvalidFilter)?;
+        let bit = byte & (0x80 >> (self.position % 8)) != 0;
+        self.position += 1;
+        Ok(bit)
+    }
+
+    fn read_golomb_rice(&mut self) -> Result<u64> {
+        let mut quotient = 0u64;
+        while self.read_bit()? {
+            quotient += 1;
+        }
+        let mut remainder = 0u64;
+        for _ in 0..FILTER_P {
+            remainder = (remainder << 1) | self.read_bit()? as u64;
+        }
+        Ok((quotient << FILTER_P) | remainder)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::transaction::{Transaction, TxIn, TxOut};
+    use super::*;
+
+    #[test]
+    fn test_block_filter() {
+        let mut block = Block::new();
+        let mut tx = Transaction::new(
+            vec![TxIn::new(1, vec![4u8; 65], [7u8; 32])],
+            (0..50).map(|i| TxOut::new(1.0, [i as u8; 20])).chain([TxOut::new_data(vec![1, 2, 3])]).collect(),
+        );
+        tx.hash();
+        block.add_transaction(tx);
+        block.mine(0, 50.0, [200u8; 20]).unwrap();
+
+        let filter = build_filter(&block);
+        let hash = block.get_hash();
+        assert!(match_any(&filter, hash, &[vec![3u8; 20]]).unwrap());
+        assert!(match_any(&filter, hash, &[vec![200u8; 20]]).unwrap());
+        assert!(match_any(&filter, hash, &[OutPoint::new([7u8; 32], 1).to_key().to_vec()]).unwrap());
+        assert!(!match_any(&filter, hash, &[vec![100u8; 20], OutPoint::new([7u8; 32], 0).to_key().to_vec()]).unwrap());
+        assert!(!match_any(&filter, hash, &[vec![1, 2, 3]]).unwrap());
+        assert!(!match_any(&filter, &[0u8; 32], &[vec![3u8; 20]]).unwrap());
+
+        // Empty filters match nothing, and headers depend on the previous header
+        assert!(!match_any(&build_filter(&Block::new()), &[0u8; 32], &[vec![3u8; 20]]).unwrap());
+        assert_ne!(get_filter_header(&filter, &[0u8; 32]), get_filter_header(&filter, &[1u8; 32]));
+        assert!(match_any(&filter[..1], hash, &[vec![3u8; 20]]).is_err());
+    }
+}
@@ -1,4 +1,5 @@
 mod rocks;
+mod memory;
 pub mod application;
 mod crypto;
 mod block;
@@ -7,4 +8,14 @@ mod cli;
 mod miner;
 mod transaction;
 mod wallet;
-mod database;
\ No newline at end of file
+mod database;
+mod cache;
+mod validation;
+mod encoding;
+mod utxo;
+mod migration;
+mod psbt;
+mod snapshot;
+mod chainfile;
+mod verify;
+mod filter;
\ No newline at end of file
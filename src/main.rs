@@ -1,6 +1,31 @@
-use blockchain_pow::application::Application;
+use blockchain_pow::application::{Application, DatabaseOptions, PruneTarget, DEFAULT_UTXO_CACHE_SIZE};
 
 fn main() {
-    let mut app = Application::new(2);
+    // Optional indexes, pruning and the minimum fee rate are set from the command line, e.g. '-txindex',
+    //     '-prune=500' or '-minfeerate=0.01'
+    let mut options = DatabaseOptions { utxo_cache: DEFAULT_UTXO_CACHE_SIZE, ..Default::default() };
+    let mut min_fee_rate = None;
+    for arg in std::env::args().skip(1) {
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (arg.as_str(), None),
+        };
+        let rate = value.and_then(|value| value.parse::<f32>().ok()).filter(|rate| rate.is_finite() && *rate >= 0.0);
+        match (name, value.and_then(|value| value.parse::<u64>().ok())) {
+            ("-txindex", None) => options.txindex = true,
+            ("-addressindex", None) => options.addressindex = true,
+            ("-blockfilterindex", None) => options.blockfilterindex = true,
+            ("-prune", Some(blocks)) => options.prune = Some(PruneTarget::Depth(blocks.min(u32::MAX as u64) as u32)),
+            ("-dbcache", Some(megabytes)) => options.utxo_cache = megabytes.saturating_mul(1_000_000) as usize,
+            ("-prunesize", Some(megabytes)) => options.prune = Some(PruneTarget::Size(megabytes.saturating_mul(1_000_000))),
+            ("-minfeerate", _) if rate.is_some() => min_fee_rate = rate,
+            _ => println!("Unknown option: {}", arg),
+        }
+    }
+
+    let mut app = Application::new(2, options);
+    if let Some(min_fee_rate) = min_fee_rate {
+        app.set_min_fee_rate(min_fee_rate);
+    }
     app.run();
-}
\ No newline at end of file
+}
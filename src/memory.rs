@@ -0,0 +1,71 @@
+/// Memory.rs
+///
+/// In-memory Storage backend (see rocks.rs for the RocksDB backend). Nothing is written to disk, so
+///     every MemoryStorage is an isolated, throwaway chain state, e.g. for tests.
+///
+/// Columns are behind a RwLock, so that the storage can be shared between threads like RocksDB.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::rocks::{Direction, Result, Storage, StorageIterator, WriteBatch};
+
+// Key/value pairs of a column family, in key order
+type Column = BTreeMap<Vec<u8>, Vec<u8>>;
+
+#[derive(Default)]
+pub struct MemoryStorage {
+    columns: RwLock<HashMap<&'static str, Column>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> MemoryStorage {
+        MemoryStorage { columns: RwLock::new(HashMap::new()) }
+    }
+
+    // Every change is applied under the write lock, so the columns are consistent even if a thread
+    //     panicked while holding it
+    fn read(&self) -> RwLockReadGuard<'_, HashMap<&'static str, Column>> {
+        self.columns.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write_lock(&self) -> RwLockWriteGuard<'_, HashMap<&'static str, Column>> {
+        self.columns.write().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn get_cf(&self, cf: &'static str, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.read().get(cf).and_then(|column| column.get(key).cloned()))
+    }
+
+    // Pairs are copied when the iterator is created, so that the column can change during the iteration
+    fn iterator_cf(&self, cf: &'static str, from: Option<&[u8]>, direction: Direction) -> Result<StorageIterator<'_>> {
+        let columns = self.read();
+        let column = match columns.get(cf) {
+            Some(column) => column,
+            None => return Ok(Box::new(std::iter::empty())),
+        };
+
+        let pairs: Vec<(Vec<u8>, Vec<u8>)> = match (from, direction) {
+            (None, Direction::Forward) => column.iter().map(|(key, value)| (key.clone(), value.clone())).collect(),
+            (None, Direction::Backward) => column.iter().rev().map(|(key, value)| (key.clone(), value.clone())).collect(),
+            (Some(from), Direction::Forward) => column.range(from.to_vec()..).map(|(key, value)| (key.clone(), value.clone())).collect(),
+            (Some(from), Direction::Backward) => column.range(..=from.to_vec()).rev().map(|(key, value)| (key.clone(), value.clone())).collect(),
+        };
+        Ok(Box::new(pairs.into_iter().map(Ok)))
+    }
+
+    // Changes are applied under a single write lock, so no reader can see part of them
+    fn write(&self, batch: WriteBatch) -> Result<()> {
+        let mut columns = self.write_lock();
+        for ((cf, key), value) in batch.changes {
+            let column = columns.entry(cf).or_default();
+            match value {
+                Some(value) => { column.insert(key, value); },
+                None => { column.remove(&key); },
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,209 @@
+/// Migration.rs
+///
+/// Stored values are raw bincode of Rust types (Block, UtxoEntry, ...), which cannot be read back once
+///     their fields change. The Database therefore stores the version of its layout (SCHEMA_VERSION, see
+///     database.rs) in the 'meta' column family, and upgrades older layouts when it is opened:
+///     - a new database is created with the current version
+///     - an older database is upgraded in place, one version at a time, by the migrations below. Each
+///       migration is written in a single batch along with the new version
+///     - a database without a migration path, or created by a newer program, is refused with an error
+///
+/// Changing a stored type or key requires increasing SCHEMA_VERSION and adding a Migration from the
+///     previous version.
+
+use crate::database::{Database, SCHEMA_VERSION};
+use crate::rocks::{DatabaseError, Result, WriteBatch};
+
+// Upgrade from version 'from' to version 'from + 1'
+pub struct Migration {
+    pub from: u32,
+    pub description: &'static str,
+    pub migrate: fn(&Database, &mut WriteBatch) -> Result<()>,
+}
+
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        from: 0,
+        description: "stamp databases created before schema versioning",
+        migrate: migrate_unversioned,
+    },
+    Migration {
+        from: 1,
+        description: "store block headers in their own column",
+        migrate: migrate_headers,
+    },
+    Migration {
+        from: 2,
+        description: "record the last block included in the stored UTXO set",
+        migrate: migrate_utxo_tip,
+    },
+    Migration {
+        from: 3,
+        description: "store one address index row per output",
+        migrate: migrate_address_rows,
+    },
+    Migration {
+        from: 4,
+        description: "index the data outputs of the stored blocks",
+        migrate: migrate_data_index,
+    },
+];
+
+
+// Bring 'database' to SCHEMA_VERSION
+pub fn upgrade(database: &Database) -> Result<()> {
+    let version = match database.get_schema_version()? {
+        Some(version) => version,
+        None if database.get_last_block_hash()?.is_none() => {
+            let mut batch = WriteBatch::new();
+            database.put_schema_version(&mut batch, SCHEMA_VERSION)?;
+            return database.write(batch);
+        },
+        None => 0,
+    };
+
+    if version > SCHEMA_VERSION {
+        return Err(DatabaseError::NewerSchema(version));
+    }
+
+    for from in version..SCHEMA_VERSION {
+        let migration = MIGRATIONS.iter()
+            .find(|migration| migration.from == from)
+            .ok_or(DatabaseError::MigrationFailed(from, "no migration available"))?;
+
+        println!("Upgrading database schema from version {} ({})", from, migration.description);
+        let mut batch = WriteBatch::new();
+        (migration.migrate)(database, &mut batch)?;
+        database.put_schema_version(&mut batch, from + 1)?;
+        database.write(batch)?;
+    }
+    Ok(())
+}
+
+
+// --- Migrations
+
+// Version 0: no schema version. The layout matches version 1 as long as the stored blocks can be read,
+//     older blocks cannot be converted since their layout is unknown
+fn migrate_unversioned(database: &Database, _batch: &mut WriteBatch) -> Result<()> {
+    match database.get_last_block() {
+        Ok(Some(_)) => Ok(()),
+        _ => Err(DatabaseError::MigrationFailed(0, "blocks use an unknown layout, delete the database directory to start a new chain")),
+    }
+}
+
+// Version 1: headers were only stored within their blocks
+fn migrate_headers(database: &Database, batch: &mut WriteBatch) -> Result<()> {
+    database.put_headers(batch)
+}
+
+// Version 2: the UTXO set was written with each block, so it always included the last block
+fn migrate_utxo_tip(database: &Database, batch: &mut WriteBatch) -> Result<()> {
+    database.put_utxo_tip(batch)
+}
+
+// Version 3: the address index stored the whole history of an address in a single row. It is cleared,
+//     and built again with the new rows when the database is opened with the index
+fn migrate_address_rows(database: &Database, batch: &mut WriteBatch) -> Result<()> {
+    database.clear_address_index(batch)
+}
+
+// Version 4: data outputs were only found by reading every block of the chain
+fn migrate_data_index(database: &Database, batch: &mut WriteBatch) -> Result<()> {
+    database.build_data_index(batch)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use crate::database::DatabaseOptions;
+    use crate::database::test_util::{connect_blocks, create_next_block, open_test_storage};
+    use crate::rocks::{columns, Direction};
+    use crate::transaction::{Transaction, TxIn, TxOut};
+    use super::*;
+
+    #[test]
+    fn test_schema_version() {
+        let (db, database) = open_test_storage(DatabaseOptions::default());
+        assert_eq!(database.get_schema_version().unwrap(), Some(SCHEMA_VERSION));
+
+        // Databases created before versioning are upgraded
+        let mut batch = WriteBatch::new();
+        database.put_schema_version(&mut batch, 0).unwrap();
+        database.write(batch).unwrap();
+        drop(database);
+        let database = Database::with_storage(Arc::clone(&db), DatabaseOptions::default()).unwrap();
+        assert_eq!(database.get_schema_version().unwrap(), Some(SCHEMA_VERSION));
+
+        // Databases created by a newer program are refused
+        let mut batch = WriteBatch::new();
+        database.put_schema_version(&mut batch, SCHEMA_VERSION + 1).unwrap();
+        database.write(batch).unwrap();
+        drop(database);
+        assert!(matches!(Database::with_storage(db, DatabaseOptions::default()), Err(DatabaseError::NewerSchema(_))));
+    }
+
+    #[test]
+    fn test_migrate_address_rows() {
+        let options = DatabaseOptions { addressindex: true, ..Default::default() };
+        let (db, database) = open_test_storage(options);
+        connect_blocks(&database, 2);
+        let history = database.get_address_history(&[1u8; 20]).unwrap();
+        assert_eq!(history.len(), 2);
+
+        // Version 3 kept the whole history of an address in one row
+        let mut batch = WriteBatch::new();
+        database.clear_address_index(&mut batch).unwrap();
+        let row = bincode::serialize(&history).unwrap();
+        batch.changes.insert((columns::ADDRESS_INDEX_CF, vec![1u8; 20]), Some(row));
+        database.put_schema_version(&mut batch, 3).unwrap();
+        database.write(batch).unwrap();
+        drop(database);
+
+        let database = Database::with_storage(db, options).unwrap();
+        assert_eq!(database.get_schema_version().unwrap(), Some(SCHEMA_VERSION));
+        assert_eq!(database.get_address_history(&[1u8; 20]).unwrap(), history);
+    }
+
+    #[test]
+    fn test_migrate_data_index() {
+        let (db, database) = open_test_storage(DatabaseOptions::default());
+        let blocks = connect_blocks(&database, 1);
+        let mut commit = Transaction::new(
+            vec![TxIn::new(0, vec![4u8; 65], *blocks[1].get_transactions()[0].get_hash())],
+            vec![TxOut::new_data(vec![1, 2, 3])],
+        );
+        commit.hash();
+        let block = create_next_block(&blocks[1], vec![commit.clone()]);
+        database.connect_block(&block).unwrap();
+        connect_blocks(&database, 2);
+
+        // Version 4 had no data index
+        let clear_data_index = |database: &Database, prune_height: Option<u32>| {
+            let mut batch = WriteBatch::new();
+            for item in db.iterator_cf(columns::DATA_INDEX_CF, None, Direction::Forward).unwrap() {
+                batch.changes.insert((columns::DATA_INDEX_CF, item.unwrap().0), None);
+            }
+            if let Some(height) = prune_height {
+                batch.changes.insert((columns::META_CF, b"prune_height".to_vec()), Some(bincode::serialize(&height).unwrap()));
+            }
+            database.put_schema_version(&mut batch, 4).unwrap();
+            database.write(batch).unwrap();
+        };
+        clear_data_index(&database, None);
+        drop(database);
+
+        let database = Database::with_storage(Arc::clone(&db), DatabaseOptions::default()).unwrap();
+        assert_eq!(database.get_schema_version().unwrap(), Some(SCHEMA_VERSION));
+        assert_eq!(database.find_data_commitment(&[1, 2, 3]).unwrap().unwrap().tx_hash, *commit.get_hash());
+        assert!(database.get_data_index_height().unwrap().is_none());
+
+        // The blocks of a pruned database below its prune height are not indexed
+        clear_data_index(&database, Some(3));
+        drop(database);
+        let database = Database::with_storage(db, DatabaseOptions::default()).unwrap();
+        assert!(database.find_data_commitment(&[1, 2, 3]).unwrap().is_none());
+        assert_eq!(database.get_data_index_height().unwrap(), Some(3));
+    }
+}
@@ -1,46 +1,101 @@
-use std::rc::Rc;
-use std::cell::RefCell;
+/// Miner.rs
+///
+/// The Miner keeps a pool of valid pending Transactions and builds Blocks out of it.
+///
+/// Fees: Block space is limited (MAX_BLOCK_TRANSACTIONS_SIZE), so Transactions compete on their fee rate,
+///     the fee paid per byte of encoded Transaction, rather than on their absolute fee:
+///     - Transactions paying less than the minimum fee rate of the Miner are not accepted in the pool
+///     - Blocks are filled with the Transactions paying the highest fee rates first. A Transaction
+///       spending the output of another pending Transaction is only selected after its parent
+///
+/// Mining runs in a background thread once started ('start' command), mining a block every BLOCK_INTERVAL
+///     until stopped. The thread shares the Database and the transaction pool with the Miner, so that
+///     transactions can still be added to the pool meanwhile.
+
+use std::sync::{mpsc, Arc, Mutex, MutexGuard, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
 
 use crate::{transaction::Transaction, block::Block, rocks};
 use crate::cli::{CLICommandExec, Command, Instruction};
 use crate::crypto;
 use crate::database::{BlockHashKeys, Database};
+use crate::rocks::DatabaseError;
+use crate::validation::{self, ValidationError};
 
 use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum MinerError {
     MiningError,
-    DatabaseError(#[from] rocks::DatabaseError)
+    DatabaseError(#[from] rocks::DatabaseError),
+    InvalidBlock(#[from] ValidationError),
 }
 
 type Result<T> = std::result::Result<T, MinerError>;
 
+// Maximum total size in bytes of the non-coinbase transactions of a block
+pub const MAX_BLOCK_TRANSACTIONS_SIZE: usize = 100_000;
+// Default minimum fee rate (per byte) of the transactions accepted in the pool
+pub const DEFAULT_MIN_FEE_RATE: f32 = 0.0;
+// Amount created by the coinbase of each block, on top of the fees
+pub const BLOCK_REWARD: f32 = 50.0;
+// Pause between two blocks mined in the background. The difficulty is fixed, so blocks would otherwise
+//     follow each other as fast as they are found
+pub const BLOCK_INTERVAL: Duration = Duration::from_secs(10);
+
 impl std::fmt::Display for MinerError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "database error")
+        match self {
+            MinerError::MiningError => write!(f, "could not mine a block on top of the last block"),
+            MinerError::DatabaseError(e) => write!(f, "database error: {}", e),
+            MinerError::InvalidBlock(e) => write!(f, "invalid block: {}", e),
+        }
     }
 }
 
 pub struct Miner {
     address: String,
     pub_key_hash: [u8; 20],
-    database: Rc<Database>,
-    tx_pool: RefCell<Vec<Transaction>>,
+    database: Arc<Database>,
+    tx_pool: Arc<Mutex<Vec<PoolEntry>>>,
     current_difficulty: u8,
     current_reward: f32,
+    min_fee_rate: f32,
+    mining: Option<MiningThread>,
+}
+
+// Background mining, stopped by dropping 'stop'
+struct MiningThread {
+    stop: mpsc::Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+// Transaction of the pool, with its fee and size when it was accepted
+struct PoolEntry {
+    tx: Transaction,
+    fee: f32,
+    size: usize,
+}
+
+impl PoolEntry {
+    fn get_fee_rate(&self) -> f32 {
+        self.fee / self.size as f32
+    }
 }
 
 impl Miner {
-    pub fn new(address: String, database: Rc<Database>, difficulty: u8) -> Miner {
+    pub fn new(address: String, database: Arc<Database>, difficulty: u8) -> Miner {
         if let Ok(pub_key_hash) = crypto::address_to_public_key_hash(&address) {
             return Miner{
                 address,
                 pub_key_hash,
                 database,
-                tx_pool: RefCell::new(Vec::new()),
+                tx_pool: Arc::new(Mutex::new(Vec::new())),
                 current_difficulty: difficulty,
-                current_reward: 50.0
+                current_reward: BLOCK_REWARD,
+                min_fee_rate: DEFAULT_MIN_FEE_RATE,
+                mining: None,
             }
         }
         panic!("Error while creating Miner: could not convert address to public key hash")
@@ -49,50 +104,166 @@ impl Miner {
 
     // --- Public
 
-    // TODO: application should call this function once networking is implemented
-    pub fn start() {
+    // Mine in a background thread until 'stop_mining', returns false if mining is already running
+    pub fn start_mining(&mut self) -> bool {
+        if self.mining.is_some() {
+            return false;
+        }
+        let miner = self.share();
+        let (stop, stopped) = mpsc::channel();
+        let handle = thread::spawn(move || miner.mine_until(stopped));
+        self.mining = Some(MiningThread { stop, handle });
+        true
+    }
 
+    // Stop the background mining once the current block is mined, returns false if it was not running
+    pub fn stop_mining(&mut self) -> bool {
+        match self.mining.take() {
+            Some(mining) => {
+                drop(mining.stop);
+                if mining.handle.join().is_err() {
+                    println!("Error: the mining thread panicked");
+                }
+                true
+            },
+            None => false,
+        }
     }
 
-    pub fn mine(&mut self) -> Result<()> {
-        if let Some(last_block) = self.database.get_last_block()? {
+    // The chain state is only read and updated under the chain guard, so that updates made of several
+    //     steps by other threads (e.g. a reindex) are never seen half done
+    pub fn mine(&self) -> Result<Block> {
+        let last_block = {
+            let _chain_guard = self.database.lock_chain();
+            self.database.get_last_block()?
+        };
+        if let Some(last_block) = last_block {
             let mut block = Block::new();
-            for tx in self.tx_pool.borrow().iter() {
-                block.add_transaction((*tx).clone());
-            }
-
             block.set_index(last_block.get_index() + 1);
             block.set_prev_hash_from_block(&last_block);
 
-            if let Ok(_) = block.mine(self.current_difficulty, self.current_reward, self.pub_key_hash) {
-                self.database.put_block(&block)?;
-                self.clear_tx_pool();
-                return Ok(());
+            let (selected, fees) = self.select_transactions(block.get_index(), validation::get_current_time());
+            selected.into_iter().for_each(|tx| block.add_transaction(tx));
+
+            // The miner earns the mining reward plus the fees of every transaction in the block
+            if let Ok(_) = block.mine(self.current_difficulty, self.current_reward + fees, self.pub_key_hash) {
+                let chain_guard = self.database.lock_chain();
+                if self.database.get_last_block_hash()? != Some(*block.get_prev_hash()) {
+                    return Err(MinerError::DatabaseError(DatabaseError::UnexpectedBlock));
+                }
+                validation::check_block(&self.database, &block, self.current_reward, self.current_difficulty)?;
+                chain_guard.connect_block(&block)?;
+                drop(chain_guard);
+                self.clear_tx_pool(&block);
+                return Ok(block);
             }
         }
         Err(MinerError::MiningError)
     }
 
-    pub fn add_tx_to_tx_pool(&mut self, tx: Transaction) -> bool {
-        if self.verify_tx(&tx) {
-            self.tx_pool.borrow_mut().push(tx);
-            return true
-        }
+    // The pool stays locked until the transaction is added, so that it is checked against the pool it joins
+    pub fn add_tx_to_tx_pool(&mut self, tx: Transaction) -> validation::Result<()> {
+        let mut tx_pool = self.lock_tx_pool();
+        let fee = self.verify_tx(&tx, &tx_pool)?;
+        let size = tx.get_size();
+        validation::check_fee_rate(fee, size, self.min_fee_rate)?;
 
-        false
+        tx_pool.push(PoolEntry { tx, fee, size });
+        Ok(())
+    }
+
+    pub fn set_min_fee_rate(&mut self, min_fee_rate: f32) {
+        self.min_fee_rate = min_fee_rate;
     }
 
 
     // --- Private
 
-    fn verify_tx(&self, tx: &Transaction) -> bool {
-        true
+    // Miner for the mining thread, sharing the Database and the transaction pool with this one
+    fn share(&self) -> Miner {
+        Miner {
+            address: self.address.clone(),
+            pub_key_hash: self.pub_key_hash,
+            database: Arc::clone(&self.database),
+            tx_pool: Arc::clone(&self.tx_pool),
+            current_difficulty: self.current_difficulty,
+            current_reward: self.current_reward,
+            min_fee_rate: self.min_fee_rate,
+            mining: None,
+        }
     }
 
-    fn clear_tx_pool(&self) {
-        // if let Some(block) = self.blockchain.borrow().get_last_block() {
-        //     self.tx_pool.borrow_mut().retain(|tx| !block.get_transactions().contains(tx));
-        // }
+    // Mine a block every BLOCK_INTERVAL, until a message is sent to 'stopped' or its sender is dropped
+    fn mine_until(&self, stopped: mpsc::Receiver<()>) {
+        loop {
+            match self.mine() {
+                Ok(block) => println!("Block {} mined: {}", block.get_index(), hex::encode(block.get_hash())),
+                // Another block was connected meanwhile, the next block is mined on top of it
+                Err(MinerError::DatabaseError(DatabaseError::UnexpectedBlock)) => (),
+                // The transactions were selected while the chain changed, they are selected again for the next block
+                Err(MinerError::InvalidBlock(e)) => println!("Mined block rejected ({e}), mining the next one"),
+                Err(e) => { println!("Mining stopped: {e}"); return; },
+            }
+            if !matches!(stopped.recv_timeout(BLOCK_INTERVAL), Err(mpsc::RecvTimeoutError::Timeout)) {
+                return;
+            }
+        }
+    }
+
+    fn lock_tx_pool(&self) -> MutexGuard<'_, Vec<PoolEntry>> {
+        self.tx_pool.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Check 'tx' against the chain and the transaction pool, as if it was included in the next block,
+    // and return its fee
+    fn verify_tx(&self, tx: &Transaction, tx_pool: &[PoolEntry]) -> validation::Result<f32> {
+        let height = match self.database.get_last_block()? {
+            Some(last_block) => last_block.get_index() + 1,
+            None => 0,
+        };
+
+        let pending: Vec<Transaction> = tx_pool.iter().map(|entry| entry.tx.clone()).collect();
+        validation::check_transaction(&self.database, tx, height, validation::get_current_time(), &pending)
+    }
+
+    // Pick the pool transactions paying the highest fee rates that fit in a block, and return them with
+    // their total fee. Transactions which became invalid since they entered the pool are left out
+    fn select_transactions(&self, height: u32, time: u64) -> (Vec<Transaction>, f32) {
+        let tx_pool = self.lock_tx_pool();
+        let mut candidates: Vec<&PoolEntry> = tx_pool.iter().collect();
+        candidates.sort_by(|a, b| b.get_fee_rate().total_cmp(&a.get_fee_rate()));
+
+        let mut selected: Vec<Transaction> = Vec::new();
+        let mut fees = 0.0;
+        let mut size = 0;
+
+        // A transaction whose parent has not been selected yet fails validation, and is retried on the
+        // next pass once its parent is in
+        loop {
+            let selected_count = selected.len();
+            candidates.retain(|entry| {
+                if size + entry.size > MAX_BLOCK_TRANSACTIONS_SIZE {
+                    return true;
+                }
+                match validation::check_transaction(&self.database, &entry.tx, height, time, &selected) {
+                    Ok(fee) => {
+                        fees += fee;
+                        size += entry.size;
+                        selected.push(entry.tx.clone());
+                        false
+                    },
+                    Err(_) => true,
+                }
+            });
+
+            if selected.len() == selected_count {
+                return (selected, fees);
+            }
+        }
+    }
+
+    fn clear_tx_pool(&self, block: &Block) {
+        self.lock_tx_pool().retain(|entry| !block.get_transactions().contains(&entry.tx));
     }
 }
 
@@ -103,6 +274,8 @@ impl CLICommandExec for Miner {
         match instruction.command {
             Command::START  => self.cli_start(),
             Command::STOP   => self.cli_stop(),
+            Command::SHOWTXPOOL => self.cli_show_tx_pool(),
+            Command::SENDRAWTRANSACTION => self.cli_send_raw_transaction(instruction),
 
             _ => (),
         };
@@ -110,11 +283,58 @@ impl CLICommandExec for Miner {
 }
 
 impl Miner {
-    fn cli_start(&self) {
-        println!("Mining started")
+    fn cli_start(&mut self) {
+        match self.start_mining() {
+            true => println!("Mining started"),
+            false => println!("Mining is already running"),
+        }
+    }
+
+    fn cli_stop(&mut self) {
+        match self.stop_mining() {
+            true => println!("Mining stopped"),
+            false => println!("Mining is not running"),
+        }
+    }
+
+    // Pool transactions by decreasing fee rate
+    fn cli_show_tx_pool(&self) {
+        let tx_pool = self.lock_tx_pool();
+        let mut entries: Vec<&PoolEntry> = tx_pool.iter().collect();
+        entries.sort_by(|a, b| b.get_fee_rate().total_cmp(&a.get_fee_rate()));
+
+        entries.iter().for_each(|entry| println!(
+            "{} (wtxid {}) - size: {} bytes, fee: {}, fee rate: {}/byte",
+            hex::encode(entry.tx.get_hash()), hex::encode(entry.tx.get_witness_hash()), entry.size, entry.fee, entry.get_fee_rate()
+        ));
+    }
+
+    // Decode a hex encoded transaction (see transaction.rs for the encoding) and add it to the pool
+    fn cli_send_raw_transaction(&mut self, instruction: Instruction) {
+        if instruction.args.len() != 1 {
+            println!("Wrong number of arguments");
+            return;
+        }
+
+        let tx = match hex::decode(&instruction.args[0]) {
+            Ok(data) => match Transaction::from_bytes(&data) {
+                Ok(tx) => tx,
+                Err(e) => { println!("Error: {e}"); return; }
+            },
+            Err(_) => { println!("Please, provide a hex encoded transaction"); return; }
+        };
+
+        let tx_hash = hex::encode(tx.get_hash());
+        match self.add_tx_to_tx_pool(tx) {
+            Ok(_) => println!("Transaction {} added to the transaction pool", tx_hash),
+            Err(e) => println!("Transaction {} rejected: {e}", tx_hash),
+        }
     }
+}
 
-    fn cli_stop(&self) {
-        println!("Mining stopped")
+// The mining thread is stopped along with the Miner
+impl Drop for Miner {
+    fn drop(&mut self) {
+        self.stop_mining();
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,384 @@
+/// Psbt.rs
+///
+/// A partially signed transaction (PSBT) lets several parties build and sign a Transaction without
+///     sharing their keys: the Wallet creating the Transaction does not need to own the keys able to
+///     spend its inputs (e.g. a cold storage key kept on an offline machine).
+///
+/// A PartiallySignedTransaction carries:
+///     - the unsigned Transaction
+///     - for each input: the output it spends, the partial signatures collected so far (by public
+///       key) and an optional key origin, hinting which wallet and key can sign it
+///
+/// Life cycle (see the 'createpsbt', 'signpsbt', 'combinepsbt' and 'finalizepsbt' wallet commands):
+///     create -> sign (by each party, possibly on different copies) -> combine -> finalize
+///
+/// A watch-only wallet can create it too ('createwatchpsbt'), from the public key and the outputs it
+///     spends: the inputs carry the public key, which tells the wallet holding the key what to sign.
+///
+/// Partial signatures are made over the signature hash of the unsigned Transaction. Since signatures
+///     are excluded from it, the hash never changes while signatures are collected. Each signature is
+///     checked against the output it spends whenever it is added, combined or decoded.
+///
+/// Serialization: JSON, where transactions and outputs are hex encoded with the canonical encoding
+///     (see transaction.rs) and keys and signatures are hex encoded. The base64 form is the base64
+///     encoding of the JSON.
+
+use std::collections::BTreeMap;
+
+use base64ct::{Base64, Encoding};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::crypto;
+use crate::transaction::{Lock, Transaction, TxOut};
+
+#[derive(Error, Debug)]
+pub enum PsbtError {
+    InvalidEncoding,
+    InputCountMismatch,
+    IndexOutOfRange,
+    DifferentTransaction,
+    KeyMismatch,
+    InvalidSignature,
+    UnspendableOutput,
+    MissingSignature(usize),
+}
+
+pub type Result<T> = std::result::Result<T, PsbtError>;
+
+impl std::fmt::Display for PsbtError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PsbtError::InvalidEncoding => write!(f, "invalid partially signed transaction encoding"),
+            PsbtError::InputCountMismatch => write!(f, "one spent output is required per transaction input"),
+            PsbtError::IndexOutOfRange => write!(f, "input index out of range"),
+            PsbtError::DifferentTransaction => write!(f, "partially signed transactions spend different transactions"),
+            PsbtError::KeyMismatch => write!(f, "public key does not match the input"),
+            PsbtError::InvalidSignature => write!(f, "invalid partial signature"),
+            PsbtError::UnspendableOutput => write!(f, "input spends a data output"),
+            PsbtError::MissingSignature(index) => write!(f, "input {} is not signed", index),
+        }
+    }
+}
+
+// Wallet and key able to sign an input
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KeyOrigin {
+    pub fingerprint: String, // Hex of the first 4 bytes of the hash of the wallet's first public key
+    pub index: usize,        // Index of the key in the wallet
+}
+
+#[derive(Debug, Clone)]
+pub struct PsbtInput {
+    spent_output: TxOut,
+    partial_signatures: BTreeMap<Vec<u8>, Vec<u8>>, // Public key -> signature
+    key_origin: Option<KeyOrigin>,
+}
+
+#[derive(Debug, Clone)]
+pub struct PartiallySignedTransaction {
+    transaction: Transaction,
+    inputs: Vec<PsbtInput>,
+}
+
+
+// ------ Serialized form
+#[derive(Serialize, Deserialize)]
+struct PsbtJson {
+    transaction: String,
+    inputs: Vec<PsbtInputJson>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PsbtInputJson {
+    spent_output: String,
+    partial_signatures: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key_origin: Option<KeyOrigin>,
+}
+
+
+// ------ PartiallySignedTransaction implementation
+impl PartiallySignedTransaction {
+
+    // --- Public
+    // 'spent_outputs' are the outputs referenced by the inputs of 'transaction', in the same order
+    pub fn new(mut transaction: Transaction, spent_outputs: Vec<TxOut>) -> Result<PartiallySignedTransaction> {
+        if transaction.get_inputs().len() != spent_outputs.len() {
+            return Err(PsbtError::InputCountMismatch);
+        }
+
+        // Signatures are collected separately until the transaction is finalized
+        (0..transaction.get_inputs().len()).for_each(|index| transaction.set_signature(index, Vec::new()));
+        transaction.hash();
+
+        let inputs = spent_outputs.into_iter()
+            .map(|spent_output| PsbtInput { spent_output, partial_signatures: BTreeMap::new(), key_origin: None })
+            .collect();
+
+        Ok(PartiallySignedTransaction { transaction, inputs })
+    }
+
+    pub fn set_key_origin(&mut self, index: usize, key_origin: KeyOrigin) -> Result<()> {
+        let input = self.inputs.get_mut(index).ok_or(PsbtError::IndexOutOfRange)?;
+        input.key_origin = Some(key_origin);
+        Ok(())
+    }
+
+    // Add the signature of 'public_key' for input 'index', after checking it against the spent output
+    pub fn add_signature(&mut self, index: usize, public_key: Vec<u8>, signature: Vec<u8>) -> Result<()> {
+        self.check_signature(index, &public_key, &signature, &self.transaction.get_signature_hash())?;
+        self.inputs[index].partial_signatures.insert(public_key, signature);
+        Ok(())
+    }
+
+    // Merge the signatures and key origins of 'other', which must spend the same transaction. Every
+    //     signature of 'other' is checked, so that an invalid one cannot take the place of a valid one
+    pub fn combine(&mut self, other: &PartiallySignedTransaction) -> Result<()> {
+        if self.transaction.get_hash() != other.transaction.get_hash() || self.inputs.len() != other.inputs.len() {
+            return Err(PsbtError::DifferentTransaction);
+        }
+        other.check_signatures()?;
+
+        for (input, other_input) in self.inputs.iter_mut().zip(other.inputs.iter()) {
+            other_input.partial_signatures.iter().for_each(|(public_key, signature)| {
+                input.partial_signatures.entry(public_key.clone()).or_insert_with(|| signature.clone());
+            });
+            if input.key_origin.is_none() {
+                input.key_origin = other_input.key_origin.clone();
+            }
+        }
+        Ok(())
+    }
+
+    // Build the final Transaction, once every input has a signature for its public key
+    pub fn finalize(&self) -> Result<Transaction> {
+        let mut transaction = self.transaction.clone();
+
+        for (index, input) in self.inputs.iter().enumerate() {
+            let public_key = transaction.get_inputs()[index].get_public_key();
+            let signature = input.partial_signatures.get(public_key).ok_or(PsbtError::MissingSignature(index))?;
+            transaction.set_signature(index, signature.clone());
+        }
+
+        transaction.hash();
+        Ok(transaction)
+    }
+
+    pub fn is_signed(&self, index: usize) -> bool {
+        match (self.transaction.get_inputs().get(index), self.inputs.get(index)) {
+            (Some(tx_input), Some(input)) => input.partial_signatures.contains_key(tx_input.get_public_key()),
+            _ => false,
+        }
+    }
+
+    pub fn get_transaction(&self) -> &Transaction { &self.transaction }
+    pub fn get_inputs(&self) -> &Vec<PsbtInput> { &self.inputs }
+
+
+    // --- Serialization
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&self.to_json_value()).unwrap_or_default()
+    }
+
+    pub fn to_json_pretty(&self) -> String {
+        serde_json::to_string_pretty(&self.to_json_value()).unwrap_or_default()
+    }
+
+    pub fn from_json(data: &str) -> Result<PartiallySignedTransaction> {
+        let json: PsbtJson = serde_json::from_str(data).map_err(|_| PsbtError::InvalidEncoding)?;
+
+        let transaction = Transaction::from_bytes(&decode_hex(&json.transaction)?).map_err(|_| PsbtError::InvalidEncoding)?;
+        if transaction.get_inputs().len() != json.inputs.len() {
+            return Err(PsbtError::InputCountMismatch);
+        }
+
+        let mut inputs = Vec::new();
+        for input in json.inputs {
+            let spent_output = TxOut::from_bytes(&decode_hex(&input.spent_output)?).map_err(|_| PsbtError::InvalidEncoding)?;
+            let mut partial_signatures = BTreeMap::new();
+            for (public_key, signature) in input.partial_signatures {
+                partial_signatures.insert(decode_hex(&public_key)?, decode_hex(&signature)?);
+            }
+            inputs.push(PsbtInput { spent_output, partial_signatures, key_origin: input.key_origin });
+        }
+
+        let psbt = PartiallySignedTransaction { transaction, inputs };
+        psbt.check_signatures()?;
+        Ok(psbt)
+    }
+
+    pub fn to_base64(&self) -> String {
+        Base64::encode_string(self.to_json().as_bytes())
+    }
+
+    pub fn from_base64(data: &str) -> Result<PartiallySignedTransaction> {
+        let json = Base64::decode_vec(data).map_err(|_| PsbtError::InvalidEncoding)?;
+        PartiallySignedTransaction::from_json(std::str::from_utf8(&json).map_err(|_| PsbtError::InvalidEncoding)?)
+    }
+
+    // Accept either the JSON or the base64 form
+    pub fn parse(data: &str) -> Result<PartiallySignedTransaction> {
+        if data.trim_start().starts_with('{') {
+            return PartiallySignedTransaction::from_json(data);
+        }
+        PartiallySignedTransaction::from_base64(data.trim())
+    }
+
+
+    // --- Private
+    // Check the signature of 'public_key' for input 'index' against the output it spends
+    fn check_signature(&self, index: usize, public_key: &[u8], signature: &[u8], signature_hash: &[u8; 32]) -> Result<()> {
+        let tx_input = self.transaction.get_inputs().get(index).ok_or(PsbtError::IndexOutOfRange)?;
+        if tx_input.get_public_key().as_slice() != public_key {
+            return Err(PsbtError::KeyMismatch);
+        }
+
+        let valid = match self.inputs[index].spent_output.get_lock() {
+            Lock::PubKeyHash(destination) => {
+                if &crypto::hash_public_key(public_key) != destination {
+                    return Err(PsbtError::KeyMismatch);
+                }
+                crypto::verify_signature(public_key, signature, signature_hash)
+            },
+            Lock::SchnorrKey(key) => {
+                if public_key != key {
+                    return Err(PsbtError::KeyMismatch);
+                }
+                crypto::verify_schnorr_signature(public_key, signature, signature_hash)
+            },
+            Lock::Data(_) => return Err(PsbtError::UnspendableOutput),
+        };
+
+        if !matches!(valid, Ok(true)) {
+            return Err(PsbtError::InvalidSignature);
+        }
+        Ok(())
+    }
+
+    // Check every partial signature, e.g. of a PSBT received from another party
+    fn check_signatures(&self) -> Result<()> {
+        let signature_hash = self.transaction.get_signature_hash();
+        for (index, input) in self.inputs.iter().enumerate() {
+            for (public_key, signature) in &input.partial_signatures {
+                self.check_signature(index, public_key, signature, &signature_hash)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn to_json_value(&self) -> PsbtJson {
+        PsbtJson {
+            transaction: hex::encode(self.transaction.to_bytes()),
+            inputs: self.inputs.iter().map(|input| PsbtInputJson {
+                spent_output: hex::encode(input.spent_output.to_bytes()),
+                partial_signatures: input.partial_signatures.iter()
+                    .map(|(public_key, signature)| (hex::encode(public_key), hex::encode(signature)))
+                    .collect(),
+                key_origin: input.key_origin.clone(),
+            }).collect(),
+        }
+    }
+}
+
+fn decode_hex(data: &str) -> Result<Vec<u8>> {
+    hex::decode(data).map_err(|_| PsbtError::InvalidEncoding)
+}
+
+
+// ------ PsbtInput implementation
+impl PsbtInput {
+    pub fn get_spent_output(&self) -> &TxOut { &self.spent_output }
+    pub fn get_key_origin(&self) -> Option<&KeyOrigin> { self.key_origin.as_ref() }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::transaction::TxIn;
+    use super::*;
+
+    fn create_test_psbt() -> (PartiallySignedTransaction, Vec<k256::ecdsa::SigningKey>) {
+        let keys = vec![crypto::create_signing_key(), crypto::create_signing_key()];
+        let inputs = vec![
+            TxIn::new(0, crypto::get_public_key(&keys[0]), [1u8; 32]),
+            TxIn::new(1, crypto::get_schnorr_public_key(&keys[1]).to_vec(), [2u8; 32]),
+        ];
+        let spent_outputs = vec![
+            TxOut::new(10.0, crypto::get_public_key_hash(&keys[0])),
+            TxOut::new_schnorr(5.0, crypto::get_schnorr_public_key(&keys[1])),
+        ];
+        let transaction = Transaction::new(inputs, vec![TxOut::new(14.0, [3u8; 20])]);
+
+        (PartiallySignedTransaction::new(transaction, spent_outputs).unwrap(), keys)
+    }
+
+    fn sign(psbt: &mut PartiallySignedTransaction, keys: &[k256::ecdsa::SigningKey], index: usize) {
+        let signature_hash = psbt.get_transaction().get_signature_hash();
+        if index == 0 {
+            psbt.add_signature(0, crypto::get_public_key(&keys[0]), crypto::get_signature(&keys[0], &signature_hash)).unwrap();
+        } else {
+            let signature = crypto::get_schnorr_signature(&keys[1], &signature_hash).unwrap();
+            psbt.add_signature(1, crypto::get_schnorr_public_key(&keys[1]).to_vec(), signature).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_base64_round_trip() {
+        let (mut psbt, keys) = create_test_psbt();
+        psbt.set_key_origin(0, KeyOrigin { fingerprint: String::from("01020304"), index: 2 }).unwrap();
+        sign(&mut psbt, &keys, 0);
+
+        let decoded = PartiallySignedTransaction::parse(&psbt.to_base64()).unwrap();
+        assert_eq!(decoded.to_json(), psbt.to_json());
+        assert_eq!(decoded.get_inputs()[0].get_key_origin().unwrap().index, 2);
+        assert!(decoded.is_signed(0));
+        assert!(!decoded.is_signed(1));
+    }
+
+    #[test]
+    fn test_combine_and_finalize() {
+        let (mut first, keys) = create_test_psbt();
+        let mut second = first.clone();
+
+        sign(&mut first, &keys, 0);
+        sign(&mut second, &keys, 1);
+        assert!(matches!(first.finalize(), Err(PsbtError::MissingSignature(1))));
+
+        first.combine(&second).unwrap();
+        let transaction = first.finalize().unwrap();
+
+        assert_eq!(transaction.get_hash(), first.get_transaction().get_hash());
+        assert!(transaction.get_inputs().iter().all(|input| !input.get_signature().is_empty()));
+    }
+
+    #[test]
+    fn test_reject_invalid_signature() {
+        let (mut psbt, keys) = create_test_psbt();
+        let signature = crypto::get_signature(&keys[0], &[0u8; 32]);
+
+        assert!(matches!(psbt.add_signature(0, crypto::get_public_key(&keys[0]), signature), Err(PsbtError::InvalidSignature)));
+        assert!(matches!(psbt.add_signature(1, crypto::get_public_key(&keys[0]), Vec::new()), Err(PsbtError::KeyMismatch)));
+    }
+
+    #[test]
+    fn test_reject_invalid_received_signature() {
+        let (mut psbt, keys) = create_test_psbt();
+        let mut forged = psbt.clone();
+        forged.inputs[0].partial_signatures.insert(crypto::get_public_key(&keys[0]), crypto::get_signature(&keys[0], &[0u8; 32]));
+
+        // An invalid signature can neither be combined nor decoded
+        assert!(matches!(psbt.combine(&forged), Err(PsbtError::InvalidSignature)));
+        assert!(!psbt.is_signed(0));
+        assert!(matches!(PartiallySignedTransaction::parse(&forged.to_base64()), Err(PsbtError::InvalidSignature)));
+
+        forged.inputs[0].partial_signatures.clear();
+        forged.inputs[1].partial_signatures.insert(crypto::get_public_key(&keys[0]), Vec::new());
+        assert!(matches!(psbt.combine(&forged), Err(PsbtError::KeyMismatch)));
+
+        // Valid signatures are still accepted
+        sign(&mut psbt, &keys, 0);
+        let decoded = PartiallySignedTransaction::parse(&psbt.to_base64()).unwrap();
+        assert!(decoded.is_signed(0));
+    }
+}
@@ -1,27 +1,69 @@
+use std::collections::BTreeMap;
 use std::marker::PhantomData;
-use std::rc::Rc;
-use rocksdb::{ColumnFamily, ColumnFamilyDescriptor, Options};
+use std::sync::Arc;
+use rocksdb::{ColumnFamily, ColumnFamilyDescriptor, IteratorMode, Options};
+use rocksdb::Direction as RocksDirection;
 use serde::{Deserialize, Serialize};
 use serde::de::DeserializeOwned;
 use crate::block::Block;
+use crate::database::{AddressEvent, DataCommitment, FilterEntry, HeaderEntry, TxIndexEntry};
+use crate::utxo::{BlockUndo, UtxoEntry};
 use thiserror::Error;
 
 
 #[derive(Error, Debug)]
 pub enum DatabaseError {
     RocksDb(#[from] rocksdb::Error),
-    Serialize(#[from] Box<bincode::ErrorKind>)
+    Serialize(#[from] Box<bincode::ErrorKind>),
+    UnexpectedBlock,
+    MissingOutput,
+    MissingUndo,
+    MissingBlock,
+    NewerSchema(u32),
+    MigrationFailed(u32, &'static str),
+    InvalidOptions(&'static str),
 }
 
 pub type Result<T> = std::result::Result<T, DatabaseError>;
 
 impl std::fmt::Display for DatabaseError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "database error")
+        match self {
+            DatabaseError::UnexpectedBlock => write!(f, "database error: block is not connected to the chain tip"),
+            DatabaseError::MissingOutput => write!(f, "database error: spent output not found in the chain state"),
+            DatabaseError::MissingUndo => write!(f, "database error: undo data of the block not found"),
+            DatabaseError::MissingBlock => write!(f, "database error: indexed block not found"),
+            DatabaseError::NewerSchema(version) => write!(f, "database error: schema version {} was created by a newer version of the program", version),
+            DatabaseError::MigrationFailed(version, reason) => write!(f, "database error: cannot upgrade schema version {}: {}", version, reason),
+            DatabaseError::InvalidOptions(reason) => write!(f, "database error: invalid options: {}", reason),
+            _ => write!(f, "database error"),
+        }
     }
 }
 
 
+// Order in which keys are iterated
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Forward,  // From lower to higher keys
+    Backward, // From higher to lower keys
+}
+
+pub type StorageIterator<'a> = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + 'a>;
+
+// Key-value store holding the columns of the Database. Column families are identified by name
+//     (see 'columns'), and keys are iterated in lexicographic order. Storages are shared between threads
+pub trait Storage: Send + Sync {
+    fn get_cf(&self, cf: &'static str, key: &[u8]) -> Result<Option<Vec<u8>>>;
+    // Key/value pairs of 'cf' in 'direction', starting from the first key >= 'from' (Forward) or the
+    //     last key <= 'from' (Backward), or from the first/last key of the column if 'from' is None
+    fn iterator_cf(&self, cf: &'static str, from: Option<&[u8]>, direction: Direction) -> Result<StorageIterator<'_>>;
+    // Apply every change of 'batch' in a single atomic write: readers see either none or all of them
+    fn write(&self, batch: WriteBatch) -> Result<()>;
+}
+
+
+// ------ RocksDB backend
 pub struct Rocks {
     db: rocksdb::DB,
 }
@@ -40,15 +82,38 @@ impl Rocks {
     pub fn cf_handle(&self, cf: &str) -> &ColumnFamily {
         self.db.cf_handle(cf).unwrap()
     }
+}
 
-    fn put_cf(&self, cf: &ColumnFamily, key: &[u8], value: &[u8]) -> Result<()> {
-        self.db.put_cf(cf, key, value)?;
-        Ok(())
+impl Storage for Rocks {
+    fn get_cf(&self, cf: &'static str, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let result = self.db.get_cf(self.cf_handle(cf), key)?;
+        Ok(result)
     }
 
-    fn get_cf(&self, cf: &ColumnFamily, key: &[u8]) -> Result<Option<Vec<u8>>> {
-        let result = self.db.get_cf(cf, key)?;
-        Ok(result)
+    fn iterator_cf(&self, cf: &'static str, from: Option<&[u8]>, direction: Direction) -> Result<StorageIterator<'_>> {
+        let mode = match (from, direction) {
+            (None, Direction::Forward) => IteratorMode::Start,
+            (None, Direction::Backward) => IteratorMode::End,
+            (Some(key), Direction::Forward) => IteratorMode::From(key, RocksDirection::Forward),
+            (Some(key), Direction::Backward) => IteratorMode::From(key, RocksDirection::Reverse),
+        };
+        let iter = self.db.iterator_cf(self.cf_handle(cf), mode).map(|item| match item {
+            Ok((key, value)) => Ok((key.to_vec(), value.to_vec())),
+            Err(e) => Err(DatabaseError::from(e)),
+        });
+        Ok(Box::new(iter))
+    }
+
+    fn write(&self, batch: WriteBatch) -> Result<()> {
+        let mut rocks_batch = rocksdb::WriteBatch::default();
+        for ((cf, key), value) in batch.changes {
+            match value {
+                Some(value) => rocks_batch.put_cf(self.cf_handle(cf), key, value),
+                None => rocks_batch.delete_cf(self.cf_handle(cf), key),
+            }
+        }
+        self.db.write(rocks_batch)?;
+        Ok(())
     }
 }
 
@@ -57,11 +122,34 @@ impl Rocks {
         vec![
             ColumnFamilyDescriptor::new(columns::Block::NAME, Options::default()),
             ColumnFamilyDescriptor::new(columns::BlockHash::NAME, Options::default()),
+            ColumnFamilyDescriptor::new(columns::Utxo::NAME, Options::default()),
+            ColumnFamilyDescriptor::new(columns::Undo::NAME, Options::default()),
+            ColumnFamilyDescriptor::new(columns::BlockHeight::NAME, Options::default()),
+            ColumnFamilyDescriptor::new(columns::TxIndex::NAME, Options::default()),
+            ColumnFamilyDescriptor::new(columns::AddressIndex::NAME, Options::default()),
+            ColumnFamilyDescriptor::new(columns::Meta::NAME, Options::default()),
+            ColumnFamilyDescriptor::new(columns::Header::NAME, Options::default()),
+            ColumnFamilyDescriptor::new(columns::BlockFilter::NAME, Options::default()),
+            ColumnFamilyDescriptor::new(columns::DataIndex::NAME, Options::default()),
         ]
     }
 }
 
 
+// Changes to several columns, committed together by 'Storage::write'. Reads made through a LedgerColumn
+//     with the batch see its pending changes, so a change can depend on an earlier one of the same batch
+#[derive(Default)]
+pub struct WriteBatch {
+    pub(crate) changes: BTreeMap<(&'static str, Vec<u8>), Option<Vec<u8>>>, // None deletes the key
+}
+
+impl WriteBatch {
+    pub fn new() -> WriteBatch {
+        WriteBatch { changes: BTreeMap::new() }
+    }
+}
+
+
 pub trait ColumnName {
     const NAME: &'static str;
 }
@@ -72,32 +160,136 @@ pub trait ColumnType {
 
 
 pub struct LedgerColumn<T: ColumnName + ColumnType> {
-    db: Rc<Rocks>,
+    db: Arc<dyn Storage>,
     column: PhantomData<T>,
 }
 
 impl<T: ColumnName + ColumnType> LedgerColumn<T> {
-    pub fn new(db: Rc<Rocks>) -> LedgerColumn<T> {
+    pub fn new(db: Arc<dyn Storage>) -> LedgerColumn<T> {
         LedgerColumn{ db, column: PhantomData }
     }
 
-    fn get_handle(&self) -> &ColumnFamily {
-        self.db.cf_handle(T::NAME)
-    }
-
+    // Single changes are written as a batch of one change
     pub fn put(&self, key: &[u8], value: &T::Type) -> Result<()> {
-        let serialized_value = bincode::serialize(value)?;
-        self.db.put_cf(self.get_handle(), key, serialized_value.as_slice())?;
-        Ok(())
+        let mut batch = WriteBatch::new();
+        self.put_batch(&mut batch, key, value)?;
+        self.db.write(batch)
     }
 
     pub fn get(&self, key: &[u8]) -> Result<Option<T::Type>> {
-        if let Some(slice) = self.db.get_cf(self.get_handle(), key)? {
+        if let Some(slice) = self.db.get_cf(T::NAME, key)? {
             let value = bincode::deserialize(slice.as_slice())?;
             return Ok(Some(value));
         }
         Ok(None)
     }
+
+    pub fn delete(&self, key: &[u8]) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        self.delete_batch(&mut batch, key);
+        self.db.write(batch)
+    }
+
+    // Whether 'key' has a value, without decoding it
+    pub fn exists(&self, key: &[u8]) -> Result<bool> {
+        Ok(self.db.get_cf(T::NAME, key)?.is_some())
+    }
+
+    // --- Batched writes
+    pub fn put_batch(&self, batch: &mut WriteBatch, key: &[u8], value: &T::Type) -> Result<()> {
+        batch.changes.insert((T::NAME, key.to_vec()), Some(bincode::serialize(value)?));
+        Ok(())
+    }
+
+    pub fn delete_batch(&self, batch: &mut WriteBatch, key: &[u8]) {
+        batch.changes.insert((T::NAME, key.to_vec()), None);
+    }
+
+    // Value of 'key' once 'batch' is written
+    pub fn get_batch(&self, batch: &WriteBatch, key: &[u8]) -> Result<Option<T::Type>> {
+        match batch.changes.get(&(T::NAME, key.to_vec())) {
+            Some(Some(value)) => Ok(Some(bincode::deserialize(value)?)),
+            Some(None) => Ok(None),
+            None => self.get(key),
+        }
+    }
+
+    // --- Iteration
+    // Every key/value pair of the column
+    pub fn iter(&self, direction: Direction) -> Result<ColumnIterator<'_, T>> {
+        self.iter_range(None, None, direction)
+    }
+
+    // Key/value pairs whose key starts with 'prefix'
+    pub fn iter_prefix(&self, prefix: &[u8], direction: Direction) -> Result<ColumnIterator<'_, T>> {
+        self.iter_range(Some(prefix), next_prefix(prefix).as_deref(), direction)
+    }
+
+    // Key/value pairs with 'start' <= key < 'end', an unset bound being the start/end of the column
+    pub fn iter_range(&self, start: Option<&[u8]>, end: Option<&[u8]>, direction: Direction) -> Result<ColumnIterator<'_, T>> {
+        let inner = match direction {
+            Direction::Forward => self.db.iterator_cf(T::NAME, start, direction)?,
+            Direction::Backward => self.db.iterator_cf(T::NAME, end, direction)?,
+        };
+        Ok(ColumnIterator {
+            inner,
+            start: start.map(|key| key.to_vec()),
+            end: end.map(|key| key.to_vec()),
+            done: false,
+            column: PhantomData,
+        })
+    }
+}
+
+
+// Decoded key/value pairs of a LedgerColumn within a key range. A value which cannot be decoded is
+//     returned as an error, the iteration can go on with the next pair
+pub struct ColumnIterator<'a, T: ColumnName + ColumnType> {
+    inner: StorageIterator<'a>,
+    start: Option<Vec<u8>>, // Included
+    end: Option<Vec<u8>>,   // Excluded
+    done: bool,
+    column: PhantomData<T>,
+}
+
+impl<'a, T: ColumnName + ColumnType> Iterator for ColumnIterator<'a, T> {
+    type Item = Result<(Vec<u8>, T::Type)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            let (key, value) = match self.inner.next()? {
+                Ok(item) => item,
+                Err(e) => { self.done = true; return Some(Err(e)); }
+            };
+
+            let before_start = self.start.as_ref().is_some_and(|start| key < *start);
+            let after_end = self.end.as_ref().is_some_and(|end| key >= *end);
+            if before_start || after_end {
+                // Backward iteration starts at 'end' itself when it exists, any other key out of the
+                //     range means that the iteration went past it
+                if after_end && key == *self.end.as_ref().unwrap() {
+                    continue;
+                }
+                self.done = true;
+                return None;
+            }
+
+            return Some(bincode::deserialize(&value).map(|value| (key, value)).map_err(DatabaseError::from));
+        }
+        None
+    }
+}
+
+// Smallest key greater than every key starting with 'prefix', None if there is none (e.g. all bytes are 0xff)
+fn next_prefix(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut next = prefix.to_vec();
+    while let Some(last) = next.pop() {
+        if last < u8::MAX {
+            next.push(last + 1);
+            return Some(next);
+        }
+    }
+    None
 }
 
 
@@ -107,6 +299,33 @@ pub mod columns {
     
     pub const BLOCK_HASH_CF: &str = "block_hash";
     pub struct BlockHash;
+
+    pub const UTXO_CF: &str = "utxo";
+    pub struct Utxo;
+
+    pub const UNDO_CF: &str = "undo";
+    pub struct Undo;
+
+    pub const BLOCK_HEIGHT_CF: &str = "block_height";
+    pub struct BlockHeight;
+
+    pub const TX_INDEX_CF: &str = "txindex";
+    pub struct TxIndex;
+
+    pub const ADDRESS_INDEX_CF: &str = "address_index";
+    pub struct AddressIndex;
+
+    pub const META_CF: &str = "meta";
+    pub struct Meta;
+
+    pub const HEADER_CF: &str = "header";
+    pub struct Header;
+
+    pub const BLOCK_FILTER_CF: &str = "block_filter";
+    pub struct BlockFilter;
+
+    pub const DATA_INDEX_CF: &str = "data_index";
+    pub struct DataIndex;
 }
 
 impl ColumnName for columns::Block {
@@ -123,4 +342,127 @@ impl ColumnName for columns::BlockHash {
 
 impl ColumnType for columns::BlockHash {
     type Type = [u8; 32];
-}
\ No newline at end of file
+}
+
+impl ColumnName for columns::Utxo {
+    const NAME: &'static str = columns::UTXO_CF;
+}
+
+impl ColumnType for columns::Utxo {
+    type Type = UtxoEntry;
+}
+
+impl ColumnName for columns::Undo {
+    const NAME: &'static str = columns::UNDO_CF;
+}
+
+impl ColumnType for columns::Undo {
+    type Type = BlockUndo;
+}
+
+impl ColumnName for columns::BlockHeight {
+    const NAME: &'static str = columns::BLOCK_HEIGHT_CF;
+}
+
+impl ColumnType for columns::BlockHeight {
+    type Type = [u8; 32];
+}
+
+impl ColumnName for columns::TxIndex {
+    const NAME: &'static str = columns::TX_INDEX_CF;
+}
+
+impl ColumnType for columns::TxIndex {
+    type Type = TxIndexEntry;
+}
+
+impl ColumnName for columns::AddressIndex {
+    const NAME: &'static str = columns::ADDRESS_INDEX_CF;
+}
+
+impl ColumnType for columns::AddressIndex {
+    type Type = AddressEvent;
+}
+
+impl ColumnName for columns::Meta {
+    const NAME: &'static str = columns::META_CF;
+}
+
+impl ColumnType for columns::Meta {
+    type Type = u32;
+}
+
+impl ColumnName for columns::Header {
+    const NAME: &'static str = columns::HEADER_CF;
+}
+
+impl ColumnType for columns::Header {
+    type Type = HeaderEntry;
+}
+
+impl ColumnName for columns::BlockFilter {
+    const NAME: &'static str = columns::BLOCK_FILTER_CF;
+}
+
+impl ColumnType for columns::BlockFilter {
+    type Type = FilterEntry;
+}
+
+impl ColumnName for columns::DataIndex {
+    const NAME: &'static str = columns::DATA_INDEX_CF;
+}
+
+impl ColumnType for columns::DataIndex {
+    type Type = DataCommitment;
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::memory::MemoryStorage;
+    use super::*;
+
+    fn keys(iter: ColumnIterator<columns::Meta>) -> Vec<Vec<u8>> {
+        iter.map(|item| item.unwrap().0).collect()
+    }
+
+    #[test]
+    fn test_column_iteration() {
+        let db: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let column: LedgerColumn<columns::Meta> = LedgerColumn::new(Arc::clone(&db));
+        for key in [&b"a"[..], b"b\x00", b"b\x01", b"b\xff", b"c"] {
+            column.put(key, &1).unwrap();
+        }
+
+        assert_eq!(column.iter(Direction::Forward).unwrap().count(), 5);
+        assert_eq!(keys(column.iter(Direction::Backward).unwrap())[0], b"c");
+        assert_eq!(keys(column.iter_prefix(b"b", Direction::Forward).unwrap()), vec![b"b\x00".to_vec(), b"b\x01".to_vec(), b"b\xff".to_vec()]);
+        assert_eq!(keys(column.iter_prefix(b"b", Direction::Backward).unwrap()), vec![b"b\xff".to_vec(), b"b\x01".to_vec(), b"b\x00".to_vec()]);
+        assert_eq!(keys(column.iter_range(Some(b"b\x01"), Some(b"c"), Direction::Forward).unwrap()), vec![b"b\x01".to_vec(), b"b\xff".to_vec()]);
+        assert_eq!(keys(column.iter_range(None, Some(b"b\x01"), Direction::Backward).unwrap()), vec![b"b\x00".to_vec(), b"a".to_vec()]);
+        assert_eq!(column.iter_prefix(b"d", Direction::Forward).unwrap().count(), 0);
+
+        // A prefix of 0xff bytes has no upper bound, its keys run to the end of the column
+        column.put(b"\xff\xff", &1).unwrap();
+        column.put(b"\xff\xff\x01", &1).unwrap();
+        assert_eq!(keys(column.iter_prefix(b"\xff", Direction::Forward).unwrap()), vec![b"\xff\xff".to_vec(), b"\xff\xff\x01".to_vec()]);
+        assert_eq!(keys(column.iter_prefix(b"\xff\xff", Direction::Backward).unwrap()), vec![b"\xff\xff\x01".to_vec(), b"\xff\xff".to_vec()]);
+        assert_eq!(next_prefix(b"a\xff"), Some(b"b".to_vec()));
+        assert_eq!(next_prefix(b"\xff\xff"), None);
+        column.delete(b"\xff\xff").unwrap();
+        column.delete(b"\xff\xff\x01").unwrap();
+
+        column.delete(b"a").unwrap();
+        assert!(!column.exists(b"a").unwrap());
+        assert!(column.exists(b"c").unwrap());
+
+        // Values which cannot be decoded are reported without stopping the iteration
+        let mut batch = WriteBatch::new();
+        batch.changes.insert((columns::Meta::NAME, b"b\x02".to_vec()), Some(vec![1]));
+        db.write(batch).unwrap();
+        let items: Vec<_> = column.iter_prefix(b"b", Direction::Forward).unwrap().collect();
+        assert_eq!(items.len(), 4);
+        assert!(items[2].is_err());
+        assert!(items[3].is_ok());
+    }
+}
@@ -0,0 +1,441 @@
+/// Snapshot.rs
+///
+/// A UTXO snapshot holds the UTXO set of the active chain at a given height, along with the headers of
+///     the chain up to that height and the block at that height. Loading it into a new database starts
+///     the node at that height, without replaying every block (see the 'dumptxoutset' and 'loadtxoutset'
+///     blockchain commands).
+///
+/// The UTXO set is committed to by its content hash: the double SHA256 of every entry (outpoint key
+///     followed by the serialized UtxoEntry) in outpoint order. The hash is printed when a snapshot is
+///     written, and a snapshot is only loaded along with the hash announced by a trusted node: the file
+///     itself cannot be trusted, its headers and UTXO set are only checked for consistency.
+///
+/// A loaded snapshot is trusted until its history is validated in the background: the blocks up to the
+///     snapshot height are validated and connected to a separate chain state, kept in memory, whose UTXO
+///     set must match the content hash once the snapshot height is reached. Blocks are validated on their
+///     own thread (see ValidationThread), so the node stays usable meanwhile. The history blocks come from
+///     a chain file (see chainfile.rs). The background chain state is not stored, validation starts again
+///     from the genesis block when the node restarts.
+///
+/// File format: SNAPSHOT_MAGIC, SNAPSHOT_VERSION (u32 big-endian), then the bincode serialized UtxoSnapshot.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread::{self, JoinHandle};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::block::{Block, BlockHeader};
+use crate::crypto;
+use crate::database::{Database, DatabaseOptions, Direction};
+use crate::rocks::DatabaseError;
+use crate::utxo::{OutPoint, UtxoEntry, OUTPOINT_KEY_SIZE};
+use crate::validation::{self, ValidationError};
+
+const SNAPSHOT_MAGIC: &[u8; 8] = b"UTXOSNAP";
+const SNAPSHOT_VERSION: u32 = 1;
+// Blocks validated between two progress updates of a ValidationThread
+const BLOCKS_PER_STEP: usize = 10;
+
+#[derive(Error, Debug)]
+pub enum SnapshotError {
+    Io(#[from] std::io::Error),
+    Serialize(#[from] Box<bincode::ErrorKind>),
+    Database(#[from] DatabaseError),
+    Validation(#[from] ValidationError),
+    InvalidFile,
+    UnsupportedVersion(u32),
+    UnknownHeight(u32),
+    InvalidHeaders,
+    HashMismatch,
+    NotEmpty,
+    UnexpectedBlock(u32),
+}
+
+pub type Result<T> = std::result::Result<T, SnapshotError>;
+
+impl std::fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SnapshotError::Io(e) => write!(f, "snapshot file error: {}", e),
+            SnapshotError::Serialize(_) => write!(f, "invalid snapshot encoding"),
+            SnapshotError::Database(e) => write!(f, "{}", e),
+            SnapshotError::Validation(e) => write!(f, "invalid block: {}", e),
+            SnapshotError::InvalidFile => write!(f, "not a UTXO snapshot file"),
+            SnapshotError::UnsupportedVersion(version) => write!(f, "unsupported snapshot version {}", version),
+            SnapshotError::UnknownHeight(height) => write!(f, "the UTXO set at height {} cannot be computed from the stored blocks", height),
+            SnapshotError::InvalidHeaders => write!(f, "snapshot headers do not form a chain from the genesis block"),
+            SnapshotError::HashMismatch => write!(f, "UTXO set does not match the snapshot content hash"),
+            SnapshotError::NotEmpty => write!(f, "a snapshot can only be loaded into a new database"),
+            SnapshotError::UnexpectedBlock(height) => write!(f, "block at height {} does not match the snapshot headers", height),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct UtxoSnapshot {
+    pub height: u32,
+    pub headers: Vec<BlockHeader>, // Headers of the chain from the genesis block to 'height'
+    pub tip: Block,                // Block at 'height'
+    pub utxos: Vec<(OutPoint, UtxoEntry)>, // In outpoint order
+    pub utxo_hash: [u8; 32],
+}
+
+
+// Content hash of a UTXO set, whose entries are in outpoint order
+pub fn compute_utxo_hash(utxos: &[(OutPoint, UtxoEntry)]) -> Result<[u8; 32]> {
+    let mut data = Vec::new();
+    for (outpoint, entry) in utxos {
+        data.extend_from_slice(&outpoint.to_key());
+        data.extend_from_slice(&bincode::serialize(entry)?);
+    }
+    let mut hash = [0u8; 32];
+    crypto::calculate_double_sha256_hash(&data, &mut hash);
+    Ok(hash)
+}
+
+// Snapshot of the active chain at 'height'. The UTXO set at a lower height than the last block is
+//     computed by undoing the blocks above it, which must still be stored
+pub fn create_snapshot(database: &Database, height: u32) -> Result<UtxoSnapshot> {
+    let tip = database.get_height()?.unwrap_or(0);
+    if height > tip || database.get_prune_height()?.unwrap_or(0) > height {
+        return Err(SnapshotError::UnknownHeight(height));
+    }
+
+    let mut utxos: BTreeMap<[u8; OUTPOINT_KEY_SIZE], (OutPoint, UtxoEntry)> = database.get_utxos()?.into_iter()
+        .map(|(outpoint, entry)| (outpoint.to_key(), (outpoint, entry)))
+        .collect();
+
+    // Same steps as disconnecting the blocks (see database.rs)
+    let mut undone = 0;
+    for block in database.iter_chain_range(height + 1, tip, Direction::Backward) {
+        let block = block?;
+        let mut undo = database.get_block_undo(block.get_hash())?.ok_or(DatabaseError::MissingUndo)?;
+        for tx in block.get_transactions().iter().rev() {
+            for n in 0..tx.get_outputs().len() {
+                utxos.remove(&OutPoint::new(*tx.get_hash(), n as u32).to_key());
+            }
+            for _ in tx.get_inputs() {
+                let (outpoint, entry) = undo.spent.pop().ok_or(DatabaseError::MissingUndo)?;
+                utxos.insert(outpoint.to_key(), (outpoint, entry));
+            }
+        }
+        undone += 1;
+    }
+    if undone != tip - height {
+        return Err(SnapshotError::UnknownHeight(height));
+    }
+
+    let mut headers = Vec::new();
+    for header_height in 0..=height {
+        headers.push(database.get_header_by_height(header_height)?.ok_or(DatabaseError::MissingBlock)?);
+    }
+    let block = database.get_block_by_height(height)?.ok_or(SnapshotError::UnknownHeight(height))?;
+    let utxos: Vec<(OutPoint, UtxoEntry)> = utxos.into_values().collect();
+    let utxo_hash = compute_utxo_hash(&utxos)?;
+
+    Ok(UtxoSnapshot { height, headers, tip: block, utxos, utxo_hash })
+}
+
+// Replace the chain of 'database', which must only hold the genesis block, by the chain of 'snapshot'
+pub fn load_snapshot(database: &Database, snapshot: &UtxoSnapshot) -> Result<()> {
+    snapshot.verify()?;
+    let chain_guard = database.lock_chain();
+    if database.get_height()? != Some(0) || database.is_pruned()? {
+        return Err(SnapshotError::NotEmpty);
+    }
+    chain_guard.load_snapshot(snapshot)?;
+    Ok(())
+}
+
+
+// ------ UtxoSnapshot implementation
+impl UtxoSnapshot {
+    // Check that the headers link the genesis block to the last block, each one with its own hash, and that
+    //     the UTXO set matches its hash
+    pub fn verify(&self) -> Result<()> {
+        let linked = self.headers.iter().enumerate().all(|(height, header)| {
+            header.index == height as u32 && header.has_valid_hash()
+                && (height == 0 || header.prev_hash == self.headers[height - 1].hash)
+        });
+        let genesis_hash = *Block::genesis().get_hash();
+        match (self.headers.first(), self.headers.last()) {
+            (Some(first), Some(last)) if linked && first.hash == genesis_hash && last.hash == *self.tip.get_hash()
+                && self.tip.has_valid_hash() && last.index == self.height && self.tip.get_index() == self.height => (),
+            _ => return Err(SnapshotError::InvalidHeaders),
+        }
+
+        if compute_utxo_hash(&self.utxos)? != self.utxo_hash {
+            return Err(SnapshotError::HashMismatch);
+        }
+        Ok(())
+    }
+
+    pub fn write_to_file(&self, path: &str) -> Result<()> {
+        let mut data = SNAPSHOT_MAGIC.to_vec();
+        data.extend_from_slice(&SNAPSHOT_VERSION.to_be_bytes());
+        data.extend_from_slice(&bincode::serialize(self)?);
+        fs::write(path, data)?;
+        Ok(())
+    }
+
+    pub fn read_from_file(path: &str) -> Result<UtxoSnapshot> {
+        let data = fs::read(path)?;
+        if data.len() < SNAPSHOT_MAGIC.len() + 4 || &data[..SNAPSHOT_MAGIC.len()] != SNAPSHOT_MAGIC {
+            return Err(SnapshotError::InvalidFile);
+        }
+        let mut version = [0u8; 4];
+        version.copy_from_slice(&data[SNAPSHOT_MAGIC.len()..SNAPSHOT_MAGIC.len() + 4]);
+        let version = u32::from_be_bytes(version);
+        if version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+        Ok(bincode::deserialize(&data[SNAPSHOT_MAGIC.len() + 4..])?)
+    }
+}
+
+
+// ------ Background validation
+// Validation of the history of a loaded snapshot, against a chain state kept in memory
+pub struct BackgroundValidation {
+    database: Arc<Database>,
+    chain: Database,
+    snapshot_height: u32,
+    utxo_hash: [u8; 32],
+    reward: f32,
+    difficulty: u8,
+    pending: BTreeMap<u32, Block>, // Blocks received but not validated yet, by height
+}
+
+impl BackgroundValidation {
+    // Validation of the snapshot loaded in 'database', None if there is none
+    pub fn new(database: Arc<Database>, reward: f32, difficulty: u8) -> Result<Option<BackgroundValidation>> {
+        let (snapshot_height, utxo_hash) = match database.get_snapshot()? {
+            Some(snapshot) => snapshot,
+            None => return Ok(None),
+        };
+        let chain = Database::open_in_memory(DatabaseOptions::default())?;
+        Ok(Some(BackgroundValidation { database, chain, snapshot_height, utxo_hash, reward, difficulty, pending: BTreeMap::new() }))
+    }
+
+    pub fn get_height(&self) -> Result<u32> {
+        Ok(self.chain.get_height()?.unwrap_or(0))
+    }
+
+    // Queue a block of the snapshot history, blocks already validated or above the snapshot are ignored
+    pub fn add_block(&mut self, block: Block) -> Result<()> {
+        if block.get_index() > self.get_height()? && block.get_index() <= self.snapshot_height {
+            self.pending.insert(block.get_index(), block);
+        }
+        Ok(())
+    }
+
+    // Validate and connect up to 'max_blocks' queued blocks. Returns true once the UTXO set at the snapshot
+    //     height matches the snapshot, which is then considered valid
+    pub fn step(&mut self, max_blocks: usize) -> Result<bool> {
+        for _ in 0..max_blocks {
+            let height = self.get_height()? + 1;
+            if height > self.snapshot_height {
+                break;
+            }
+            let block = match self.pending.remove(&height) {
+                Some(block) => block,
+                None => break,
+            };
+
+            if self.database.get_block_hash(height)? != Some(*block.get_hash()) {
+                return Err(SnapshotError::UnexpectedBlock(height));
+            }
+            validation::check_block(&self.chain, &block, self.reward, self.difficulty)?;
+            self.chain.connect_block(&block)?;
+        }
+
+        if self.get_height()? < self.snapshot_height {
+            return Ok(false);
+        }
+        if compute_utxo_hash(&self.chain.get_utxos()?)? != self.utxo_hash {
+            return Err(SnapshotError::HashMismatch);
+        }
+        self.database.clear_snapshot()?;
+        Ok(true)
+    }
+
+    // Validate on a new thread the blocks sent to the returned ValidationThread
+    pub fn spawn(self) -> Result<ValidationThread> {
+        let snapshot_height = self.snapshot_height;
+        let height = Arc::new(AtomicU32::new(self.get_height()?));
+        let (blocks, received) = mpsc::channel();
+        let progress = Arc::clone(&height);
+        let handle = thread::spawn(move || self.run(received, progress));
+        Ok(ValidationThread { blocks, height, snapshot_height, handle })
+    }
+
+    // Validate the blocks from 'received' as they come, until the snapshot is validated or found invalid,
+    //     or until every sender is dropped
+    fn run(mut self, received: mpsc::Receiver<Block>, progress: Arc<AtomicU32>) -> Result<bool> {
+        loop {
+            loop {
+                let height = self.get_height()?;
+                if self.step(BLOCKS_PER_STEP)? {
+                    return Ok(true);
+                }
+                progress.store(self.get_height()?, Ordering::Relaxed);
+                if self.get_height()? == height {
+                    break;
+                }
+            }
+            match received.recv() {
+                Ok(block) => self.add_block(block)?,
+                Err(_) => return Ok(false),
+            }
+            for block in received.try_iter() {
+                self.add_block(block)?;
+            }
+        }
+    }
+}
+
+
+// Handle of a BackgroundValidation running on its own thread. The thread ends once the snapshot is
+//     validated or found invalid, or when the handle is dropped
+pub struct ValidationThread {
+    blocks: mpsc::Sender<Block>,
+    height: Arc<AtomicU32>, // Height validated so far
+    snapshot_height: u32,
+    handle: JoinHandle<Result<bool>>,
+}
+
+impl ValidationThread {
+    // Queue a block of the snapshot history, ignored once the thread has ended
+    pub fn add_block(&self, block: Block) {
+        let _ = self.blocks.send(block);
+    }
+
+    pub fn get_height(&self) -> u32 {
+        self.height.load(Ordering::Relaxed)
+    }
+
+    pub fn get_snapshot_height(&self) -> u32 {
+        self.snapshot_height
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.handle.is_finished()
+    }
+
+    // Wait for the end of the thread: true if the snapshot is valid, false if its history is incomplete.
+    //     Err if the thread panicked
+    pub fn join(self) -> thread::Result<Result<bool>> {
+        drop(self.blocks);
+        self.handle.join()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::database::test_util::{connect_blocks, create_next_block};
+    use crate::transaction::{Transaction, TxIn, TxOut};
+    use super::*;
+
+    #[test]
+    fn test_snapshot() {
+        let database = Database::open_in_memory(DatabaseOptions::default()).unwrap();
+        let mut blocks = connect_blocks(&database, 2);
+        let snapshot = create_snapshot(&database, 2).unwrap();
+
+        // The UTXO set at height 2 is computed by undoing the blocks above it
+        let mut spend = Transaction::new(
+            vec![TxIn::new(0, vec![4u8; 65], *blocks[1].get_transactions()[0].get_hash())],
+            vec![TxOut::new(40.0, [2u8; 20])],
+        );
+        spend.hash();
+        let block3 = create_next_block(&blocks[2], vec![spend]);
+        database.connect_block(&block3).unwrap();
+        assert_eq!(create_snapshot(&database, 2).unwrap().utxo_hash, snapshot.utxo_hash);
+        assert!(create_snapshot(&database, 4).is_err());
+
+        let path = std::env::temp_dir().join("utxo-snapshot-test.dat");
+        let path = path.to_str().unwrap();
+        snapshot.write_to_file(path).unwrap();
+        let snapshot = UtxoSnapshot::read_from_file(path).unwrap();
+        fs::remove_file(path).unwrap();
+
+        let node = Arc::new(Database::open_in_memory(DatabaseOptions::default()).unwrap());
+        load_snapshot(&node, &snapshot).unwrap();
+        assert_eq!(node.get_height().unwrap(), Some(2));
+        assert_eq!(node.get_utxos().unwrap().len(), 3);
+        assert!(load_snapshot(&node, &snapshot).is_err());
+        node.connect_block(&block3).unwrap();
+
+        // The history is validated against the snapshot hash
+        let mut background = BackgroundValidation::new(Arc::clone(&node), 50.0, 0).unwrap().unwrap();
+        let mut other_block = Block::new();
+        other_block.set_index(1);
+        other_block.mine(0, 50.0, [1u8; 20]).unwrap();
+        background.add_block(other_block).unwrap();
+        assert!(matches!(background.step(10), Err(SnapshotError::UnexpectedBlock(1))));
+
+        background.add_block(blocks.pop().unwrap()).unwrap();
+        assert!(!background.step(10).unwrap());
+        background.add_block(blocks.pop().unwrap()).unwrap();
+        assert!(!background.step(1).unwrap());
+        assert!(background.step(1).unwrap());
+        assert!(node.get_snapshot().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_validation_thread() {
+        let database = Database::open_in_memory(DatabaseOptions::default()).unwrap();
+        let blocks = connect_blocks(&database, 3);
+        let node = Arc::new(Database::open_in_memory(DatabaseOptions::default()).unwrap());
+        load_snapshot(&node, &create_snapshot(&database, 3).unwrap()).unwrap();
+
+        // Blocks are validated as they are received, in any order
+        let background = BackgroundValidation::new(Arc::clone(&node), 50.0, 0).unwrap().unwrap().spawn().unwrap();
+        assert_eq!(background.get_snapshot_height(), 3);
+        for block in blocks.into_iter().skip(1).rev() {
+            background.add_block(block);
+        }
+        assert!(background.join().unwrap().unwrap());
+        assert!(node.get_snapshot().unwrap().is_none());
+
+        // The thread stops without a result when no more blocks are sent
+        let node = Arc::new(Database::open_in_memory(DatabaseOptions::default()).unwrap());
+        load_snapshot(&node, &create_snapshot(&database, 3).unwrap()).unwrap();
+        let background = BackgroundValidation::new(Arc::clone(&node), 50.0, 0).unwrap().unwrap().spawn().unwrap();
+        background.add_block(database.get_block_by_height(1).unwrap().unwrap());
+        assert!(!background.join().unwrap().unwrap());
+        assert!(node.get_snapshot().unwrap().is_some());
+    }
+
+    #[test]
+    fn test_invalid_snapshot() {
+        let database = Database::open_in_memory(DatabaseOptions::default()).unwrap();
+        let mut snapshot = create_snapshot(&database, 0).unwrap();
+        snapshot.utxos[0].1.output = TxOut::new(1000.0, [1u8; 20]);
+        assert!(matches!(snapshot.verify(), Err(SnapshotError::HashMismatch)));
+
+        snapshot.headers.clear();
+        assert!(matches!(snapshot.verify(), Err(SnapshotError::InvalidHeaders)));
+
+        // Headers and the last block must hash to their own hash
+        connect_blocks(&database, 2);
+        let snapshot = create_snapshot(&database, 2).unwrap();
+        let mut forged = create_snapshot(&database, 2).unwrap();
+        forged.headers[1].timestamp += 1;
+        assert!(matches!(forged.verify(), Err(SnapshotError::InvalidHeaders)));
+
+        let mut forged = create_snapshot(&database, 2).unwrap();
+        forged.tip.set_prev_hash_from_block(&Block::new());
+        assert!(matches!(forged.verify(), Err(SnapshotError::InvalidHeaders)));
+        assert!(snapshot.verify().is_ok());
+
+        // Loading a snapshot leaves no history to index
+        let node = Database::open_in_memory(DatabaseOptions { txindex: true, ..Default::default() }).unwrap();
+        assert!(matches!(load_snapshot(&node, &snapshot), Err(SnapshotError::Database(DatabaseError::InvalidOptions(_)))));
+    }
+}
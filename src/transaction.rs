@@ -12,41 +12,105 @@
 ///     information on the how the Transaction is handled after it has been added to the transaction pool)
 ///
 ///
-/// For now, the protocol only allows one address to sign transaction inputs
+/// Each TxIn carries its own signature (witness). Signatures are not part of the transaction hash, so
+///     re-encoding a signature cannot change the hash of a pending transaction.
+///
+/// Timelocks:
+///     - lock_time (Transaction): the transaction cannot be mined in a block before the given height
+///       (if lower than LOCKTIME_THRESHOLD) or before the given unix timestamp in seconds (otherwise).
+///       A lock_time of 0 disables the lock.
+///     - sequence (TxIn): relative lock on the referenced output, counted from the block that
+///       contains it. Bit 31 disables the lock, bit 22 selects time (units of 512 seconds) instead of
+///       blocks, and the lower 16 bits hold the value.
+///
+/// Encoding (see encoding.rs for integers and varints):
+///     Transaction:
+///         version         u32 (TRANSACTION_VERSION)
+///         input count     varint
+///         inputs          TxIn * input count
+///         output count    varint
+///         outputs         TxOut * output count
+///         lock_time       u32
+///         witnesses       (varint length + DER or Schnorr signature) * input count
+///     TxIn:
+///         prev_utxo       32 bytes (hash of the referenced transaction)
+///         n               u32 (index of the referenced output)
+///         public_key      varint length + SEC1 public key (or x-only public key for Schnorr outputs)
+///         sequence        u32
+///     TxOut:
+///         amount          f32 (IEEE 754)
+///         lock type       u8 (0: public key hash, 1: data, 2: Schnorr public key)
+///         lock            20 bytes public key hash, varint length + data, or 32 bytes x-only public key
+///
+/// Size estimation: before signing, the size of a Transaction is estimated from the type of the outputs
+///     its inputs spend (which sets the public key and signature sizes) and from its outputs. DER signatures
+///     have a variable length, so the estimate uses their maximum size and is never below the real size.
+///
+/// The transaction hash (txid) is Sha256(Sha256(encoding without witnesses)) and is used to reference
+///     outputs. The witness hash (wtxid) is Sha256(Sha256(encoding)) and commits to the signatures too.
 
 use std::fmt;
 
 use serde::{Deserialize, Serialize};
 use crate::crypto;
+use crate::encoding::{self, EncodingError, Reader};
 
 // Size in bytes
 const TRANSACTION_HASH_SIZE: usize = 32;
 const PUB_KEY_HASH_SIZE: usize = 20;
+pub const SCHNORR_PUB_KEY_SIZE: usize = 32;
+pub const SEC1_PUB_KEY_SIZE: usize = 65; // Uncompressed SEC1 public key
+pub const MAX_DER_SIGNATURE_SIZE: usize = 72;
+pub const SCHNORR_SIGNATURE_SIZE: usize = 64;
+
+pub const TRANSACTION_VERSION: u32 = 1;
+
+// Data outputs
+pub const MAX_DATA_SIZE: usize = 80;
+
+// Timelocks
+pub const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+pub const SEQUENCE_FINAL: u32 = 0xffff_ffff;
+pub const SEQUENCE_LOCKTIME_DISABLE_FLAG: u32 = 1 << 31;
+pub const SEQUENCE_LOCKTIME_TYPE_FLAG: u32 = 1 << 22;
+pub const SEQUENCE_LOCKTIME_MASK: u32 = 0x0000_ffff;
+pub const SEQUENCE_LOCKTIME_GRANULARITY: u32 = 9; // Time locks are expressed in units of 2^9 = 512 seconds
 
 // Unspent transaction output
 pub struct UTXO {
     pub reference: [u8; 32], // Transaction hash
-    pub n: usize,
+    pub n: u32,
     pub amount: f32,
+    pub lock: Lock,
 }
 
 impl UTXO {
-    pub fn new(reference: [u8; 32], n: usize, amount: f32) -> UTXO {
-        UTXO{ reference, n, amount }
+    pub fn new(reference: [u8; 32], n: u32, amount: f32, lock: Lock) -> UTXO {
+        UTXO{ reference, n, amount, lock }
     }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TxIn {
-    n: usize,
+    n: u32,
     prev_utxo: [u8; TRANSACTION_HASH_SIZE],
-    public_key: String,
+    public_key: Vec<u8>,
+    sequence: u32,
+    signature: Vec<u8>, // Witness, excluded from the transaction hash
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TxOut {
     amount: f32,
-    destination: [u8; PUB_KEY_HASH_SIZE], // Hash of the public key (Ripemd160(Sha256(PubKey)))
+    lock: Lock,
+}
+
+// Condition to spend a TxOut
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Lock {
+    PubKeyHash([u8; PUB_KEY_HASH_SIZE]), // Hash of the public key (Ripemd160(Sha256(PubKey)))
+    Data(Vec<u8>), // Arbitrary data (at most MAX_DATA_SIZE bytes), provably unspendable
+    SchnorrKey([u8; SCHNORR_PUB_KEY_SIZE]), // x-only public key, spent with a BIP340 Schnorr signature
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -54,25 +118,187 @@ pub struct Transaction {
     hash: [u8; TRANSACTION_HASH_SIZE],
     tx_in_sz: usize,
     tx_out_sz: usize,
-    signature: String,
+    lock_time: u32,
 
     inputs: Vec<TxIn>,
     outputs: Vec<TxOut>,
 }
 
+// Relative lock decoded from a TxIn sequence
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RelativeLock {
+    Blocks(u32),
+    Seconds(u64),
+}
+
 
 // ------ TxIn implementation
 impl TxIn {
-    pub fn new(n: usize, public_key: String, prev_utxo: [u8; TRANSACTION_HASH_SIZE]) -> TxIn {
-        TxIn { n, prev_utxo, public_key }
+    pub fn new(n: u32, public_key: Vec<u8>, prev_utxo: [u8; TRANSACTION_HASH_SIZE]) -> TxIn {
+        TxIn { n, prev_utxo, public_key, sequence: SEQUENCE_FINAL, signature: Vec::new() }
+    }
+
+    // Decode the relative lock carried by 'sequence', if any
+    pub fn relative_lock(&self) -> Option<RelativeLock> {
+        if self.sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG != 0 {
+            return None;
+        }
+
+        let value = self.sequence & SEQUENCE_LOCKTIME_MASK;
+        if self.sequence & SEQUENCE_LOCKTIME_TYPE_FLAG != 0 {
+            Some(RelativeLock::Seconds((value as u64) << SEQUENCE_LOCKTIME_GRANULARITY))
+        } else {
+            Some(RelativeLock::Blocks(value))
+        }
+    }
+
+    pub fn get_n(&self) -> u32 { self.n }
+    pub fn get_prev_utxo(&self) -> &[u8; TRANSACTION_HASH_SIZE] { &self.prev_utxo }
+    pub fn get_public_key(&self) -> &Vec<u8> { &self.public_key }
+    pub fn get_sequence(&self) -> u32 { self.sequence }
+    pub fn get_signature(&self) -> &Vec<u8> { &self.signature }
+
+    pub fn set_sequence(&mut self, sequence: u32) {
+        self.sequence = sequence;
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.prev_utxo);
+        encoding::write_u32(buf, self.n);
+        encoding::write_bytes(buf, &self.public_key);
+        encoding::write_u32(buf, self.sequence);
     }
+
+    fn decode(reader: &mut Reader) -> encoding::Result<TxIn> {
+        let prev_utxo = reader.read_array()?;
+        let n = reader.read_u32()?;
+        let public_key = reader.read_bytes()?;
+        let sequence = reader.read_u32()?;
+        Ok(TxIn { n, prev_utxo, public_key, sequence, signature: Vec::new() })
+    }
+}
+
+// Estimated size of a signed input spending an output locked by 'lock', witness included
+pub fn estimate_input_size(lock: &Lock) -> usize {
+    let (public_key_size, signature_size) = match lock {
+        Lock::SchnorrKey(_) => (SCHNORR_PUB_KEY_SIZE, SCHNORR_SIGNATURE_SIZE),
+        _ => (SEC1_PUB_KEY_SIZE, MAX_DER_SIGNATURE_SIZE),
+    };
+
+    TRANSACTION_HASH_SIZE + 4
+        + encoding::varint_size(public_key_size as u64) + public_key_size
+        + 4
+        + encoding::varint_size(signature_size as u64) + signature_size
+}
+
+// Estimated size of a signed transaction spending outputs locked by 'spent_locks' and creating 'outputs'
+pub fn estimate_size(spent_locks: &[&Lock], outputs: &[TxOut]) -> usize {
+    4
+        + encoding::varint_size(spent_locks.len() as u64)
+        + spent_locks.iter().map(|lock| estimate_input_size(lock)).sum::<usize>()
+        + encoding::varint_size(outputs.len() as u64)
+        + outputs.iter().map(|output| output.get_size()).sum::<usize>()
+        + 4
+}
+
+// Build a sequence locking an input for 'blocks' blocks after the referenced output was mined
+pub fn sequence_from_blocks(blocks: u16) -> u32 {
+    blocks as u32
+}
+
+// Build a sequence locking an input for at least 'seconds' seconds (rounded up to 512 seconds)
+pub fn sequence_from_seconds(seconds: u32) -> Option<u32> {
+    let units = (seconds as u64 + (1 << SEQUENCE_LOCKTIME_GRANULARITY) - 1) >> SEQUENCE_LOCKTIME_GRANULARITY;
+    if units > SEQUENCE_LOCKTIME_MASK as u64 {
+        return None;
+    }
+    Some(SEQUENCE_LOCKTIME_TYPE_FLAG | units as u32)
 }
 
 
 // ------ TxOut implementation
 impl TxOut {
     pub fn new(amount: f32, destination: [u8; PUB_KEY_HASH_SIZE]) -> TxOut {
-        TxOut { amount, destination }
+        TxOut { amount, lock: Lock::PubKeyHash(destination) }
+    }
+
+    pub fn with_lock(amount: f32, lock: Lock) -> TxOut {
+        TxOut { amount, lock }
+    }
+
+    pub fn new_schnorr(amount: f32, public_key: [u8; SCHNORR_PUB_KEY_SIZE]) -> TxOut {
+        TxOut { amount, lock: Lock::SchnorrKey(public_key) }
+    }
+
+    // Output carrying 'data' instead of coins. It can never be spent, so it never becomes a UTXO
+    pub fn new_data(data: Vec<u8>) -> TxOut {
+        TxOut { amount: 0.0, lock: Lock::Data(data) }
+    }
+
+    pub fn get_amount(&self) -> f32 { self.amount }
+    pub fn get_lock(&self) -> &Lock { &self.lock }
+
+    // Public key hash able to spend self, if any
+    pub fn get_destination(&self) -> Option<&[u8; PUB_KEY_HASH_SIZE]> {
+        match &self.lock {
+            Lock::PubKeyHash(destination) => Some(destination),
+            Lock::Data(_) | Lock::SchnorrKey(_) => None,
+        }
+    }
+
+    pub fn is_unspendable(&self) -> bool {
+        matches!(self.lock, Lock::Data(_))
+    }
+
+    // Canonical encoding of self (see top of this file)
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        self.encode(&mut data);
+        data
+    }
+
+    pub fn get_size(&self) -> usize {
+        match &self.lock {
+            Lock::PubKeyHash(_) => 4 + 1 + PUB_KEY_HASH_SIZE,
+            Lock::Data(data) => 4 + 1 + encoding::varint_size(data.len() as u64) + data.len(),
+            Lock::SchnorrKey(_) => 4 + 1 + SCHNORR_PUB_KEY_SIZE,
+        }
+    }
+
+    pub fn from_bytes(data: &[u8]) -> encoding::Result<TxOut> {
+        let mut reader = Reader::new(data);
+        let output = TxOut::decode(&mut reader)?;
+        reader.finish()?;
+        Ok(output)
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        encoding::write_f32(buf, self.amount);
+        match &self.lock {
+            Lock::PubKeyHash(destination) => {
+                buf.push(0);
+                buf.extend_from_slice(destination);
+            },
+            Lock::Data(data) => {
+                buf.push(1);
+                encoding::write_bytes(buf, data);
+            },
+            Lock::SchnorrKey(public_key) => {
+                buf.push(2);
+                buf.extend_from_slice(public_key);
+            },
+        }
+    }
+
+    fn decode(reader: &mut Reader) -> encoding::Result<TxOut> {
+        let amount = reader.read_f32()?;
+        let lock = match reader.read_u8()? {
+            0 => Lock::PubKeyHash(reader.read_array()?),
+            1 => Lock::Data(reader.read_bytes()?),
+            2 => Lock::SchnorrKey(reader.read_array()?),
+            _ => return Err(EncodingError::InvalidValue),
+        };
+        Ok(TxOut { amount, lock })
     }
 }
 
@@ -86,7 +312,7 @@ impl Transaction {
             hash: [0u8; TRANSACTION_HASH_SIZE],
             tx_in_sz: inputs.len(),
             tx_out_sz: outputs.len(),
-            signature: String::new(),
+            lock_time: 0,
             inputs,
             outputs,
         };
@@ -94,9 +320,23 @@ impl Transaction {
         tx
     }
 
-    // Calculate and set hash to transaction
+    // Calculate and set hash to transaction: Sha256(Sha256(encoding without witnesses))
     pub fn hash(&mut self) {
-        crypto::calculate_sha256_hash(self.get_transaction_data(true).as_bytes(),&mut self.hash);
+        self.hash = self.compute_hash();
+    }
+
+    // Hash of the current content of self, to check the stored hash of a transaction read from elsewhere
+    pub fn compute_hash(&self) -> [u8; TRANSACTION_HASH_SIZE] {
+        let mut hash = [0u8; TRANSACTION_HASH_SIZE];
+        crypto::calculate_double_sha256_hash(&self.get_transaction_data(false), &mut hash);
+        hash
+    }
+
+    // Witness hash: Sha256(Sha256(encoding)), changes with the signatures
+    pub fn get_witness_hash(&self) -> [u8; TRANSACTION_HASH_SIZE] {
+        let mut witness_hash = [0u8; TRANSACTION_HASH_SIZE];
+        crypto::calculate_double_sha256_hash(&self.to_bytes(), &mut witness_hash);
+        witness_hash
     }
 
     pub fn add_tx_input(&mut self, tx_in: TxIn) {
@@ -106,68 +346,102 @@ impl Transaction {
         self.outputs.push(tx_out);
     }
 
-    // Concatenate fields of self. If 'add_signature' set to true, signature is not used for the concatenation
-    pub fn get_transaction_data(&self, add_signature: bool) -> String {
-        let mut data = String::new();
+    // Canonical encoding of self (see top of this file)
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.get_transaction_data(true)
+    }
 
-        if add_signature {
-            data.push_str(&self.signature);
-        }
+    pub fn from_bytes(data: &[u8]) -> encoding::Result<Transaction> {
+        let mut reader = Reader::new(data);
 
-        data.push_str(&self.tx_in_sz.to_string());
-        data.push_str(&self.tx_out_sz.to_string());
+        if reader.read_u32()? != TRANSACTION_VERSION {
+            return Err(EncodingError::InvalidValue);
+        }
 
-        data.push_str(&hex::encode(&self.calculate_inputs_hash()));
-        data.push_str(&hex::encode(&self.calculate_outputs_hash()));
+        let tx_in_sz = reader.read_len()?;
+        let mut inputs = (0..tx_in_sz).map(|_| TxIn::decode(&mut reader)).collect::<encoding::Result<Vec<TxIn>>>()?;
+        let tx_out_sz = reader.read_len()?;
+        let outputs = (0..tx_out_sz).map(|_| TxOut::decode(&mut reader)).collect::<encoding::Result<Vec<TxOut>>>()?;
+        let lock_time = reader.read_u32()?;
+        for input in inputs.iter_mut() {
+            input.signature = reader.read_bytes()?;
+        }
+        reader.finish()?;
 
-        data
+        let mut tx = Transaction { hash: [0u8; TRANSACTION_HASH_SIZE], tx_in_sz, tx_out_sz, lock_time, inputs, outputs };
+        tx.hash();
+        Ok(tx)
     }
 
-    pub fn set_signature(&mut self, signature: String) {
-        self.signature = signature;
-    }
+    // Encoding of self. If 'add_witness' set to false, signatures are left out (this is the data hashed
+    //     into the transaction hash and signed by the Wallet)
+    pub fn get_transaction_data(&self, add_witness: bool) -> Vec<u8> {
+        let mut data = Vec::new();
+
+        encoding::write_u32(&mut data, TRANSACTION_VERSION);
 
-    // --- Private
-    fn get_concatenated_inputs(&self) -> String {
-        let mut data = String::new();
-        for input in &self.inputs {
-            let mut cur_tx_in_hash = [0u8; TRANSACTION_HASH_SIZE];
-            let cur_tx_in_data = input.n.to_string()
-                + &hex::encode(input.prev_utxo)
-                + &input.public_key;
-            crypto::calculate_sha256_hash(cur_tx_in_data.as_bytes(), &mut cur_tx_in_hash);
-            data.push_str(&hex::encode(cur_tx_in_hash));
+        encoding::write_varint(&mut data, self.inputs.len() as u64);
+        self.inputs.iter().for_each(|input| input.encode(&mut data));
+        encoding::write_varint(&mut data, self.outputs.len() as u64);
+        self.outputs.iter().for_each(|output| output.encode(&mut data));
+
+        encoding::write_u32(&mut data, self.lock_time);
+
+        if add_witness {
+            self.inputs.iter().for_each(|input| encoding::write_bytes(&mut data, &input.signature));
         }
 
         data
     }
 
-    fn get_concatenated_outputs(&self) -> String {
-        let mut data = String::new();
-        for output in &self.outputs {
-            let mut cur_tx_out_hash = [0u8; TRANSACTION_HASH_SIZE];
-            let cur_tx_out_data = output.amount.to_string() + &hex::encode(&output.destination);
-            crypto::calculate_sha256_hash(cur_tx_out_data.as_bytes(), &mut cur_tx_out_hash);
-            data.push_str(&hex::encode(cur_tx_out_hash));
+    // Set the signature of input 'index'
+    pub fn set_signature(&mut self, index: usize, signature: Vec<u8>) {
+        if let Some(input) = self.inputs.get_mut(index) {
+            input.signature = signature;
         }
+    }
 
-        data
+    pub fn set_lock_time(&mut self, lock_time: u32) {
+        self.lock_time = lock_time;
+    }
+
+    // Data signed for every input: Sha256(encoding without witnesses)
+    pub fn get_signature_hash(&self) -> [u8; TRANSACTION_HASH_SIZE] {
+        let mut signature_hash = [0u8; TRANSACTION_HASH_SIZE];
+        crypto::calculate_sha256_hash(&self.get_transaction_data(false), &mut signature_hash);
+        signature_hash
+    }
+
+    // Check if one of the inputs of self references output 'n' of transaction 'tx_hash'
+    pub fn spends(&self, tx_hash: &[u8; TRANSACTION_HASH_SIZE], n: u32) -> bool {
+        self.inputs.iter().any(|input| &input.prev_utxo == tx_hash && input.n == n)
     }
 
-    fn calculate_inputs_hash(&self) -> [u8; TRANSACTION_HASH_SIZE] {
-        let data = self.get_concatenated_inputs();
-        let mut inputs_hash = [0u8; TRANSACTION_HASH_SIZE];
+    // A coinbase transaction creates the mining reward and fees out of nothing, so it has no inputs
+    pub fn is_coinbase(&self) -> bool {
+        self.inputs.is_empty()
+    }
 
-        crypto::calculate_sha256_hash(data.as_bytes(), &mut inputs_hash);
-        inputs_hash
+    pub fn get_outputs_amount(&self) -> f32 {
+        self.outputs.iter().map(|output| output.amount).sum()
     }
 
-    fn calculate_outputs_hash(&self) -> [u8; TRANSACTION_HASH_SIZE] {
-        let data = self.get_concatenated_outputs();
-        let mut outputs_hash = [0u8; TRANSACTION_HASH_SIZE];
+    // Size in bytes of the encoded transaction, used to apply fee rates
+    pub fn get_size(&self) -> usize {
+        self.to_bytes().len()
+    }
 
-        crypto::calculate_sha256_hash(data.as_bytes(), &mut outputs_hash);
-        outputs_hash
+    // Check 'lock_time' against the height and time (unix seconds) of the block including self
+    pub fn is_final(&self, height: u32, time: u64) -> bool {
+        if self.lock_time == 0 {
+            return true;
+        }
+
+        if self.lock_time < LOCKTIME_THRESHOLD {
+            height >= self.lock_time
+        } else {
+            time >= self.lock_time as u64
+        }
     }
 }
 
@@ -177,7 +451,9 @@ impl Transaction {
     pub fn get_hash(&self) -> &[u8; TRANSACTION_HASH_SIZE] {
         &self.hash
     }
-    pub fn get_signature(&self) -> &String { &self.signature }
+    pub fn get_lock_time(&self) -> u32 { self.lock_time }
+    pub fn get_inputs(&self) -> &Vec<TxIn> { &self.inputs }
+    pub fn get_outputs(&self) -> &Vec<TxOut> { &self.outputs }
 }
 
 
@@ -197,20 +473,26 @@ impl fmt::Display for Transaction {
 
         writeln!(f, "Transaction {{")?;
         writeln!(f, "{}    hash: {},", tab, hex::encode(self.hash))?;
-        writeln!(f, "{}    signature: {},", tab, self.signature)?;
+        writeln!(f, "{}    lock_time: {},", tab, self.lock_time)?;
         writeln!(f, "{}    inputs: [", tab)?;
         self.inputs.iter().for_each(|tx| {
             writeln!(f, "{}        {{", tab);
             writeln!(f, "{}            n: {},", tab, tx.n);
             writeln!(f, "{}            prev_utxo: {},", tab, &hex::encode(tx.prev_utxo));
-            writeln!(f, "{}            public_key: {},", tab, tx.public_key);
+            writeln!(f, "{}            public_key: {},", tab, hex::encode(&tx.public_key));
+            writeln!(f, "{}            sequence: {},", tab, tx.sequence);
+            writeln!(f, "{}            signature: {},", tab, hex::encode(&tx.signature));
         });
         writeln!(f, "{}    ],", tab)?;
         writeln!(f, "{}    outputs: [", tab)?;
         self.outputs.iter().for_each(|tx| {
             writeln!(f, "{}        {{", tab);
             writeln!(f, "{}            amount: {},", tab, tx.amount);
-            writeln!(f, "{}            destination: {},", tab, hex::encode(tx.destination));
+            match &tx.lock {
+                Lock::PubKeyHash(destination) => writeln!(f, "{}            destination: {},", tab, hex::encode(destination)),
+                Lock::Data(data) => writeln!(f, "{}            data: {},", tab, hex::encode(data)),
+                Lock::SchnorrKey(public_key) => writeln!(f, "{}            schnorr_key: {},", tab, hex::encode(public_key)),
+            };
             writeln!(f, "{}        }},", tab);
         });
         writeln!(f, "{}    ],", tab)?;
@@ -227,3 +509,117 @@ impl fmt::Display for UTXO {
         writeln!(f, "}}")
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_test_transaction() -> Transaction {
+        let mut input = TxIn::new(1, vec![4u8; 65], [7u8; 32]);
+        input.set_sequence(sequence_from_blocks(10));
+
+        let mut tx = Transaction::new(vec![input], vec![TxOut::new(12.5, [3u8; 20]), TxOut::new(0.25, [5u8; 20])]);
+        tx.set_lock_time(800);
+        tx.set_signature(0, vec![0x30, 0x44, 0x02]);
+        tx.hash();
+        tx
+    }
+
+    #[test]
+    fn test_serialization_round_trip() {
+        let tx = create_test_transaction();
+        let data = tx.to_bytes();
+        let decoded = Transaction::from_bytes(&data).unwrap();
+
+        assert_eq!(decoded.to_bytes(), data);
+        assert_eq!(decoded.get_hash(), tx.get_hash());
+        assert_eq!(decoded.get_lock_time(), 800);
+        assert_eq!(decoded.get_inputs()[0].get_sequence(), 10);
+        assert_eq!(decoded.get_inputs()[0].get_signature(), &vec![0x30, 0x44, 0x02]);
+    }
+
+    #[test]
+    fn test_serialization_layout() {
+        let tx = create_test_transaction();
+        let data = tx.to_bytes();
+
+        // version + input count + input (32 + 4 + 1 + 65 + 4) + output count + outputs (2 * 25) + lock_time + witness (1 + 3)
+        assert_eq!(data.len(), 4 + 1 + 106 + 1 + 50 + 4 + 4);
+        assert_eq!(&data[0..4], &TRANSACTION_VERSION.to_le_bytes());
+
+        // The transaction hash leaves the witnesses out, the witness hash covers everything
+        let mut txid = [0u8; 32];
+        crypto::calculate_double_sha256_hash(&data[..data.len() - 4], &mut txid);
+        assert_eq!(&txid, tx.get_hash());
+
+        let mut wtxid = [0u8; 32];
+        crypto::calculate_double_sha256_hash(&data, &mut wtxid);
+        assert_eq!(wtxid, tx.get_witness_hash());
+    }
+
+    #[test]
+    fn test_estimate_size() {
+        let signing_key = crypto::create_signing_key();
+        let spent_locks = [
+            Lock::PubKeyHash(crypto::get_public_key_hash(&signing_key)),
+            Lock::SchnorrKey(crypto::get_schnorr_public_key(&signing_key)),
+        ];
+        let inputs = vec![
+            TxIn::new(0, crypto::get_public_key(&signing_key), [1u8; 32]),
+            TxIn::new(0, crypto::get_schnorr_public_key(&signing_key).to_vec(), [2u8; 32]),
+        ];
+        let outputs = vec![TxOut::new(1.0, [3u8; 20]), TxOut::new_data(vec![9u8; 40])];
+        let mut tx = Transaction::new(inputs, outputs.clone());
+
+        let signature_hash = tx.get_signature_hash();
+        tx.set_signature(0, crypto::get_signature(&signing_key, &signature_hash));
+        tx.set_signature(1, crypto::get_schnorr_signature(&signing_key, &signature_hash).unwrap());
+
+        // Only the DER signature length may differ from the estimate
+        let estimate = estimate_size(&spent_locks.iter().collect::<Vec<_>>(), &outputs);
+        assert!(estimate >= tx.get_size());
+        assert!(estimate - tx.get_size() <= 8);
+        assert!(outputs.iter().all(|output| output.get_size() == output.to_bytes().len()));
+    }
+
+    #[test]
+    fn test_signature_does_not_change_hash() {
+        let tx = create_test_transaction();
+        let mut malleated = tx.clone();
+        malleated.set_signature(0, vec![0x30, 0x45, 0x02, 0x00]);
+        malleated.hash();
+
+        assert_eq!(malleated.get_hash(), tx.get_hash());
+        assert_ne!(malleated.get_witness_hash(), tx.get_witness_hash());
+    }
+
+    #[test]
+    fn test_data_output_round_trip() {
+        let mut tx = create_test_transaction();
+        tx.add_tx_output(TxOut::new_data(vec![9u8; 32]));
+        let decoded = Transaction::from_bytes(&tx.to_bytes()).unwrap();
+
+        let output = &decoded.get_outputs()[2];
+        assert!(output.is_unspendable());
+        assert_eq!(output.get_lock(), &Lock::Data(vec![9u8; 32]));
+        assert_eq!(output.get_destination(), None);
+    }
+
+    #[test]
+    fn test_stored_output_index() {
+        // Stored inputs encode the output index on 4 bytes
+        let input = TxIn::new(7, Vec::new(), [0u8; 32]);
+        let data = bincode::serialize(&input).unwrap();
+        assert_eq!(&data[..4], &7u32.to_le_bytes());
+        assert_eq!(bincode::deserialize::<TxIn>(&data).unwrap().get_n(), 7);
+    }
+
+    #[test]
+    fn test_deserialize_rejects_trailing_bytes() {
+        let mut data = create_test_transaction().to_bytes();
+        data.push(0);
+
+        assert!(matches!(Transaction::from_bytes(&data), Err(EncodingError::TrailingBytes)));
+    }
+}
@@ -0,0 +1,96 @@
+/// Utxo.rs
+///
+/// The UTXO set holds every output of the active chain which has not been spent yet. It is stored in
+///     the 'utxo' column family (see rocks.rs), behind a cache (see cache.rs), and updated by the Database
+///     when a Block is connected to or disconnected from the chain (see database.rs).
+///
+/// Entries are keyed by OutPoint: the hash of the Transaction creating the output (32 bytes) followed
+///     by the index of the output in that Transaction (u32 big-endian), so that the outputs of a
+///     Transaction are stored next to each other.
+///
+/// Data outputs can never be spent, so they are not part of the UTXO set.
+///
+/// Spent outputs are removed from the UTXO set, so each connected Block keeps a BlockUndo record (in the
+///     'undo' column family, keyed by block hash) holding the entries it spent. Disconnecting the Block
+///     puts them back, restoring the UTXO set exactly as it was before the Block.
+
+use serde::{Deserialize, Serialize};
+
+use crate::transaction::{Lock, TxOut};
+
+pub const OUTPOINT_KEY_SIZE: usize = 36;
+
+// Reference to a transaction output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct OutPoint {
+    pub tx_hash: [u8; 32],
+    pub n: u32,
+}
+
+// Unspent output with the metadata needed to validate its spending
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UtxoEntry {
+    pub output: TxOut,
+    pub height: u32, // Index of the block containing the output
+    pub coinbase: bool,
+}
+
+
+// Entries spent by the transactions of a block, in spending order
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BlockUndo {
+    pub spent: Vec<(OutPoint, UtxoEntry)>,
+}
+
+
+// ------ OutPoint implementation
+impl OutPoint {
+    pub fn new(tx_hash: [u8; 32], n: u32) -> OutPoint {
+        OutPoint { tx_hash, n }
+    }
+
+    pub fn to_key(&self) -> [u8; OUTPOINT_KEY_SIZE] {
+        let mut key = [0u8; OUTPOINT_KEY_SIZE];
+        key[..32].copy_from_slice(&self.tx_hash);
+        key[32..].copy_from_slice(&self.n.to_be_bytes());
+        key
+    }
+
+    pub fn from_key(key: &[u8]) -> Option<OutPoint> {
+        if key.len() != OUTPOINT_KEY_SIZE {
+            return None;
+        }
+        let mut tx_hash = [0u8; 32];
+        tx_hash.copy_from_slice(&key[..32]);
+        let mut n = [0u8; 4];
+        n.copy_from_slice(&key[32..]);
+        Some(OutPoint { tx_hash, n: u32::from_be_bytes(n) })
+    }
+}
+
+
+// ------ UtxoEntry implementation
+impl UtxoEntry {
+    pub fn new(output: TxOut, height: u32, coinbase: bool) -> UtxoEntry {
+        UtxoEntry { output, height, coinbase }
+    }
+
+    pub fn get_amount(&self) -> f32 { self.output.get_amount() }
+    pub fn get_lock(&self) -> &Lock { self.output.get_lock() }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_outpoint_key() {
+        let outpoint = OutPoint::new([7u8; 32], 258);
+        let key = outpoint.to_key();
+
+        assert_eq!(&key[32..], &[0, 0, 1, 2]);
+        assert_eq!(OutPoint::from_key(&key), Some(outpoint));
+        assert_eq!(OutPoint::from_key(&key[1..]), None);
+    }
+}
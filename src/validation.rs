@@ -0,0 +1,441 @@
+/// Validation.rs
+///
+/// Consensus rules shared by the Miner when accepting a Transaction into its transaction pool and
+///     when checking a Block before it is stored in the database.
+///
+/// Timelocks:
+///     - A Transaction is final once the height or time of the Block including it reaches its lock_time
+///     - Each TxIn with a relative lock can only be included once enough blocks (or time) have passed
+///       since the Block containing the referenced output
+///
+/// Fees:
+///     - The fee of a Transaction is the value of the outputs it spends minus the value of its outputs
+///     - A Transaction whose outputs exceed its inputs is rejected
+///     - The fee rate of a Transaction is its fee per byte of encoding (see transaction.rs for size
+///       estimation before signing)
+///     - The coinbase of a Block may claim at most the mining reward plus the fees of the Block
+///
+/// Blocks:
+///     - The hash of every Transaction must match its content, and no Transaction may appear twice
+///     - The merkle root must be computed from the transaction hashes, so that the block hash commits to
+///       the transactions, and the block hash must match the header and meet the difficulty
+///     - The coinbase must be locked to the height of the Block
+///
+/// Inputs:
+///     - Each TxIn must reference an output of the UTXO set (see utxo.rs), not spent by a pending
+///       Transaction (an earlier Transaction of the same Block, or of the transaction pool)
+///     - Outputs of pending Transactions can be spent, which allows chains of unconfirmed payments
+///     - The public key of the TxIn must hash to the destination of the referenced output, and its
+///       signature must be valid for that public key over the signature hash of the Transaction
+///     - For Schnorr outputs, the public key of the TxIn must be the x-only key of the output and its
+///       signature a BIP340 signature. Schnorr signatures of a Block are verified in a single batch
+///
+/// Data outputs:
+///     - A data output carries at most MAX_DATA_SIZE bytes and can never be spent
+
+use std::collections::HashSet;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use thiserror::Error;
+
+use crate::block::Block;
+use crate::crypto::{self, SchnorrBatchItem};
+use crate::database::Database;
+use crate::rocks;
+use crate::transaction::{Lock, RelativeLock, Transaction, TxIn, TxOut, MAX_DATA_SIZE};
+use crate::utxo::OutPoint;
+
+#[derive(Error, Debug)]
+pub enum ValidationError {
+    NonFinal,
+    SequenceLocked,
+    MissingInput,
+    InputAlreadySpent,
+    DuplicateInput,
+    UnexpectedCoinbase,
+    PublicKeyMismatch,
+    InvalidSignature,
+    UnspendableOutput,
+    NegativeAmount,
+    DataTooLarge,
+    DataWithAmount,
+    OutputsExceedInputs,
+    InsufficientFeeRate,
+    InvalidCoinbase,
+    InvalidTransactionHash,
+    DuplicateTransaction,
+    InvalidMerkleRoot,
+    InvalidBlockHash,
+    InsufficientWork,
+    DatabaseError(#[from] rocks::DatabaseError)
+}
+
+pub type Result<T> = std::result::Result<T, ValidationError>;
+
+impl std::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ValidationError::NonFinal => write!(f, "transaction lock time not reached"),
+            ValidationError::SequenceLocked => write!(f, "transaction input relative lock not reached"),
+            ValidationError::MissingInput => write!(f, "transaction input references an unknown output"),
+            ValidationError::InputAlreadySpent => write!(f, "transaction input references a spent output"),
+            ValidationError::DuplicateInput => write!(f, "transaction references the same output twice"),
+            ValidationError::UnexpectedCoinbase => write!(f, "coinbase transaction outside of a block"),
+            ValidationError::PublicKeyMismatch => write!(f, "public key does not match the output destination"),
+            ValidationError::InvalidSignature => write!(f, "invalid input signature"),
+            ValidationError::UnspendableOutput => write!(f, "transaction input references a data output"),
+            ValidationError::NegativeAmount => write!(f, "transaction output amount is negative"),
+            ValidationError::DataTooLarge => write!(f, "data output exceeds the maximum data size"),
+            ValidationError::DataWithAmount => write!(f, "data output carries a nonzero amount"),
+            ValidationError::OutputsExceedInputs => write!(f, "transaction outputs exceed its inputs"),
+            ValidationError::InsufficientFeeRate => write!(f, "transaction fee rate is below the minimum fee rate"),
+            ValidationError::InvalidCoinbase => write!(f, "block must contain one coinbase claiming at most the reward and fees"),
+            ValidationError::InvalidTransactionHash => write!(f, "transaction hash does not match its content"),
+            ValidationError::DuplicateTransaction => write!(f, "block contains the same transaction twice"),
+            ValidationError::InvalidMerkleRoot => write!(f, "block merkle root does not match its transactions"),
+            ValidationError::InvalidBlockHash => write!(f, "block hash does not match its header"),
+            ValidationError::InsufficientWork => write!(f, "block hash does not meet the difficulty"),
+            ValidationError::DatabaseError(_) => write!(f, "database error"),
+        }
+    }
+}
+
+
+// --- Transaction
+
+// Check every rule for a non-coinbase 'tx' and return its fee. 'height' and 'time' are those of the
+//     Block that would include 'tx', and 'pending' are the Transactions that would precede it
+pub fn check_transaction(database: &Database, tx: &Transaction, height: u32, time: u64, pending: &[Transaction]) -> Result<f32> {
+    let mut schnorr_batch = Vec::new();
+    let fee = check_transaction_with_batch(database, tx, height, time, pending, &mut schnorr_batch)?;
+    check_schnorr_batch(&schnorr_batch)?;
+    Ok(fee)
+}
+
+// Same as 'check_transaction', but Schnorr signatures are added to 'schnorr_batch' instead of being verified
+fn check_transaction_with_batch(database: &Database, tx: &Transaction, height: u32, time: u64, pending: &[Transaction], schnorr_batch: &mut Vec<SchnorrBatchItem>) -> Result<f32> {
+    if tx.is_coinbase() {
+        return Err(ValidationError::UnexpectedCoinbase);
+    }
+    check_final(tx, height, time)?;
+    check_outputs(tx)?;
+
+    let signature_hash = tx.get_signature_hash();
+    let mut outpoints = HashSet::new();
+    let mut inputs_amount = 0.0;
+
+    for input in tx.get_inputs() {
+        if !outpoints.insert((*input.get_prev_utxo(), input.get_n())) {
+            return Err(ValidationError::DuplicateInput);
+        }
+        if pending.iter().any(|pending_tx| pending_tx.spends(input.get_prev_utxo(), input.get_n())) {
+            return Err(ValidationError::InputAlreadySpent);
+        }
+
+        let spent_output = get_spent_output(database, input, height, time, pending)?;
+        check_signature(input, &spent_output, &signature_hash, schnorr_batch)?;
+        inputs_amount += spent_output.get_amount();
+    }
+
+    let outputs_amount = tx.get_outputs_amount();
+    if outputs_amount > inputs_amount {
+        return Err(ValidationError::OutputsExceedInputs);
+    }
+    Ok(inputs_amount - outputs_amount)
+}
+
+pub fn check_final(tx: &Transaction, height: u32, time: u64) -> Result<()> {
+    if tx.is_final(height, time) {
+        return Ok(());
+    }
+    Err(ValidationError::NonFinal)
+}
+
+// Check that 'fee' paid for 'size' bytes reaches 'min_fee_rate' (per byte)
+pub fn check_fee_rate(fee: f32, size: usize, min_fee_rate: f32) -> Result<()> {
+    if fee < size as f32 * min_fee_rate {
+        return Err(ValidationError::InsufficientFeeRate);
+    }
+    Ok(())
+}
+
+fn check_outputs(tx: &Transaction) -> Result<()> {
+    for output in tx.get_outputs() {
+        if !(output.get_amount() >= 0.0 && output.get_amount().is_finite()) {
+            return Err(ValidationError::NegativeAmount);
+        }
+        if let Lock::Data(data) = output.get_lock() {
+            if data.len() > MAX_DATA_SIZE {
+                return Err(ValidationError::DataTooLarge);
+            }
+            // A data output can never be spent, any amount it carries would be lost
+            if output.get_amount() != 0.0 {
+                return Err(ValidationError::DataWithAmount);
+            }
+        }
+    }
+    Ok(())
+}
+
+// Find the output referenced by 'input', in 'pending' or in the database, and check its relative lock
+fn get_spent_output(database: &Database, input: &TxIn, height: u32, time: u64, pending: &[Transaction]) -> Result<TxOut> {
+    if let Some(parent) = pending.iter().find(|pending_tx| pending_tx.get_hash() == input.get_prev_utxo()) {
+        // A pending output has not been mined yet, so no relative lock can be satisfied
+        if input.relative_lock().is_some() {
+            return Err(ValidationError::SequenceLocked);
+        }
+        return parent.get_outputs().get(input.get_n() as usize).cloned().ok_or(ValidationError::MissingInput);
+    }
+
+    let entry = database.get_utxo(&OutPoint::new(*input.get_prev_utxo(), input.get_n()))?
+        .ok_or(ValidationError::MissingInput)?;
+
+    let satisfied = match input.relative_lock() {
+        Some(RelativeLock::Blocks(blocks)) => height >= entry.height + blocks,
+        Some(RelativeLock::Seconds(seconds)) => {
+            let prev_header = database.get_header_by_height(entry.height)?.ok_or(ValidationError::MissingInput)?;
+            time >= prev_header.get_time() + seconds
+        },
+        None => true,
+    };
+    if !satisfied {
+        return Err(ValidationError::SequenceLocked);
+    }
+    Ok(entry.output)
+}
+
+fn check_signature(input: &TxIn, spent_output: &TxOut, signature_hash: &[u8; 32], schnorr_batch: &mut Vec<SchnorrBatchItem>) -> Result<()> {
+    match spent_output.get_lock() {
+        Lock::PubKeyHash(destination) => {
+            if &crypto::hash_public_key(input.get_public_key()) != destination {
+                return Err(ValidationError::PublicKeyMismatch);
+            }
+
+            match crypto::verify_signature(input.get_public_key(), input.get_signature(), signature_hash) {
+                Ok(true) => Ok(()),
+                _ => Err(ValidationError::InvalidSignature),
+            }
+        },
+        Lock::SchnorrKey(public_key) => {
+            if input.get_public_key().as_slice() != public_key {
+                return Err(ValidationError::PublicKeyMismatch);
+            }
+
+            schnorr_batch.push(SchnorrBatchItem {
+                public_key: public_key.to_vec(),
+                signature: input.get_signature().clone(),
+                message: *signature_hash,
+            });
+            Ok(())
+        },
+        Lock::Data(_) => Err(ValidationError::UnspendableOutput),
+    }
+}
+
+fn check_schnorr_batch(schnorr_batch: &[SchnorrBatchItem]) -> Result<()> {
+    match crypto::verify_schnorr_batch(schnorr_batch) {
+        Ok(true) => Ok(()),
+        _ => Err(ValidationError::InvalidSignature),
+    }
+}
+
+
+// --- Block
+// Check every rule for 'block', on top of the chain stored in 'database'. Blocks must meet 'difficulty'
+pub fn check_block(database: &Database, block: &Block, reward: f32, difficulty: u8) -> Result<()> {
+    let mut hashes = HashSet::new();
+    for tx in block.get_transactions() {
+        if tx.compute_hash() != *tx.get_hash() {
+            return Err(ValidationError::InvalidTransactionHash);
+        }
+        if !hashes.insert(*tx.get_hash()) {
+            return Err(ValidationError::DuplicateTransaction);
+        }
+    }
+    if block.compute_merkle_root() != *block.get_merkle_root() {
+        return Err(ValidationError::InvalidMerkleRoot);
+    }
+    if !block.has_valid_hash() {
+        return Err(ValidationError::InvalidBlockHash);
+    }
+    if !block.has_proof_of_work(difficulty) {
+        return Err(ValidationError::InsufficientWork);
+    }
+
+    let height = block.get_index();
+    let time = block.get_time();
+    let mut pending: Vec<Transaction> = Vec::new();
+    let mut schnorr_batch = Vec::new();
+    let mut coinbase = None;
+    let mut fees = 0.0;
+
+    for tx in block.get_transactions() {
+        if tx.is_coinbase() {
+            if coinbase.replace(tx).is_some() {
+                return Err(ValidationError::InvalidCoinbase);
+            }
+            continue;
+        }
+        fees += check_transaction_with_batch(database, tx, height, time, &pending, &mut schnorr_batch)?;
+        pending.push(tx.clone());
+    }
+    check_schnorr_batch(&schnorr_batch)?;
+
+    match coinbase {
+        Some(coinbase) => {
+            // The coinbase is locked to the height of its block, which keeps coinbase hashes unique
+            if coinbase.get_lock_time() != height {
+                return Err(ValidationError::InvalidCoinbase);
+            }
+            check_final(coinbase, height, time)?;
+            check_outputs(coinbase)?;
+            if coinbase.get_outputs_amount() > reward + fees {
+                return Err(ValidationError::InvalidCoinbase);
+            }
+            Ok(())
+        },
+        None => Err(ValidationError::InvalidCoinbase),
+    }
+}
+
+
+// Current unix time in seconds, used for the timelocks of the next block to be mined
+pub fn get_current_time() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(time) => time.as_secs(),
+        Err(_) => 0,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use k256::ecdsa::SigningKey;
+    use crate::database::DatabaseOptions;
+    use crate::transaction::{sequence_from_blocks, TxOut};
+    use super::*;
+
+    // Connect a block whose coinbase pays 50 to 'signing_key', and return the hash of the coinbase
+    fn connect_funding_block(database: &Database, signing_key: &SigningKey) -> [u8; 32] {
+        let prev_block = database.get_last_block().unwrap().unwrap();
+        let mut block = Block::new();
+        block.set_index(prev_block.get_index() + 1);
+        block.set_prev_hash_from_block(&prev_block);
+        block.mine(0, 50.0, crypto::get_public_key_hash(signing_key)).unwrap();
+        database.connect_block(&block).unwrap();
+        *block.get_transactions()[0].get_hash()
+    }
+
+    // Transaction spending output 'n' of every transaction of 'spent' with 'signing_key'
+    fn create_spend(signing_key: &SigningKey, spent: &[([u8; 32], u32)], outputs: Vec<TxOut>) -> Transaction {
+        let public_key = crypto::get_public_key(signing_key);
+        let inputs = spent.iter().map(|(hash, n)| TxIn::new(*n, public_key.clone(), *hash)).collect();
+        let mut tx = Transaction::new(inputs, outputs);
+        sign(&mut tx, signing_key);
+        tx
+    }
+
+    fn sign(tx: &mut Transaction, signing_key: &SigningKey) {
+        let signature_hash = tx.get_signature_hash();
+        for index in 0..tx.get_inputs().len() {
+            tx.set_signature(index, crypto::get_signature(signing_key, &signature_hash));
+        }
+        tx.hash();
+    }
+
+    #[test]
+    fn test_check_transaction() {
+        let database = Database::open_in_memory(DatabaseOptions::default()).unwrap();
+        let signing_key = crypto::create_signing_key();
+        let pub_key_hash = crypto::get_public_key_hash(&signing_key);
+        let funding = connect_funding_block(&database, &signing_key);
+        let (height, time) = (2, get_current_time());
+        let check = |tx: &Transaction, pending: &[Transaction]| check_transaction(&database, tx, height, time, pending);
+
+        let payment = create_spend(&signing_key, &[(funding, 0)], vec![TxOut::new(40.0, pub_key_hash)]);
+        assert_eq!(check(&payment, &[]).unwrap(), 10.0);
+
+        // Outputs of pending transactions can be spent, but only once
+        let child = create_spend(&signing_key, &[(*payment.get_hash(), 0)], vec![TxOut::new(39.0, [2u8; 20])]);
+        assert_eq!(check(&child, std::slice::from_ref(&payment)).unwrap(), 1.0);
+        assert!(matches!(check(&child, &[]), Err(ValidationError::MissingInput)));
+        let double_spend = create_spend(&signing_key, &[(funding, 0)], vec![TxOut::new(30.0, [2u8; 20])]);
+        assert!(matches!(check(&double_spend, std::slice::from_ref(&payment)), Err(ValidationError::InputAlreadySpent)));
+
+        let missing = create_spend(&signing_key, &[(funding, 1)], vec![TxOut::new(1.0, [2u8; 20])]);
+        assert!(matches!(check(&missing, &[]), Err(ValidationError::MissingInput)));
+        let duplicate = create_spend(&signing_key, &[(funding, 0), (funding, 0)], vec![TxOut::new(1.0, [2u8; 20])]);
+        assert!(matches!(check(&duplicate, &[]), Err(ValidationError::DuplicateInput)));
+        let negative = create_spend(&signing_key, &[(funding, 0)], vec![TxOut::new(-1.0, [2u8; 20])]);
+        assert!(matches!(check(&negative, &[]), Err(ValidationError::NegativeAmount)));
+        let too_much = create_spend(&signing_key, &[(funding, 0)], vec![TxOut::new(60.0, [2u8; 20])]);
+        assert!(matches!(check(&too_much, &[]), Err(ValidationError::OutputsExceedInputs)));
+        let paid_data = create_spend(&signing_key, &[(funding, 0)], vec![TxOut::with_lock(1.0, Lock::Data(vec![1u8; 4]))]);
+        assert!(matches!(check(&paid_data, &[]), Err(ValidationError::DataWithAmount)));
+
+        // Keys and signatures
+        let other_key = crypto::create_signing_key();
+        let wrong_key = create_spend(&other_key, &[(funding, 0)], vec![TxOut::new(1.0, [2u8; 20])]);
+        assert!(matches!(check(&wrong_key, &[]), Err(ValidationError::PublicKeyMismatch)));
+        let mut forged = payment.clone();
+        forged.set_signature(0, crypto::get_signature(&other_key, &payment.get_signature_hash()));
+        assert!(matches!(check(&forged, &[]), Err(ValidationError::InvalidSignature)));
+
+        // Timelocks
+        let mut locked = Transaction::new(vec![TxIn::new(0, crypto::get_public_key(&signing_key), funding)], vec![TxOut::new(1.0, [2u8; 20])]);
+        locked.set_lock_time(height + 1);
+        sign(&mut locked, &signing_key);
+        assert!(matches!(check(&locked, &[]), Err(ValidationError::NonFinal)));
+        let mut input = TxIn::new(0, crypto::get_public_key(&signing_key), funding);
+        input.set_sequence(sequence_from_blocks(10));
+        let mut relative = Transaction::new(vec![input], vec![TxOut::new(1.0, [2u8; 20])]);
+        sign(&mut relative, &signing_key);
+        assert!(matches!(check(&relative, &[]), Err(ValidationError::SequenceLocked)));
+
+        // Data outputs cannot be spent
+        let with_data = create_spend(&signing_key, &[(funding, 0)], vec![TxOut::new(40.0, pub_key_hash), TxOut::new_data(vec![1u8; 4])]);
+        let data_spend = create_spend(&signing_key, &[(*with_data.get_hash(), 1)], vec![TxOut::new(0.0, [2u8; 20])]);
+        assert!(matches!(check(&data_spend, std::slice::from_ref(&with_data)), Err(ValidationError::UnspendableOutput)));
+    }
+
+    #[test]
+    fn test_check_block() {
+        let database = Database::open_in_memory(DatabaseOptions::default()).unwrap();
+        let signing_key = crypto::create_signing_key();
+        let funding = connect_funding_block(&database, &signing_key);
+        let prev_block = database.get_last_block().unwrap().unwrap();
+        let payment = create_spend(&signing_key, &[(funding, 0)], vec![TxOut::new(40.0, [2u8; 20])]);
+        let create_block = |transactions: Vec<Transaction>, reward: f32| {
+            let mut block = Block::new();
+            block.set_index(2);
+            block.set_prev_hash_from_block(&prev_block);
+            transactions.into_iter().for_each(|tx| block.add_transaction(tx));
+            block.mine(0, reward, [1u8; 20]).unwrap();
+            block
+        };
+
+        // The coinbase may claim the reward and the fees
+        let block = create_block(vec![payment.clone()], 60.0);
+        check_block(&database, &block, 50.0, 0).unwrap();
+        assert!(matches!(check_block(&database, &block, 50.0, 64), Err(ValidationError::InsufficientWork)));
+        assert!(matches!(check_block(&database, &create_block(vec![payment.clone()], 61.0), 50.0, 0), Err(ValidationError::InvalidCoinbase)));
+
+        // The coinbase is locked to the block height
+        let mut block = create_block(Vec::new(), 50.0);
+        block.set_index(3);
+        block.calculate_hash();
+        assert!(matches!(check_block(&database, &block, 50.0, 0), Err(ValidationError::InvalidCoinbase)));
+
+        // The block hash commits to the transactions
+        let mut block = create_block(Vec::new(), 50.0);
+        block.add_transaction(payment.clone());
+        assert!(matches!(check_block(&database, &block, 50.0, 0), Err(ValidationError::InvalidMerkleRoot)));
+        let mut block = create_block(Vec::new(), 50.0);
+        block.set_index(3);
+        assert!(matches!(check_block(&database, &block, 50.0, 0), Err(ValidationError::InvalidBlockHash)));
+        let unhashed = Transaction::new(payment.get_inputs().clone(), payment.get_outputs().clone());
+        assert!(matches!(check_block(&database, &create_block(vec![unhashed], 50.0), 50.0, 0), Err(ValidationError::InvalidTransactionHash)));
+        let block = create_block(vec![payment.clone(), payment.clone()], 50.0);
+        assert!(matches!(check_block(&database, &block, 50.0, 0), Err(ValidationError::DuplicateTransaction)));
+    }
+}
@@ -0,0 +1,347 @@
+/// Verify.rs
+///
+/// Integrity checks and repair of the Database (see the 'verifychain' and 'reindex' blockchain commands).
+///
+/// 'verify_chain' checks the most recent blocks of the active chain without changing anything:
+///     - the last block is stored and matches the height index
+///     - each block is stored under its hash, matches its header and the height index, and links to
+///       its parent
+///     - each block has undo data with one spent entry per input, and its transactions are in the
+///       transaction index when it is enabled
+///     - each block has a filter matching its filter header, when the block filter index is enabled
+///     - the blocks are validated again (see validation.rs) against the UTXO set preceding them, computed
+///       by undoing them in memory, and connecting them again must give the stored UTXO set
+///
+/// 'reindex' rebuilds the UTXO set, undo data, height index and optional indexes by validating and
+///     connecting again the stored blocks of the active chain, found by following the parent hashes from
+///     the last block. It requires every block, so it cannot run on a pruned database.
+
+use std::fmt;
+
+use thiserror::Error;
+
+use crate::block::Block;
+use crate::database::{Database, DatabaseOptions};
+use crate::filter;
+use crate::rocks::{self, DatabaseError};
+use crate::snapshot::{self, compute_utxo_hash};
+use crate::validation::{self, ValidationError};
+
+// Blocks checked by 'verifychain' when no depth is given
+pub const DEFAULT_CHECK_DEPTH: u32 = 6;
+
+// Inconsistency found by 'verify_chain'
+#[derive(Debug)]
+pub enum Corruption {
+    MissingTip,
+    TipMismatch,
+    MissingHeight(u32),
+    MissingHeader(u32),
+    HeaderMismatch(u32),
+    MissingBlock(u32),
+    InvalidHash(u32),
+    BrokenLink(u32),
+    MissingUndo(u32),
+    UndoMismatch(u32),
+    TxIndexMismatch(u32),
+    FilterMismatch(u32),
+    AddressIndexTip,
+    Unreadable(String, DatabaseError),
+    InvalidBlock(u32, String),
+    UtxoMismatch,
+}
+
+impl fmt::Display for Corruption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Corruption::MissingTip => write!(f, "the last block is not stored"),
+            Corruption::TipMismatch => write!(f, "the last block does not match the height index"),
+            Corruption::MissingHeight(height) => write!(f, "height {} is missing from the height index", height),
+            Corruption::MissingHeader(height) => write!(f, "header of block {} is missing", height),
+            Corruption::HeaderMismatch(height) => write!(f, "header of block {} does not match the block", height),
+            Corruption::MissingBlock(height) => write!(f, "block {} is missing", height),
+            Corruption::InvalidHash(height) => write!(f, "block {} does not match its hash", height),
+            Corruption::BrokenLink(height) => write!(f, "block {} does not link to block {}", height, height.saturating_sub(1)),
+            Corruption::MissingUndo(height) => write!(f, "undo data of block {} is missing", height),
+            Corruption::UndoMismatch(height) => write!(f, "undo data of block {} does not match its inputs", height),
+            Corruption::TxIndexMismatch(height) => write!(f, "transaction index entries of block {} are missing or wrong", height),
+            Corruption::FilterMismatch(height) => write!(f, "block filter of block {} is missing or does not match its header", height),
+            Corruption::AddressIndexTip => write!(f, "the address index does not match the last block"),
+            Corruption::Unreadable(what, e) => write!(f, "{} cannot be read: {}", what, e),
+            Corruption::InvalidBlock(height, reason) => write!(f, "block {} fails validation: {}", height, reason),
+            Corruption::UtxoMismatch => write!(f, "the UTXO set does not match the blocks"),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ReindexError {
+    Database(#[from] DatabaseError),
+    Pruned,
+    MissingTip,
+    MissingBlock(String),
+    InvalidHash(u32),
+    InvalidBlock(u32, ValidationError),
+}
+
+impl fmt::Display for ReindexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReindexError::Database(e) => write!(f, "{}", e),
+            ReindexError::Pruned => write!(f, "a pruned database cannot be reindexed"),
+            ReindexError::MissingTip => write!(f, "the last block is not stored, delete the database directory to start a new chain"),
+            ReindexError::MissingBlock(hash) => write!(f, "block {} of the active chain is missing", hash),
+            ReindexError::InvalidHash(height) => write!(f, "block {} does not match its hash, the chain stops at block {}", height, height - 1),
+            ReindexError::InvalidBlock(height, e) => write!(f, "block {} fails validation ({}), the chain stops at block {}", height, e, height - 1),
+        }
+    }
+}
+
+
+// ------ Verification
+// Check the last 'depth' blocks (every stored block if 'depth' is 0) and return the inconsistencies found.
+//     'progress' is called with the number of blocks checked so far and the number of blocks to check
+pub fn verify_chain<F: FnMut(u32, u32)>(database: &Database, depth: u32, reward: f32, difficulty: u8, mut progress: F) -> rocks::Result<Vec<Corruption>> {
+    let mut corruptions = Vec::new();
+
+    let tip = match database.get_last_block() {
+        Ok(Some(tip)) => tip,
+        Ok(None) => return Ok(vec![Corruption::MissingTip]),
+        Err(e) => return Ok(vec![Corruption::Unreadable(String::from("last block"), e)]),
+    };
+    let height = tip.get_index();
+    if database.get_block_hash(height)? != Some(*tip.get_hash()) {
+        corruptions.push(Corruption::TipMismatch);
+    }
+    if database.is_address_index_enabled() && database.get_address_index_tip()? != Some(*tip.get_hash()) {
+        corruptions.push(Corruption::AddressIndexTip);
+    }
+
+    // The undo data of the lowest block of a pruned database (or of a loaded snapshot) may be missing, so
+    //     only the blocks above it are checked
+    let lowest = database.get_prune_height()?.map_or(0, |prune_height| prune_height + 1);
+    let start = (height + 1).saturating_sub(if depth == 0 { height + 1 } else { depth }).max(lowest);
+    for (checked, block_height) in (start..=height).enumerate() {
+        check_block_storage(database, block_height, &mut corruptions)?;
+        progress(checked as u32 + 1, height + 1 - start);
+    }
+
+    // Blocks can only be validated again once they are known to be stored correctly
+    if corruptions.is_empty() {
+        revalidate(database, start.max(1), height, reward, difficulty, &mut corruptions);
+    }
+    Ok(corruptions)
+}
+
+// Check that the block at 'height' is stored consistently
+fn check_block_storage(database: &Database, height: u32, corruptions: &mut Vec<Corruption>) -> rocks::Result<()> {
+    let hash = match database.get_block_hash(height)? {
+        Some(hash) => hash,
+        None => { corruptions.push(Corruption::MissingHeight(height)); return Ok(()); },
+    };
+
+    match database.get_header(&hash) {
+        Ok(Some(header)) if header.hash == hash && header.index == height => (),
+        Ok(Some(_)) => corruptions.push(Corruption::HeaderMismatch(height)),
+        Ok(None) => corruptions.push(Corruption::MissingHeader(height)),
+        Err(e) => corruptions.push(Corruption::Unreadable(format!("header of block {}", height), e)),
+    }
+
+    let block = match database.get_block(&hash) {
+        Ok(Some(block)) => block,
+        Ok(None) => { corruptions.push(Corruption::MissingBlock(height)); return Ok(()); },
+        Err(e) => { corruptions.push(Corruption::Unreadable(format!("block {}", height), e)); return Ok(()); },
+    };
+    if !block.has_valid_hash() || block.get_hash() != &hash || block.get_index() != height {
+        corruptions.push(Corruption::InvalidHash(height));
+    }
+    let parent = if height > 0 { database.get_block_hash(height - 1)? } else { Some([0u8; 32]) };
+    if parent.as_ref() != Some(block.get_prev_hash()) {
+        corruptions.push(Corruption::BrokenLink(height));
+    }
+
+    let inputs: usize = block.get_transactions().iter().map(|tx| tx.get_inputs().len()).sum();
+    match database.get_block_undo(&hash) {
+        Ok(Some(undo)) if undo.spent.len() == inputs => (),
+        Ok(Some(_)) => corruptions.push(Corruption::UndoMismatch(height)),
+        Ok(None) => corruptions.push(Corruption::MissingUndo(height)),
+        Err(e) => corruptions.push(Corruption::Unreadable(format!("undo data of block {}", height), e)),
+    }
+
+    if database.is_tx_index_enabled() {
+        for (position, tx) in block.get_transactions().iter().enumerate() {
+            match database.get_tx_index_entry(tx.get_hash()) {
+                Ok(Some(entry)) if entry.block_hash == hash && entry.position == position as u32 => (),
+                _ => { corruptions.push(Corruption::TxIndexMismatch(height)); break; },
+            }
+        }
+    }
+
+    if database.is_block_filter_index_enabled() {
+        let prev_header = match height {
+            0 => Some([0u8; 32]),
+            _ => database.get_block_filter(block.get_prev_hash()).ok().flatten().map(|entry| entry.header),
+        };
+        match (database.get_block_filter(&hash), prev_header) {
+            (Ok(Some(entry)), Some(prev_header)) if entry.filter == filter::build_filter(&block)
+                && entry.header == filter::get_filter_header(&entry.filter, &prev_header) => (),
+            _ => corruptions.push(Corruption::FilterMismatch(height)),
+        }
+    }
+    Ok(())
+}
+
+// Validate the blocks from 'start' to 'end' again, on top of the UTXO set at 'start - 1' computed in memory,
+//     and compare the resulting UTXO set with the stored one
+fn revalidate(database: &Database, start: u32, end: u32, reward: f32, difficulty: u8, corruptions: &mut Vec<Corruption>) {
+    if start > end {
+        return;
+    }
+
+    let chain = match snapshot::create_snapshot(database, start - 1)
+        .and_then(|base| {
+            let chain = Database::open_in_memory(DatabaseOptions::default())?;
+            snapshot::load_snapshot(&chain, &base)?;
+            Ok(chain)
+        }) {
+        Ok(chain) => chain,
+        Err(e) => { corruptions.push(Corruption::InvalidBlock(start, format!("cannot undo the blocks: {}", e))); return; },
+    };
+
+    for height in start..=end {
+        let block = match database.get_block_by_height(height) {
+            Ok(Some(block)) => block,
+            _ => { corruptions.push(Corruption::MissingBlock(height)); return; },
+        };
+        if let Err(e) = validation::check_block(&chain, &block, reward, difficulty) {
+            corruptions.push(Corruption::InvalidBlock(height, e.to_string()));
+            return;
+        }
+        if let Err(e) = chain.connect_block(&block) {
+            corruptions.push(Corruption::InvalidBlock(height, e.to_string()));
+            return;
+        }
+    }
+
+    let stored = match database.get_utxos() {
+        Ok(utxos) => utxos,
+        Err(e) => { corruptions.push(Corruption::Unreadable(String::from("UTXO set"), e)); return; },
+    };
+    match (chain.get_utxos().map(|utxos| compute_utxo_hash(&utxos)), compute_utxo_hash(&stored)) {
+        (Ok(Ok(expected)), Ok(actual)) if expected == actual => (),
+        _ => corruptions.push(Corruption::UtxoMismatch),
+    }
+}
+
+
+// ------ Reindex
+// Rebuild the chain state from the stored blocks, and return the height of the last block. 'progress' is
+//     called with the height of each block connected and the height of the last block
+pub fn reindex<F: FnMut(u32, u32)>(database: &Database, reward: f32, difficulty: u8, mut progress: F) -> Result<u32, ReindexError> {
+    if database.is_pruned()? {
+        return Err(ReindexError::Pruned);
+    }
+
+    // No block can be connected by another thread meanwhile, it would land on a partial chain state
+    let chain_guard = database.lock_chain();
+
+    // Active chain, from the last block back to the genesis block
+    let mut hash = database.get_last_block_hash()?.ok_or(ReindexError::MissingTip)?;
+    let mut chain = Vec::new();
+    loop {
+        let block = database.get_block(&hash)?.ok_or_else(|| ReindexError::MissingBlock(hex::encode(hash)))?;
+        chain.push(hash);
+        if block.get_index() == 0 {
+            break;
+        }
+        hash = *block.get_prev_hash();
+    }
+    if chain.last() != Some(Block::genesis().get_hash()) {
+        return Err(ReindexError::MissingBlock(String::from("genesis")));
+    }
+    chain.reverse();
+
+    chain_guard.clear_chain_state()?;
+    let tip = chain.len() as u32 - 1;
+    for (height, hash) in chain.iter().enumerate() {
+        let height = height as u32;
+        let block = database.get_block(hash)?.ok_or_else(|| ReindexError::MissingBlock(hex::encode(hash)))?;
+        if height > 0 {
+            if block.get_index() != height || !block.has_valid_hash() {
+                return Err(ReindexError::InvalidHash(height));
+            }
+            validation::check_block(database, &block, reward, difficulty).map_err(|e| ReindexError::InvalidBlock(height, e))?;
+        }
+        chain_guard.connect_block(&block)?;
+        progress(height, tip);
+    }
+    Ok(tip)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+    use crate::crypto;
+    use crate::database::test_util::{connect_blocks, open_test_storage};
+    use crate::miner::{Miner, MinerError};
+    use crate::rocks::{columns, DatabaseError, WriteBatch};
+    use crate::utxo::OutPoint;
+    use super::*;
+
+    #[test]
+    fn test_verify_and_reindex() {
+        let (db, database) = open_test_storage(DatabaseOptions { txindex: true, ..Default::default() });
+        connect_blocks(&database, 4);
+        let mut checked = 0;
+        assert!(verify_chain(&database, 0, 50.0, 0, |count, _| checked = count).unwrap().is_empty());
+        assert_eq!(checked, 5);
+
+        // A spent output which should be unspent
+        let coinbase = *database.get_block_by_height(3).unwrap().unwrap().get_transactions()[0].get_hash();
+        let mut batch = WriteBatch::new();
+        batch.changes.insert((columns::UTXO_CF, OutPoint::new(coinbase, 0).to_key().to_vec()), None);
+        db.write(batch).unwrap();
+        let corruptions = verify_chain(&database, 2, 50.0, 0, |_, _| ()).unwrap();
+        assert!(matches!(corruptions.as_slice(), [Corruption::UtxoMismatch]));
+
+        // A missing transaction index entry
+        let mut batch = WriteBatch::new();
+        batch.changes.insert((columns::TX_INDEX_CF, coinbase.to_vec()), None);
+        db.write(batch).unwrap();
+        let corruptions = verify_chain(&database, DEFAULT_CHECK_DEPTH, 50.0, 0, |_, _| ()).unwrap();
+        assert!(matches!(corruptions.as_slice(), [Corruption::TxIndexMismatch(3)]));
+
+        assert_eq!(reindex(&database, 50.0, 0, |_, _| ()).unwrap(), 4);
+        assert!(verify_chain(&database, 0, 50.0, 0, |_, _| ()).unwrap().is_empty());
+        assert!(database.get_utxo(&OutPoint::new(coinbase, 0)).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_reindex_while_mining() {
+        let database = Arc::new(Database::open_in_memory(DatabaseOptions::default()).unwrap());
+        connect_blocks(&database, 3);
+
+        // Blocks are mined on top of the chain being reindexed, never on a partial chain state
+        let miner = Miner::new(crypto::get_address(crypto::create_signing_key()), Arc::clone(&database), 0);
+        let (stop, stopped) = mpsc::channel::<()>();
+        let mining = thread::spawn(move || {
+            while matches!(stopped.try_recv(), Err(mpsc::TryRecvError::Empty)) {
+                match miner.mine() {
+                    Ok(_) | Err(MinerError::DatabaseError(DatabaseError::UnexpectedBlock)) => (),
+                    Err(e) => panic!("mining failed: {e}"),
+                }
+            }
+        });
+        while database.get_height().unwrap() < Some(5) {
+            thread::yield_now();
+        }
+        for _ in 0..5 {
+            assert!(reindex(&database, 50.0, 0, |_, _| ()).unwrap() >= 5);
+        }
+        drop(stop);
+        mining.join().unwrap();
+        assert!(verify_chain(&database, 0, 50.0, 0, |_, _| ()).unwrap().is_empty());
+    }
+}
@@ -1,16 +1,18 @@
-use std::rc::Rc;
-use std::cell::RefCell;
-use std::fs::{File, OpenOptions};
+use std::sync::Arc;
+use std::fs::{self, File, OpenOptions};
 use std::io::{self, Write, Read};
 
 use k256::ecdsa::SigningKey;
 use thiserror::Error;
 
-use crate::blockchain::Blockchain;
 use crate::cli::{CLICommandExec, Command, Instruction};
 use crate::crypto;
 use crate::database::Database;
-use crate::transaction::{Transaction, TxIn, TxOut, UTXO};
+use crate::filter::{self, FilterError};
+use crate::psbt::{self, KeyOrigin, PartiallySignedTransaction};
+use crate::rocks::DatabaseError;
+use crate::transaction::{self, Lock, Transaction, TxIn, TxOut, UTXO};
+use crate::utxo::OutPoint;
 
 #[derive(Error, Debug)]
 pub enum WalletError {
@@ -20,30 +22,53 @@ pub enum WalletError {
     InvalidSigningKey,
     NotEnoughFunds,
     HexDecode(#[from] hex::FromHexError),
-    CryptoError(#[from] crypto::CryptoError)
+    CryptoError(#[from] crypto::CryptoError),
+    PsbtError(#[from] psbt::PsbtError),
+    DatabaseError(#[from] DatabaseError),
+    FilterError(#[from] FilterError),
+    UnknownOutput,
 }
 
 pub type Result<T> = std::result::Result<T, WalletError>;
 
 impl std::fmt::Display for WalletError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "wallet error")
+        match self {
+            WalletError::Io(e) => write!(f, "wallet file error: {}", e),
+            WalletError::InvalidTxSig => write!(f, "the transaction signature is invalid"),
+            WalletError::IndexOutOfRange => write!(f, "key index out of range"),
+            WalletError::InvalidSigningKey => write!(f, "no valid wallet key for this public key"),
+            WalletError::NotEnoughFunds => write!(f, "not enough funds to cover the outputs and the fee"),
+            WalletError::HexDecode(e) => write!(f, "invalid hex encoding: {}", e),
+            WalletError::CryptoError(e) => write!(f, "{}", e),
+            WalletError::PsbtError(e) => write!(f, "{}", e),
+            WalletError::DatabaseError(e) => write!(f, "{}", e),
+            WalletError::FilterError(e) => write!(f, "{}", e),
+            WalletError::UnknownOutput => write!(f, "output not found in the UTXO set"),
+        }
     }
 }
 
+// Fee paid by transactions created by the wallet
+#[derive(Clone, Copy)]
+enum FeePolicy {
+    Absolute(f32),
+    Rate(f32), // Per byte
+}
+
 pub struct Wallet {
     private_keys: Vec<[u8; 32]>,
     current_private_key: usize,
     storage_file_name: String,
     utxo : Vec<UTXO>,
-    database: Rc<Database>,
+    database: Arc<Database>,
 }
 
 // ------ General
 impl Wallet {
 
     // ------ Public
-    pub fn new(database: Rc<Database>, storage_file_name: String) -> Wallet {
+    pub fn new(database: Arc<Database>, storage_file_name: String) -> Wallet {
         Wallet { private_keys: Vec::new(), current_private_key: 0, database, utxo: Vec::new(), storage_file_name }
     }
 
@@ -114,61 +139,295 @@ impl Wallet {
 
 
     // --- Transaction management
-    fn create_transaction(&self, amount: f32, destination: [u8; 20]) -> Result<Transaction> {
-        let inputs_total_amount = self.utxo[0].amount + self.utxo[1].amount;
-
-        match self.get_public_key_hash() {
-            Ok(wallet_pub_key_hash) => {
-                if inputs_total_amount > amount {
-                    let inputs = vec![
-                        TxIn::new(
-                            0,
-                            hex::encode(self.get_public_key(self.current_private_key).unwrap()),
-                            self.utxo[0].reference
-                        ),
-                        TxIn::new(
-                            0,
-                            hex::encode(self.get_public_key(self.current_private_key).unwrap()),
-                            self.utxo[1].reference
-                        )
-                    ];
-
-                    let outputs = vec![
-                        TxOut::new(amount, destination),
-                        TxOut::new(inputs_total_amount-amount, wallet_pub_key_hash)
-                    ];
-
-                    return Ok(Transaction::new(inputs, outputs));
-                }
+    // Spend wallet UTXO (at least one) until 'outputs' and 'fee' are covered, and send the remainder
+    // back as change. 'lock_time' is applied to the transaction and 'sequence' to each of its inputs
+    fn create_transaction(&self, mut outputs: Vec<TxOut>, fee: f32, lock_time: u32, sequence: u32) -> Result<Transaction> {
+        let wallet_pub_key_hash = self.get_public_key_hash()?;
+        let amount: f32 = outputs.iter().map(|output| output.get_amount()).sum();
+
+        let mut inputs = Vec::new();
+        let mut inputs_total_amount = 0.0;
+        for utxo in &self.utxo {
+            if !inputs.is_empty() && inputs_total_amount >= amount + fee {
+                break;
+            }
+            let input_public_key = match self.get_public_key_for_lock(&utxo.lock) {
+                Some(public_key) => public_key,
+                None => continue,
+            };
+            let mut input = TxIn::new(utxo.n, input_public_key, utxo.reference);
+            input.set_sequence(sequence);
+            inputs.push(input);
+            inputs_total_amount += utxo.amount;
+        }
+
+        if inputs.is_empty() || inputs_total_amount < amount + fee {
+            return Err(WalletError::NotEnoughFunds);
+        }
+
+        let change = inputs_total_amount - amount - fee;
+        if change > 0.0 {
+            outputs.push(TxOut::new(change, wallet_pub_key_hash));
+        }
+
+        let mut transaction = Transaction::new(inputs, outputs);
+        transaction.set_lock_time(lock_time);
+        Ok(transaction)
+    }
 
-                return Err(WalletError::NotEnoughFunds)
+    // Create an unsigned transaction and return it with the outputs it spends. With a fee rate, the fee is
+    // raised until it covers the estimated size of the signed transaction
+    fn create_funded_transaction(&self, outputs: Vec<TxOut>, fee_policy: FeePolicy, lock_time: u32, sequence: u32) -> Result<(Transaction, Vec<TxOut>)> {
+        let mut fee = match fee_policy {
+            FeePolicy::Absolute(fee) => fee,
+            FeePolicy::Rate(_) => 0.0,
+        };
+
+        loop {
+            let transaction = self.create_transaction(outputs.clone(), fee, lock_time, sequence)?;
+            let spent_outputs = self.get_spent_outputs(&transaction)?;
+
+            if let FeePolicy::Rate(rate) = fee_policy {
+                let spent_locks: Vec<&Lock> = spent_outputs.iter().map(|output| output.get_lock()).collect();
+                let required_fee = transaction::estimate_size(&spent_locks, transaction.get_outputs()) as f32 * rate;
+                if fee < required_fee {
+                    fee = required_fee;
+                    continue;
+                }
             }
-            Err(e) => Err(e)
+            return Ok((transaction, spent_outputs));
         }
     }
 
+    // Create, sign and hash a transaction
+    fn create_signed_transaction(&self, outputs: Vec<TxOut>, fee_policy: FeePolicy, lock_time: u32, sequence: u32) -> Result<Transaction> {
+        let (mut transaction, _) = self.create_funded_transaction(outputs, fee_policy, lock_time, sequence)?;
+        self.sign_tx(&mut transaction)?;
+        transaction.hash();
+        Ok(transaction)
+    }
+
+    // Wallet UTXO referenced by the inputs of 'transaction'
+    fn get_spent_outputs(&self, transaction: &Transaction) -> Result<Vec<TxOut>> {
+        let mut spent_outputs = Vec::new();
+        for input in transaction.get_inputs() {
+            let utxo = self.utxo.iter()
+                .find(|utxo| &utxo.reference == input.get_prev_utxo() && utxo.n == input.get_n())
+                .ok_or(WalletError::NotEnoughFunds)?;
+            spent_outputs.push(TxOut::with_lock(utxo.amount, utxo.lock.clone()));
+        }
+        Ok(spent_outputs)
+    }
+
     fn sign_tx(&self, tx: &mut Transaction) -> Result<()> {
-        if let Ok(signing_key) = self.get_signing_key(self.current_private_key) {
-            // Transaction data
-            let mut transaction_data_buffer = [0u8; 32];
-            crypto::calculate_sha256_hash(tx.get_transaction_data(false).as_bytes(), &mut transaction_data_buffer);
-            // Signature
-            let signature = crypto::get_signature(&signing_key, &transaction_data_buffer);
-            // Signature check
-            let public_key = crypto::get_public_key(&signing_key);
-            if crypto::verify_signature(public_key.as_slice(), signature.as_slice(), &transaction_data_buffer).unwrap() {
-                tx.set_signature(hex::encode(signature));
-                return Ok(());
+        // Transaction data
+        let transaction_data_buffer = tx.get_signature_hash();
+        // Every input is signed over the same data, which excludes signatures, with the key owning its public key
+        for index in 0..tx.get_inputs().len() {
+            let public_key = tx.get_inputs()[index].get_public_key().clone();
+            let signing_key = self.find_signing_key(&public_key, None).ok_or(WalletError::InvalidSigningKey)?;
+            let signature = self.sign_input(&signing_key, &public_key, &transaction_data_buffer)?;
+            tx.set_signature(index, signature);
+        }
+        Ok(())
+    }
+
+    // Wallet key owning 'public_key', trying 'hint' (a key index) first
+    fn find_signing_key(&self, public_key: &Vec<u8>, hint: Option<usize>) -> Option<SigningKey> {
+        hint.into_iter().chain(0..self.private_keys.len())
+            .filter_map(|index| self.get_signing_key(index).ok())
+            .find(|signing_key| Wallet::owns_public_key(signing_key, public_key))
+    }
+
+    // Public key an input must carry to spend an output locked by 'lock', if one of the wallet keys owns it
+    fn get_public_key_for_lock(&self, lock: &Lock) -> Option<Vec<u8>> {
+        (0..self.private_keys.len())
+            .filter_map(|index| self.get_signing_key(index).ok())
+            .find_map(|signing_key| match lock {
+                Lock::PubKeyHash(destination) => {
+                    let public_key = crypto::get_public_key(&signing_key);
+                    (&crypto::hash_public_key(&public_key) == destination).then_some(public_key)
+                },
+                Lock::SchnorrKey(key) => {
+                    let public_key = crypto::get_schnorr_public_key(&signing_key);
+                    (&public_key == key).then(|| public_key.to_vec())
+                },
+                Lock::Data(_) => None,
+            })
+    }
+
+    // Sign 'signature_hash' for an input carrying 'public_key' and check the signature. Inputs spending
+    // Schnorr outputs carry an x-only public key and are signed with Schnorr, the others with ECDSA
+    fn sign_input(&self, signing_key: &SigningKey, public_key: &Vec<u8>, signature_hash: &[u8; 32]) -> Result<Vec<u8>> {
+        let is_schnorr = public_key.len() == transaction::SCHNORR_PUB_KEY_SIZE;
+
+        // Signature
+        let signature = if is_schnorr {
+            crypto::get_schnorr_signature(signing_key, signature_hash)?
+        } else {
+            crypto::get_signature(signing_key, signature_hash)
+        };
+        // Signature check
+        let valid = if is_schnorr {
+            crypto::verify_schnorr_signature(public_key, &signature, signature_hash)?
+        } else {
+            crypto::verify_signature(public_key, &signature, signature_hash)?
+        };
+        if !valid {
+            return Err(WalletError::InvalidTxSig);
+        }
+        Ok(signature)
+    }
+
+
+    // --- Partially signed transactions
+    // Unsigned transaction carrying the outputs spent by its inputs, and the key able to sign them
+    fn create_psbt(&self, outputs: Vec<TxOut>, fee_policy: FeePolicy, lock_time: u32, sequence: u32) -> Result<PartiallySignedTransaction> {
+        let (transaction, spent_outputs) = self.create_funded_transaction(outputs, fee_policy, lock_time, sequence)?;
+
+        let mut psbt = PartiallySignedTransaction::new(transaction, spent_outputs)?;
+        let key_origin = KeyOrigin { fingerprint: self.get_fingerprint()?, index: self.current_private_key };
+        for index in 0..psbt.get_inputs().len() {
+            psbt.set_key_origin(index, key_origin.clone())?;
+        }
+        Ok(psbt)
+    }
+
+    // Unsigned transaction spending 'outpoints' with a key this wallet does not hold (watch-only), e.g. a
+    //     cold storage key: the inputs carry 'public_key', which the wallet owning it uses to sign them.
+    //     The change goes back to the lock of the first spent output
+    fn create_watch_only_psbt(&self, public_key: Vec<u8>, outpoints: &[OutPoint], outputs: Vec<TxOut>, fee_policy: FeePolicy, lock_time: u32, sequence: u32) -> Result<PartiallySignedTransaction> {
+        let mut spent_outputs = Vec::new();
+        for outpoint in outpoints {
+            let entry = self.database.get_utxo(outpoint)?.ok_or(WalletError::UnknownOutput)?;
+            if !Wallet::public_key_spends(&public_key, entry.get_lock()) {
+                return Err(WalletError::PsbtError(psbt::PsbtError::KeyMismatch));
             }
+            spent_outputs.push(TxOut::with_lock(entry.get_amount(), entry.get_lock().clone()));
         }
+        let change_lock = spent_outputs.first().ok_or(WalletError::NotEnoughFunds)?.get_lock().clone();
+        let inputs_total_amount: f32 = spent_outputs.iter().map(|output| output.get_amount()).sum();
+        let amount: f32 = outputs.iter().map(|output| output.get_amount()).sum();
+
+        let mut fee = match fee_policy {
+            FeePolicy::Absolute(fee) => fee,
+            FeePolicy::Rate(_) => 0.0,
+        };
 
-        //Err("Error: could not sign transaction")
-        Err(WalletError::InvalidSigningKey)
+        loop {
+            if inputs_total_amount < amount + fee {
+                return Err(WalletError::NotEnoughFunds);
+            }
+            let mut tx_outputs = outputs.clone();
+            let change = inputs_total_amount - amount - fee;
+            if change > 0.0 {
+                tx_outputs.push(TxOut::with_lock(change, change_lock.clone()));
+            }
+            let inputs = outpoints.iter().map(|outpoint| {
+                let mut input = TxIn::new(outpoint.n, public_key.clone(), outpoint.tx_hash);
+                input.set_sequence(sequence);
+                input
+            }).collect();
+            let mut transaction = Transaction::new(inputs, tx_outputs);
+            transaction.set_lock_time(lock_time);
+
+            if let FeePolicy::Rate(rate) = fee_policy {
+                let spent_locks: Vec<&Lock> = spent_outputs.iter().map(|output| output.get_lock()).collect();
+                let required_fee = transaction::estimate_size(&spent_locks, transaction.get_outputs()) as f32 * rate;
+                if fee < required_fee {
+                    fee = required_fee;
+                    continue;
+                }
+            }
+            return Ok(PartiallySignedTransaction::new(transaction, spent_outputs)?);
+        }
     }
 
+    // Sign every input of 'psbt' whose public key belongs to this wallet, and return the number of signed inputs
+    fn sign_psbt(&self, psbt: &mut PartiallySignedTransaction) -> Result<usize> {
+        let signature_hash = psbt.get_transaction().get_signature_hash();
+        let fingerprint = self.get_fingerprint()?;
+        let mut signed = 0;
+
+        for index in 0..psbt.get_inputs().len() {
+            if psbt.is_signed(index) {
+                continue;
+            }
+            let public_key = psbt.get_transaction().get_inputs()[index].get_public_key().clone();
+
+            // Try the key hinted by the key origin first, then every key of the wallet
+            let hinted_index = psbt.get_inputs()[index].get_key_origin()
+                .filter(|key_origin| key_origin.fingerprint == fingerprint)
+                .map(|key_origin| key_origin.index);
+
+            if let Some(signing_key) = self.find_signing_key(&public_key, hinted_index) {
+                let signature = self.sign_input(&signing_key, &public_key, &signature_hash)?;
+                psbt.add_signature(index, public_key, signature)?;
+                signed += 1;
+            }
+        }
+        Ok(signed)
+    }
+
+    // Whether an input carrying 'public_key' can spend an output locked by 'lock'
+    fn public_key_spends(public_key: &[u8], lock: &Lock) -> bool {
+        match lock {
+            Lock::PubKeyHash(destination) => &crypto::hash_public_key(public_key) == destination,
+            Lock::SchnorrKey(key) => public_key == key,
+            Lock::Data(_) => false,
+        }
+    }
+
+    fn owns_public_key(signing_key: &SigningKey, public_key: &Vec<u8>) -> bool {
+        &crypto::get_public_key(signing_key) == public_key
+            || crypto::get_schnorr_public_key(signing_key).as_slice() == public_key.as_slice()
+    }
+
+    // --- Block filters
+    // Elements tested against block filters (see filter.rs): the destinations of the wallet keys, so that
+    //     blocks paying the wallet match, and the wallet outputs, so that blocks spending them match
+    fn get_filter_elements(&self) -> Vec<Vec<u8>> {
+        let mut elements = Vec::new();
+        for signing_key in (0..self.private_keys.len()).filter_map(|index| self.get_signing_key(index).ok()) {
+            elements.push(crypto::get_public_key_hash(&signing_key).to_vec());
+            elements.push(crypto::get_schnorr_public_key(&signing_key).to_vec());
+        }
+        elements.extend(self.utxo.iter().map(|utxo| OutPoint::new(utxo.reference, utxo.n).to_key().to_vec()));
+        elements
+    }
+
+    // Height and hash of the blocks, among 'count' blocks from 'start', whose filter matches the wallet.
+    //     Matching happens here, as a light client would do it, so false positives are possible
+    fn scan_block_filters(&self, start: u32, count: u32) -> Result<Vec<(u32, [u8; 32])>> {
+        let elements = self.get_filter_elements();
+        let mut matches = Vec::new();
+        for height in start..start.saturating_add(count) {
+            let hash = match self.database.get_block_hash(height)? {
+                Some(hash) => hash,
+                None => break,
+            };
+            let entry = match self.database.get_block_filter(&hash)? {
+                Some(entry) => entry,
+                None => break,
+            };
+            if filter::match_any(&entry.filter, &hash, &elements)? {
+                matches.push((height, hash));
+            }
+        }
+        Ok(matches)
+    }
+
+    // Load the outputs of the UTXO set owned by one of the wallet keys
     fn get_and_set_utxo(&mut self) {
-        self.utxo.push(UTXO::new([1u8; 32], 123, 10.0));
-        self.utxo.push(UTXO::new([2u8; 32], 123, 5.0));
+        match self.database.get_utxos() {
+            Ok(utxos) => {
+                self.utxo = utxos.into_iter()
+                    .filter(|(_, entry)| self.get_public_key_for_lock(entry.get_lock()).is_some())
+                    .map(|(outpoint, entry)| UTXO::new(outpoint.tx_hash, outpoint.n, entry.get_amount(), entry.get_lock().clone()))
+                    .collect();
+            },
+            Err(e) => println!("Error: could not load UTXO: {e}"),
+        }
     }
 
 
@@ -211,6 +470,11 @@ impl Wallet {
         Err(WalletError::IndexOutOfRange)
     }
 
+    pub fn get_schnorr_address(&self, index: usize) -> Result<String> {
+        let signing_key = self.get_signing_key(index)?;
+        Ok(crypto::get_schnorr_address(&signing_key))
+    }
+
     pub fn get_private_key(&self, index: usize) -> Option<[u8; 32]> {
         if index < self.private_keys.len() {
             return Some(self.private_keys[index]);
@@ -234,17 +498,35 @@ impl Wallet {
         }
         None
     }
+
+    // Identifies the wallet in key origins: hex of the first 4 bytes of the hash of its first public key
+    pub fn get_fingerprint(&self) -> Result<String> {
+        let public_key = self.get_public_key(0).ok_or(WalletError::IndexOutOfRange)?;
+        Ok(hex::encode(&crypto::hash_public_key(&public_key)[..4]))
+    }
 }
 
 
 // ------ Instruction execution
 impl CLICommandExec for Wallet {
     fn execute(&mut self, instruction: Instruction) {
+        // Blocks may have been connected since the last command
+        self.get_and_set_utxo();
+
         match instruction.command {
             Command::NEWPRIVATEKEY  => self.cli_new_private_key(),
             Command::GETADDRESS     => self.cli_get_address(instruction),
             Command::SHOWUTXO       => self.cli_show_utxo(),
             Command::SEND           => self.cli_send(instruction),
+            Command::PUBLISH        => self.cli_publish(instruction),
+            Command::LOOKUP         => self.cli_lookup(instruction),
+            Command::CREATEPSBT     => self.cli_create_psbt(instruction),
+            Command::CREATEWATCHPSBT => self.cli_create_watch_psbt(instruction),
+            Command::SIGNPSBT       => self.cli_sign_psbt(instruction),
+            Command::COMBINEPSBT    => self.cli_combine_psbt(instruction),
+            Command::FINALIZEPSBT   => self.cli_finalize_psbt(instruction),
+            Command::DECODEPSBT     => self.cli_decode_psbt(instruction),
+            Command::SCANBLOCKFILTERS => self.cli_scan_block_filters(instruction),
 
             _ => (),
         };
@@ -266,7 +548,12 @@ impl Wallet {
                 Err(_) => { println!("Please enter a valid index"); return; }
             }
         }
-        let address = self.get_address(index);
+        // '--s' shows the address of the Schnorr public key
+        let address = if instruction.options.contains(&'s') {
+            self.get_schnorr_address(index)
+        } else {
+            self.get_address(index)
+        };
         match address {
             Ok(addr) => println!("Address: {}", addr),
             Err(e) => println!("Error: {}", e)
@@ -277,18 +564,22 @@ impl Wallet {
         if instruction.args.len() > 1 {
             // Check if amount was correctly typed
             if let Ok(amount) = instruction.args[0].parse::<f32>() {
-                // Check if address is valid and convert it to public key hash
-                if let Ok(destination) = crypto::address_to_public_key_hash(&instruction.args[1]) {
-                    match self.create_transaction(amount, destination) {
-                        Ok(mut transaction) => {
-                            // Sign Transaction
-                            if let Err(e) = self.sign_tx(&mut transaction) {
-                                println!("{e}");
-                                return;
-                            }
-
-                            transaction.hash();
+                // Check if address is valid and convert it to an output
+                if let Some(output) = self.address_to_output(&instruction.args[1], amount) {
+                    let (lock_time, sequence) = match self.parse_timelocks(&instruction) {
+                        Some(timelocks) => timelocks,
+                        None => { println!("Please, provide valid timelocks"); return; }
+                    };
+
+                    let fee_policy = match self.parse_fee(&instruction) {
+                        Some(fee_policy) => fee_policy,
+                        None => { println!("Please, provide either a valid fee or fee rate"); return; }
+                    };
+
+                    match self.create_signed_transaction(vec![output], fee_policy, lock_time, sequence) {
+                        Ok(transaction) => {
                             println!("{}", transaction);
+                            println!("Raw transaction: {}", hex::encode(transaction.to_bytes()));
                         },
                         Err(e) => println!("{e}")
                     }
@@ -303,20 +594,391 @@ impl Wallet {
         }
     }
 
+    // Public key hash and Schnorr addresses are both accepted
+    fn address_to_output(&self, address: &String, amount: f32) -> Option<TxOut> {
+        if let Ok(destination) = crypto::address_to_public_key_hash(address) {
+            return Some(TxOut::new(amount, destination));
+        }
+        if let Ok(public_key) = crypto::address_to_schnorr_public_key(address) {
+            return Some(TxOut::new_schnorr(amount, public_key));
+        }
+        None
+    }
+
+    // Publish data on-chain in a data output: 'publish <hex data>', or 'publish <file> --f' to publish the
+    //     Sha256 hash of a file
+    fn cli_publish(&self, instruction: Instruction) {
+        if instruction.args.len() != 1 {
+            println!("Wrong number of arguments");
+            return;
+        }
+
+        let data = if instruction.options.contains(&'f') {
+            match fs::read(&instruction.args[0]) {
+                Ok(content) => {
+                    let mut file_hash = vec![0u8; 32];
+                    crypto::calculate_sha256_hash(&content, &mut file_hash);
+                    file_hash
+                },
+                Err(e) => { println!("Error: {e}"); return; }
+            }
+        } else {
+            match hex::decode(&instruction.args[0]) {
+                Ok(data) => data,
+                Err(_) => { println!("Please, provide hex encoded data"); return; }
+            }
+        };
+
+        if data.len() > transaction::MAX_DATA_SIZE {
+            println!("Data cannot exceed {} bytes", transaction::MAX_DATA_SIZE);
+            return;
+        }
+
+        let fee_policy = match self.parse_fee(&instruction) {
+            Some(fee_policy) => fee_policy,
+            None => { println!("Please, provide either a valid fee or fee rate"); return; }
+        };
+
+        println!("Data: {}", hex::encode(&data));
+        match self.create_signed_transaction(vec![TxOut::new_data(data)], fee_policy, 0, transaction::SEQUENCE_FINAL) {
+            Ok(transaction) => {
+                println!("{}", transaction);
+                println!("Raw transaction: {}", hex::encode(transaction.to_bytes()));
+            },
+            Err(e) => println!("{e}")
+        }
+    }
+
+    // Find the block committing to some data: 'lookup <hex data>'
+    fn cli_lookup(&self, instruction: Instruction) {
+        if instruction.args.len() != 1 {
+            println!("Wrong number of arguments");
+            return;
+        }
+
+        let data = match hex::decode(&instruction.args[0]) {
+            Ok(data) => data,
+            Err(_) => { println!("Please, provide hex encoded data"); return; }
+        };
+
+        match self.database.find_data_commitment(&data) {
+            Ok(Some(commitment)) => {
+                println!("Block: {} (index {})", hex::encode(commitment.block_hash), commitment.height);
+                println!("Transaction: {}", hex::encode(commitment.tx_hash));
+            },
+            Ok(None) => match self.database.get_data_index_height() {
+                Ok(Some(height)) => println!("No block commits to this data since block {height}, older blocks were not indexed"),
+                Ok(None) => println!("No block commits to this data"),
+                Err(e) => println!("Error: {e}"),
+            },
+            Err(e) => println!("Error: {e}"),
+        }
+    }
+
+    // Create a partially signed transaction: 'createpsbt <amount> <address>', with the fee and timelock
+    //     parameters of 'send'
+    fn cli_create_psbt(&self, instruction: Instruction) {
+        if instruction.args.len() != 2 {
+            println!("Wrong number of arguments");
+            return;
+        }
+
+        let output = match instruction.args[0].parse::<f32>() {
+            Ok(amount) => match self.address_to_output(&instruction.args[1], amount) {
+                Some(output) => output,
+                None => { println!("Please, provide a valid address"); return; }
+            },
+            Err(_) => { println!("Please, provide a valid amount"); return; }
+        };
+
+        let (lock_time, sequence) = match self.parse_timelocks(&instruction) {
+            Some(timelocks) => timelocks,
+            None => { println!("Please, provide valid timelocks"); return; }
+        };
+
+        let fee_policy = match self.parse_fee(&instruction) {
+            Some(fee_policy) => fee_policy,
+            None => { println!("Please, provide either a valid fee or fee rate"); return; }
+        };
+
+        match self.create_psbt(vec![output], fee_policy, lock_time, sequence) {
+            Ok(psbt) => println!("Psbt: {}", psbt.to_base64()),
+            Err(e) => println!("Error: {e}"),
+        }
+    }
+
+    // Create a partially signed transaction spending outputs of a key this wallet does not hold:
+    //     'createwatchpsbt <amount> <address> <public key|schnorr address> <tx hash>:<n> [<tx hash>:<n> ...]',
+    //     with the fee and timelock parameters of 'send'. The wallet holding the key signs it with 'signpsbt'
+    fn cli_create_watch_psbt(&self, instruction: Instruction) {
+        if instruction.args.len() < 4 {
+            println!("Wrong number of arguments");
+            return;
+        }
+
+        let output = match instruction.args[0].parse::<f32>() {
+            Ok(amount) => match self.address_to_output(&instruction.args[1], amount) {
+                Some(output) => output,
+                None => { println!("Please, provide a valid address"); return; }
+            },
+            Err(_) => { println!("Please, provide a valid amount"); return; }
+        };
+
+        let public_key = match crypto::address_to_schnorr_public_key(&instruction.args[2]) {
+            Ok(public_key) => public_key.to_vec(),
+            Err(_) => match hex::decode(&instruction.args[2]) {
+                Ok(public_key) => public_key,
+                Err(_) => { println!("Please, provide a hex encoded public key or a Schnorr address"); return; }
+            },
+        };
+
+        let outpoints: Option<Vec<OutPoint>> = instruction.args[3..].iter().map(|outpoint| self.parse_outpoint(outpoint)).collect();
+        let outpoints = match outpoints {
+            Some(outpoints) => outpoints,
+            None => { println!("Please, provide outputs as <tx hash>:<n>"); return; }
+        };
+
+        let (lock_time, sequence) = match self.parse_timelocks(&instruction) {
+            Some(timelocks) => timelocks,
+            None => { println!("Please, provide valid timelocks"); return; }
+        };
+
+        let fee_policy = match self.parse_fee(&instruction) {
+            Some(fee_policy) => fee_policy,
+            None => { println!("Please, provide either a valid fee or fee rate"); return; }
+        };
+
+        match self.create_watch_only_psbt(public_key, &outpoints, vec![output], fee_policy, lock_time, sequence) {
+            Ok(psbt) => println!("Psbt: {}", psbt.to_base64()),
+            Err(e) => println!("Error: {e}"),
+        }
+    }
+
+    // Output referenced as '<hex tx hash>:<n>'
+    fn parse_outpoint(&self, outpoint: &str) -> Option<OutPoint> {
+        let (tx_hash, n) = outpoint.split_once(':')?;
+        let tx_hash: [u8; 32] = hex::decode(tx_hash).ok()?.try_into().ok()?;
+        Some(OutPoint::new(tx_hash, n.parse::<u32>().ok()?))
+    }
+
+    // Sign the inputs of a partially signed transaction owned by this wallet: 'signpsbt <psbt>'
+    fn cli_sign_psbt(&self, instruction: Instruction) {
+        if instruction.args.len() != 1 {
+            println!("Wrong number of arguments");
+            return;
+        }
+
+        let mut psbt = match PartiallySignedTransaction::parse(&instruction.args[0]) {
+            Ok(psbt) => psbt,
+            Err(e) => { println!("Error: {e}"); return; }
+        };
+
+        match self.sign_psbt(&mut psbt) {
+            Ok(signed) => {
+                println!("Signed inputs: {}", signed);
+                println!("Psbt: {}", psbt.to_base64());
+            },
+            Err(e) => println!("Error: {e}"),
+        }
+    }
+
+    // Merge the signatures of several copies of a partially signed transaction: 'combinepsbt <psbt> <psbt> ...'
+    fn cli_combine_psbt(&self, instruction: Instruction) {
+        if instruction.args.len() < 2 {
+            println!("Wrong number of arguments");
+            return;
+        }
+
+        let mut combined: Option<PartiallySignedTransaction> = None;
+        for arg in &instruction.args {
+            let psbt = match PartiallySignedTransaction::parse(arg) {
+                Ok(psbt) => psbt,
+                Err(e) => { println!("Error: {e}"); return; }
+            };
+            match combined.as_mut() {
+                Some(combined) => if let Err(e) = combined.combine(&psbt) { println!("Error: {e}"); return; },
+                None => combined = Some(psbt),
+            }
+        }
+
+        if let Some(psbt) = combined {
+            println!("Psbt: {}", psbt.to_base64());
+        }
+    }
+
+    // Build the signed transaction once every input is signed: 'finalizepsbt <psbt>'
+    fn cli_finalize_psbt(&self, instruction: Instruction) {
+        if instruction.args.len() != 1 {
+            println!("Wrong number of arguments");
+            return;
+        }
+
+        match PartiallySignedTransaction::parse(&instruction.args[0]).and_then(|psbt| psbt.finalize()) {
+            Ok(transaction) => {
+                println!("{}", transaction);
+                println!("Raw transaction: {}", hex::encode(transaction.to_bytes()));
+            },
+            Err(e) => println!("Error: {e}"),
+        }
+    }
+
+    // Show a partially signed transaction as JSON: 'decodepsbt <psbt>'
+    fn cli_decode_psbt(&self, instruction: Instruction) {
+        if instruction.args.len() != 1 {
+            println!("Wrong number of arguments");
+            return;
+        }
+
+        match PartiallySignedTransaction::parse(&instruction.args[0]) {
+            Ok(psbt) => println!("{}", psbt.to_json_pretty()),
+            Err(e) => println!("Error: {e}"),
+        }
+    }
+
+    // Blocks which may pay or be spent by the wallet, found with their block filters:
+    //     'scanblockfilters <start height> [count]', up to the last block by default
+    fn cli_scan_block_filters(&self, instruction: Instruction) {
+        if instruction.args.is_empty() || instruction.args.len() > 2 {
+            println!("Wrong number of arguments");
+            return;
+        }
+        if !self.database.is_block_filter_index_enabled() {
+            println!("The block filter index is disabled, restart with -blockfilterindex to enable it");
+            return;
+        }
+
+        let (start, count) = match (instruction.args[0].parse::<u32>(), instruction.args.get(1).map(|count| count.parse::<u32>())) {
+            (Ok(start), None) => (start, u32::MAX),
+            (Ok(start), Some(Ok(count))) => (start, count),
+            _ => { println!("Please, provide a valid start height and count"); return; }
+        };
+
+        match self.scan_block_filters(start, count) {
+            Ok(matches) => {
+                matches.iter().for_each(|(height, hash)| println!("{} {}", height, hex::encode(hash)));
+                println!("Matching blocks: {}", matches.len());
+            },
+            Err(e) => println!("Error: {e}"),
+        }
+    }
+
+    // Fee parameters of 'send' and 'publish' (optional, defaults to no fee):
+    //     fee=<amount>                 fee paid to the miner
+    //     feerate=<amount>             fee paid to the miner per byte of the signed transaction (estimated
+    //                                  before signing, see transaction.rs)
+    fn parse_fee(&self, instruction: &Instruction) -> Option<FeePolicy> {
+        let fee_policy = match (instruction.params.get("fee"), instruction.params.get("feerate")) {
+            (Some(_), Some(_)) => return None,
+            (Some(fee), None) => FeePolicy::Absolute(fee.parse::<f32>().ok()?),
+            (None, Some(rate)) => FeePolicy::Rate(rate.parse::<f32>().ok()?),
+            (None, None) => FeePolicy::Absolute(0.0),
+        };
+
+        match fee_policy {
+            FeePolicy::Absolute(value) | FeePolicy::Rate(value) if value >= 0.0 => Some(fee_policy),
+            _ => None,
+        }
+    }
+
+    // Timelock parameters of 'send' (optional):
+    //     locktime=<height|unix time>  transaction cannot be mined before this height or time
+    //     relativeblocks=<blocks>      inputs cannot be mined until <blocks> after the outputs they spend
+    //     relativetime=<seconds>       inputs cannot be mined until <seconds> after the outputs they spend
+    fn parse_timelocks(&self, instruction: &Instruction) -> Option<(u32, u32)> {
+        let lock_time = match instruction.params.get("locktime") {
+            Some(value) => value.parse::<u32>().ok()?,
+            None => 0,
+        };
+
+        let sequence = match (instruction.params.get("relativeblocks"), instruction.params.get("relativetime")) {
+            (Some(_), Some(_)) => return None,
+            (Some(blocks), None) => transaction::sequence_from_blocks(blocks.parse::<u16>().ok()?),
+            (None, Some(seconds)) => transaction::sequence_from_seconds(seconds.parse::<u32>().ok()?)?,
+            (None, None) => transaction::SEQUENCE_FINAL,
+        };
+
+        Some((lock_time, sequence))
+    }
+
     fn cli_show_utxo(&self) {
-        self.utxo.iter().for_each( |tx| println!("{}", tx))
+        self.utxo.iter().for_each( |tx| println!("{}", tx));
+        println!("Balance: {}", self.utxo.iter().map(|utxo| utxo.amount).sum::<f32>());
     }
 }
 
 
 #[cfg(test)]
 mod tests {
+    use crate::block::Block;
+    use crate::database::DatabaseOptions;
+    use crate::database::test_util::{connect_blocks, open_test_storage};
     use crate::transaction::{TxIn, TxOut};
     use super::*;
 
+    #[test]
+    fn test_watch_only_psbt() {
+        let (_, database) = open_test_storage(DatabaseOptions::default());
+        let watching = Wallet::new(Arc::new(database), String::from("keys.txt"));
+        let cold_key = crypto::create_signing_key();
+
+        let blocks = connect_blocks(&watching.database, 1);
+        let mut block = Block::new();
+        block.set_index(2);
+        block.set_prev_hash_from_block(blocks.last().unwrap());
+        block.mine(0, 50.0, crypto::get_public_key_hash(&cold_key)).unwrap();
+        watching.database.connect_block(&block).unwrap();
+        let outpoint = OutPoint::new(*block.get_transactions()[0].get_hash(), 0);
+
+        // The watching wallet holds no key, the inputs carry the cold public key
+        let output = TxOut::new(20.0, [3u8; 20]);
+        let public_key = crypto::get_public_key(&cold_key);
+        let mut psbt = watching.create_watch_only_psbt(public_key.clone(), &[outpoint], vec![output.clone()], FeePolicy::Absolute(1.0), 0, transaction::SEQUENCE_FINAL).unwrap();
+        assert_eq!(psbt.get_transaction().get_inputs()[0].get_public_key(), &public_key);
+        assert_eq!(psbt.get_transaction().get_outputs()[1].get_amount(), 29.0);
+        assert!(psbt.get_inputs()[0].get_key_origin().is_none());
+
+        let mut cold = Wallet::new(Arc::clone(&watching.database), String::from("keys.txt"));
+        cold.private_keys.push(crypto::get_private_key(&cold_key));
+        assert_eq!(cold.sign_psbt(&mut psbt).unwrap(), 1);
+        assert!(psbt.finalize().is_ok());
+
+        let other_key = crypto::get_public_key(&crypto::create_signing_key());
+        let result = watching.create_watch_only_psbt(other_key, &[outpoint], vec![output.clone()], FeePolicy::Absolute(1.0), 0, transaction::SEQUENCE_FINAL);
+        assert_eq!(result.unwrap_err().to_string(), "public key does not match the input");
+        let result = watching.create_watch_only_psbt(public_key.clone(), &[OutPoint::new(outpoint.tx_hash, 1)], vec![output.clone()], FeePolicy::Absolute(1.0), 0, transaction::SEQUENCE_FINAL);
+        assert!(matches!(result, Err(WalletError::UnknownOutput)));
+        assert_eq!(WalletError::UnknownOutput.to_string(), "output not found in the UTXO set");
+        let result = watching.create_watch_only_psbt(public_key, &[outpoint], vec![output], FeePolicy::Absolute(31.0), 0, transaction::SEQUENCE_FINAL);
+        assert!(matches!(result, Err(WalletError::NotEnoughFunds)));
+    }
+
+    #[test]
+    fn test_scan_block_filters() {
+        let (_, database) = open_test_storage(DatabaseOptions { blockfilterindex: true, ..Default::default() });
+        let mut wallet = Wallet::new(Arc::new(database), String::from("keys.txt"));
+        let signing_key = crypto::create_signing_key();
+        wallet.private_keys.push(crypto::get_private_key(&signing_key));
+
+        // Block 3 pays the wallet
+        let blocks = connect_blocks(&wallet.database, 2);
+        let mut block = Block::new();
+        block.set_index(3);
+        block.set_prev_hash_from_block(blocks.last().unwrap());
+        block.mine(0, 50.0, crypto::get_public_key_hash(&signing_key)).unwrap();
+        wallet.database.connect_block(&block).unwrap();
+        connect_blocks(&wallet.database, 2);
+
+        assert_eq!(wallet.scan_block_filters(0, u32::MAX).unwrap(), vec![(3, *block.get_hash())]);
+        assert!(wallet.scan_block_filters(4, 10).unwrap().is_empty());
+
+        // Once the wallet knows its output, blocks spending it match too
+        wallet.get_and_set_utxo();
+        assert_eq!(wallet.get_filter_elements().len(), 3);
+    }
+
     //#[test]
     fn test_wallet_creation() {
-        let mut wallet = Wallet::new(Rc::new(Database::open("database-test").unwrap()), String::from("keys.txt"));
+        let mut wallet = Wallet::new(Arc::new(Database::open_in_memory(DatabaseOptions::default()).unwrap()), String::from("keys.txt"));
         wallet.initialize();
 
         assert_eq!(wallet.get_address(0).unwrap(), crypto::get_address(SigningKey::from_slice(&wallet.get_private_key(0).unwrap()).unwrap()))
@@ -324,7 +986,7 @@ mod tests {
 
     //#[test]
     fn test_wallet_creation_from_file() {
-        let mut wallet = Wallet::new(Rc::new(Database::open("database-test").unwrap()), String::from("keys.txt"));
+        let mut wallet = Wallet::new(Arc::new(Database::open_in_memory(DatabaseOptions::default()).unwrap()), String::from("keys.txt"));
         wallet.initialize();
         wallet.create_and_store_private_key();
         println!("{}", wallet.get_address(0).unwrap());
@@ -335,13 +997,13 @@ mod tests {
 
     //#[test]
     fn test_transaction_signature() {
-        let mut wallet = Wallet::new(Rc::new(Database::open("database-test").unwrap()), String::from("keys.txt"));
+        let mut wallet = Wallet::new(Arc::new(Database::open_in_memory(DatabaseOptions::default()).unwrap()), String::from("keys.txt"));
         wallet.initialize();
 
         // Create test Transaction
         let inputs = vec![
-            TxIn::new(0, String::from("04d2bb60cc37f89b5b07ea53724cd198acb5223b72ba98017278a428fdace203aedb21e038e8f7546a6d45e30737ad2d85236e187ee30f01bcb2aee6e94a3f143c"), [0u8; 32]),
-            TxIn::new(1, String::from("04d2bb60cc37f89b5b07ea53724cd198acb5223b72ba98017278a428fdace203aedb21e038e8f7546a6d45e30737ad2d85236e187ee30f01bcb2aee6e94a3f143c"), [1u8; 32])
+            TxIn::new(0, hex::decode("04d2bb60cc37f89b5b07ea53724cd198acb5223b72ba98017278a428fdace203aedb21e038e8f7546a6d45e30737ad2d85236e187ee30f01bcb2aee6e94a3f143c").unwrap(), [0u8; 32]),
+            TxIn::new(1, hex::decode("04d2bb60cc37f89b5b07ea53724cd198acb5223b72ba98017278a428fdace203aedb21e038e8f7546a6d45e30737ad2d85236e187ee30f01bcb2aee6e94a3f143c").unwrap(), [1u8; 32])
         ];
         let outputs = vec![
             TxOut::new(10.0, [3u8; 20]),
@@ -352,9 +1014,9 @@ mod tests {
         wallet.sign_tx(&mut transaction).expect("Could not sign transaction");
 
         let mut transaction_data_buffer = [0u8; 32];
-        crypto::calculate_sha256_hash(transaction.get_transaction_data(false).as_bytes(), &mut transaction_data_buffer);
+        crypto::calculate_sha256_hash(&transaction.get_transaction_data(false), &mut transaction_data_buffer);
 
 
-        assert!(crypto::verify_signature(wallet.get_public_key(0).unwrap().as_slice(), hex::decode(transaction.get_signature()).unwrap().as_slice(), &transaction_data_buffer).unwrap());
+        assert!(crypto::verify_signature(wallet.get_public_key(0).unwrap().as_slice(), transaction.get_inputs()[0].get_signature().as_slice(), &transaction_data_buffer).unwrap());
     }
 }
\ No newline at end of file